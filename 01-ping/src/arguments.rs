@@ -0,0 +1,37 @@
+use libp2p::Multiaddr;
+use std::{path::PathBuf, time::Duration};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct Opt {
+    /// Address to listen on.
+    #[structopt(short, long, default_value = "/ip4/0.0.0.0/tcp/3000")]
+    pub listen: Multiaddr,
+    /// Pings to send to every target before exiting, 0 pings until Ctrl-C.
+    #[structopt(short, long, default_value = "0")]
+    pub count: usize,
+    /// Seconds between two pings to the same peer.
+    #[structopt(short, long, default_value = "1", parse(try_from_str = parse_secs))]
+    pub interval: Duration,
+    /// Seconds to wait for a pong before counting the ping as lost.
+    #[structopt(short, long, default_value = "20", parse(try_from_str = parse_secs))]
+    pub timeout: Duration,
+    /// Write every ping result and the summary as JSON lines, "-" for stdout.
+    #[structopt(long)]
+    pub json: Option<PathBuf>,
+    /// Also ping every peer discovered through mDNS on the local network.
+    #[structopt(long)]
+    pub mdns: bool,
+    /// Show a live per-peer table instead of one line per ping.
+    #[structopt(long)]
+    pub table: bool,
+    /// Peers to ping, all of them at the same time.
+    pub targets: Vec<Multiaddr>,
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("invalid number of seconds: {}", s)),
+    }
+}
@@ -0,0 +1,31 @@
+use libp2p::{
+    mdns::{Mdns, MdnsEvent},
+    ping,
+    swarm::toggle::Toggle,
+    NetworkBehaviour,
+};
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "Event")]
+pub struct Behaviour {
+    pub ping: ping::Behaviour,
+    /// Only enabled with `--mdns`, to find targets on the local network.
+    pub mdns: Toggle<Mdns>,
+}
+
+pub enum Event {
+    Ping(ping::Event),
+    Mdns(Box<MdnsEvent>),
+}
+
+impl From<ping::Event> for Event {
+    fn from(event: ping::Event) -> Self {
+        Event::Ping(event)
+    }
+}
+
+impl From<MdnsEvent> for Event {
+    fn from(event: MdnsEvent) -> Self {
+        Event::Mdns(Box::new(event))
+    }
+}
@@ -1,31 +1,165 @@
-use futures::{executor::block_on, future, StreamExt};
-use libp2p::{identity, ping, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
-use std::error::Error;
-use std::task::Poll;
+mod arguments;
+mod behaviour;
+mod stats;
+use behaviour::{Behaviour, Event};
+use futures::{channel::mpsc, executor::block_on, StreamExt};
+use libp2p::{
+    identity,
+    mdns::{Mdns, MdnsEvent},
+    multiaddr::Protocol,
+    ping,
+    swarm::{DialError, SwarmEvent},
+    PeerId, Swarm,
+};
+use serde_json::json;
+use stats::Stats;
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::File,
+    io::{self, Write},
+    num::NonZeroU32,
+    path::Path,
+};
+use structopt::StructOpt;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let opt = arguments::Opt::from_args();
     let local_key = identity::Keypair::generate_ed25519();
     let local_peer_id = PeerId::from(local_key.public());
     println!("Local peer id: {:?}", local_peer_id);
     let transport = block_on(libp2p::development_transport(local_key))?;
-    let behaviour = ping::Behaviour::new(ping::Config::new().with_keep_alive(true));
+    // Failures are counted as loss, so keep pinging instead of closing the
+    // connection after the first one.
+    let config = ping::Config::new()
+        .with_keep_alive(true)
+        .with_interval(opt.interval)
+        .with_timeout(opt.timeout)
+        .with_max_failures(NonZeroU32::new(u32::MAX).unwrap());
+    let mdns = if opt.mdns {
+        Some(block_on(Mdns::new(Default::default()))?)
+    } else {
+        None
+    };
+    let behaviour = Behaviour {
+        ping: ping::Behaviour::new(config),
+        mdns: mdns.into(),
+    };
     let mut swarm = Swarm::new(transport, behaviour, local_peer_id);
-    swarm.listen_on("/ip4/0.0.0.0/tcp/3000".parse()?)?;
-    if let Some(addr) = std::env::args().nth(1) {
-        let remote: Multiaddr = addr.parse()?;
-        swarm.dial(remote)?;
-        println!("Dialed {}", addr);
+    swarm.listen_on(opt.listen.clone())?;
+    let mut stats = Stats::default();
+    for remote in &opt.targets {
+        if let Some(Protocol::P2p(hash)) = remote.iter().last() {
+            if let Ok(peer) = PeerId::from_multihash(hash) {
+                stats.record_target(peer);
+            }
+        }
+        swarm.dial(remote.clone())?;
+        println!("Dialed {}", remote);
     }
-    block_on(future::poll_fn(move |cx| loop {
-        match swarm.poll_next_unpin(cx) {
-            Poll::Ready(Some(event)) => match event {
-                SwarmEvent::NewListenAddr { address, .. } => println!("Listening on {:?}", address),
-                SwarmEvent::Behaviour(event) => println!("{:?}", event),
-                _ => {}
-            },
-            Poll::Ready(None) => return Poll::Ready(()),
-            Poll::Pending => return Poll::Pending,
+
+    let mut json = match &opt.json {
+        Some(path) => Some(json_writer(path)?),
+        None => None,
+    };
+    let (stop_sender, mut stop) = mpsc::unbounded();
+    ctrlc::set_handler(move || {
+        let _ = stop_sender.unbounded_send(());
+    })?;
+
+    let mut discovered = HashSet::new();
+    block_on(async {
+        loop {
+            futures::select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::NewListenAddr { address, .. } => println!("Listening on {:?}", address),
+                    SwarmEvent::Behaviour(Event::Mdns(event)) => {
+                        if let MdnsEvent::Discovered(list) = *event {
+                            for (peer, _) in list {
+                                if discovered.insert(peer) {
+                                    stats.record_target(peer);
+                                    swarm.dial(peer).ok();
+                                }
+                            }
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Ping(ping::Event { peer, result })) => {
+                        if opt.count > 0 && stats.peer(&peer).is_some_and(|p| p.sent() >= opt.count) {
+                            continue;
+                        }
+                        let line = match result {
+                            Ok(ping::Success::Ping { rtt }) => {
+                                stats.record_rtt(peer, rtt);
+                                if !opt.table {
+                                    println!("{}: time={:.3} ms", peer, rtt.as_secs_f64() * 1000.0);
+                                }
+                                json!({ "peer": peer.to_base58(), "rtt_ms": rtt.as_secs_f64() * 1000.0 })
+                            }
+                            Ok(ping::Success::Pong) => continue,
+                            Err(error) => {
+                                stats.record_failure(peer);
+                                if !opt.table {
+                                    println!("{}: {}", peer, error);
+                                }
+                                json!({ "peer": peer.to_base58(), "error": error.to_string() })
+                            }
+                        };
+                        if let Some(json) = json.as_mut() {
+                            writeln!(json, "{}", line)?;
+                        }
+                        if opt.table {
+                            // Clear the screen and redraw from the top left corner.
+                            print!("\x1B[2J\x1B[H{}", stats.table());
+                            io::stdout().flush()?;
+                        }
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } if endpoint.is_dialer() => {
+                        stats.record_target(peer_id);
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                        match (peer_id, &error) {
+                            (Some(peer), _) => stats.record_unreachable_peer(peer),
+                            (None, DialError::Transport(addrs)) => {
+                                for (addr, _) in addrs {
+                                    stats.record_unreachable_addr(addr.clone());
+                                }
+                            }
+                            (None, _) => {}
+                        }
+                        println!("Failed to dial {:?}: {}", peer_id, error);
+                    }
+                    _ => {}
+                },
+                _ = stop.next() => break,
+            }
+            let expected = stats.expected(opt.targets.len());
+            if opt.count > 0 && expected > 0 && stats.done(opt.count) >= expected {
+                break;
+            }
         }
-    }));
+        Ok::<_, io::Error>(())
+    })?;
+
+    println!("--- ping statistics ---");
+    for (peer, peer_stats) in stats.peers() {
+        println!("{}: {}", peer, peer_stats.summary());
+    }
+    let summary = stats.summary();
+    println!("total: {}", summary);
+    if let Some(json) = json.as_mut() {
+        writeln!(json, "{}", json!({ "summary": summary }))?;
+        json.flush()?;
+    }
+    if stats.failed(!opt.targets.is_empty()) {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+fn json_writer(path: &Path) -> io::Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}
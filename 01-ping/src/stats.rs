@@ -0,0 +1,316 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
+
+/// Round trip times and failures collected for a single peer.
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    rtts: Vec<Duration>,
+    failures: usize,
+    /// Outcome of the most recent ping, `None` if it failed.
+    last: Option<Duration>,
+}
+
+impl PeerStats {
+    pub fn sent(&self) -> usize {
+        self.rtts.len() + self.failures
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary::new(&self.rtts, self.failures)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    peers: HashMap<PeerId, PeerStats>,
+    /// Peers we dialed or discovered, pings from peers that dialed us do
+    /// not decide when a run is done.
+    targets: HashSet<PeerId>,
+    /// Targets that could not be dialed, once each however often that
+    /// was tried.
+    unreachable_peers: HashSet<PeerId>,
+    /// Targets given without a peer id that could not be dialed.
+    unreachable_addrs: HashSet<Multiaddr>,
+}
+
+impl Stats {
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        let peer = self.peers.entry(peer).or_default();
+        peer.rtts.push(rtt);
+        peer.last = Some(rtt);
+    }
+
+    pub fn record_failure(&mut self, peer: PeerId) {
+        let peer = self.peers.entry(peer).or_default();
+        peer.failures += 1;
+        peer.last = None;
+    }
+
+    pub fn record_target(&mut self, peer: PeerId) {
+        self.targets.insert(peer);
+    }
+
+    pub fn record_unreachable_peer(&mut self, peer: PeerId) {
+        self.unreachable_peers.insert(peer);
+    }
+
+    pub fn record_unreachable_addr(&mut self, addr: Multiaddr) {
+        self.unreachable_addrs.insert(addr);
+    }
+
+    pub fn unreachable(&self) -> usize {
+        self.unreachable_peers.len() + self.unreachable_addrs.len()
+    }
+
+    pub fn peer(&self, peer: &PeerId) -> Option<&PeerStats> {
+        self.peers.get(peer)
+    }
+
+    /// Number of targets a run waits for, with `given` the number of
+    /// addresses it was started with. A given peer that is also discovered
+    /// counts once, one given without a peer id counts from the start
+    /// although its peer id is only known once it was dialed.
+    pub fn expected(&self, given: usize) -> usize {
+        given.max(self.targets.len() + self.unreachable_addrs.len())
+    }
+
+    /// Number of targets that are finished, either because `count` pings
+    /// were sent to them or because they could not be dialed.
+    pub fn done(&self, count: usize) -> usize {
+        let finished = self.targets.iter().filter(|peer| {
+            self.unreachable_peers.contains(peer)
+                || self.peers.get(peer).is_some_and(|p| p.sent() >= count)
+        });
+        let unreachable = self
+            .unreachable_peers
+            .iter()
+            .filter(|peer| !self.targets.contains(peer));
+        finished.count() + unreachable.count() + self.unreachable_addrs.len()
+    }
+
+    pub fn summary(&self) -> Summary {
+        let rtts: Vec<Duration> = self.peers.values().flat_map(|p| p.rtts.clone()).collect();
+        let failures = self.peers.values().map(|p| p.failures).sum();
+        Summary::new(&rtts, failures)
+    }
+
+    /// Whether any ping was lost or any target was unreachable. Without
+    /// targets, e.g. when only listening, receiving nothing is no failure.
+    pub fn failed(&self, has_targets: bool) -> bool {
+        let summary = self.summary();
+        self.unreachable() > 0
+            || (has_targets && summary.received == 0)
+            || summary.received < summary.sent
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &PeerStats)> {
+        self.peers.iter()
+    }
+
+    /// Renders one row per peer with the latest and aggregated results.
+    pub fn table(&self) -> String {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(peer, _)| peer.to_base58());
+        let mut table = format!(
+            "{:<52} {:>10} {:>10} {:>10} {:>6} {:>6} {:>7}\n",
+            "PEER", "LAST(ms)", "AVG(ms)", "P95(ms)", "SENT", "FAIL", "LOSS"
+        );
+        let ms = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.3}", v));
+        for (peer, stats) in peers {
+            let summary = stats.summary();
+            let last = stats.last.map(|d| d.as_secs_f64() * 1000.0);
+            table.push_str(&format!(
+                "{:<52} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6.1}%\n",
+                peer.to_base58(),
+                ms(last),
+                ms(summary.avg),
+                ms(summary.p95),
+                summary.sent,
+                stats.failures,
+                summary.loss * 100.0
+            ));
+        }
+        if self.unreachable() > 0 {
+            table.push_str(&format!("{} target(s) unreachable\n", self.unreachable()));
+        }
+        table
+    }
+}
+
+/// RTT figures are in milliseconds, `None` when nothing was received.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub sent: usize,
+    pub received: usize,
+    pub loss: f64,
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
+    pub stddev: Option<f64>,
+    pub p95: Option<f64>,
+}
+
+impl Summary {
+    fn new(rtts: &[Duration], failures: usize) -> Self {
+        let mut ms: Vec<f64> = rtts.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        ms.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let received = ms.len();
+        let sent = received + failures;
+        let loss = if sent == 0 {
+            0.0
+        } else {
+            failures as f64 / sent as f64
+        };
+        if ms.is_empty() {
+            return Summary {
+                sent,
+                received,
+                loss,
+                min: None,
+                avg: None,
+                max: None,
+                stddev: None,
+                p95: None,
+            };
+        }
+        let avg = ms.iter().sum::<f64>() / received as f64;
+        let variance = ms.iter().map(|v| (v - avg).powi(2)).sum::<f64>() / received as f64;
+        // Nearest-rank percentile.
+        let rank = ((0.95 * received as f64).ceil() as usize).max(1);
+        Summary {
+            sent,
+            received,
+            loss,
+            min: ms.first().copied(),
+            avg: Some(avg),
+            max: ms.last().copied(),
+            stddev: Some(variance.sqrt()),
+            p95: Some(ms[rank - 1]),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} received, {:.1}% loss",
+            self.sent,
+            self.received,
+            self.loss * 100.0
+        )?;
+        if let (Some(min), Some(avg), Some(max), Some(stddev), Some(p95)) =
+            (self.min, self.avg, self.max, self.stddev, self.p95)
+        {
+            write!(
+                f,
+                ", rtt min/avg/max/stddev/p95 = {:.3}/{:.3}/{:.3}/{:.3}/{:.3} ms",
+                min, avg, max, stddev, p95
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|v| Duration::from_millis(*v)).collect()
+    }
+
+    #[test]
+    fn summary_percentile_and_stddev() {
+        let rtts = ms(&(1..=20).collect::<Vec<_>>());
+        let summary = Summary::new(&rtts, 0);
+        assert_eq!(summary.min, Some(1.0));
+        assert_eq!(summary.max, Some(20.0));
+        assert_eq!(summary.avg, Some(10.5));
+        // Nearest rank: the 19th of 20 values.
+        assert_eq!(summary.p95, Some(19.0));
+        let stddev = summary.stddev.unwrap();
+        assert!((stddev - 5.766_281_3).abs() < 1e-6, "{}", stddev);
+    }
+
+    #[test]
+    fn summary_of_single_value() {
+        let summary = Summary::new(&ms(&[7]), 1);
+        assert_eq!(summary.p95, Some(7.0));
+        assert_eq!(summary.stddev, Some(0.0));
+        assert_eq!(summary.sent, 2);
+        assert_eq!(summary.loss, 0.5);
+    }
+
+    #[test]
+    fn summary_without_replies() {
+        let summary = Summary::new(&[], 3);
+        assert_eq!(summary.received, 0);
+        assert_eq!(summary.loss, 1.0);
+        assert!(summary.p95.is_none() && summary.stddev.is_none());
+    }
+
+    #[test]
+    fn done_ignores_peers_that_dialed_us() {
+        let target = PeerId::random();
+        let inbound = PeerId::random();
+        let mut stats = Stats::default();
+        stats.record_target(target);
+        stats.record_rtt(inbound, Duration::from_millis(1));
+        assert_eq!(stats.done(1), 0);
+        stats.record_rtt(target, Duration::from_millis(1));
+        assert_eq!(stats.done(1), 1);
+    }
+
+    #[test]
+    fn discovered_targets_are_expected_once() {
+        let given = PeerId::random();
+        let mut stats = Stats::default();
+        stats.record_target(given);
+        assert_eq!(stats.expected(1), 1);
+        // mDNS finds the peer we were pointed at, and one more.
+        stats.record_target(given);
+        stats.record_target(PeerId::random());
+        assert_eq!(stats.expected(1), 2);
+        stats.record_rtt(given, Duration::from_millis(1));
+        assert!(stats.done(1) < stats.expected(1));
+    }
+
+    #[test]
+    fn targets_without_peer_id_are_expected_before_dialing() {
+        let named = PeerId::random();
+        let mut stats = Stats::default();
+        stats.record_target(named);
+        stats.record_rtt(named, Duration::from_millis(1));
+        assert_eq!(stats.expected(2), 2);
+        assert!(stats.done(1) < stats.expected(2));
+        stats.record_unreachable_addr("/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        assert_eq!(stats.done(1), stats.expected(2));
+    }
+
+    #[test]
+    fn unreachable_counts_each_target_once() {
+        let target = PeerId::random();
+        let mut stats = Stats::default();
+        stats.record_target(target);
+        stats.record_unreachable_peer(target);
+        stats.record_unreachable_peer(target);
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        stats.record_unreachable_addr(addr.clone());
+        stats.record_unreachable_addr(addr);
+        assert_eq!(stats.unreachable(), 2);
+        assert_eq!(stats.done(1), 2);
+    }
+
+    #[test]
+    fn nothing_received_fails_only_with_targets() {
+        let stats = Stats::default();
+        assert!(!stats.failed(false));
+        assert!(stats.failed(true));
+    }
+}
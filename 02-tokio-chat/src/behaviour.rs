@@ -0,0 +1,244 @@
+use crate::{
+    chat::{Chat, Outbound},
+    dm::{Direct, DmRequest, DmResponse},
+    output::Output,
+    store::{StoreForward, StoreRequest, StoreResponse},
+    sync::{HistorySync, SyncRequest, SyncResponse},
+    transfer::{FileRequest, FileResponse, FileTransfer},
+};
+use libp2p::{
+    floodsub::{Floodsub, FloodsubEvent},
+    mdns::{Mdns, MdnsEvent},
+    request_response::{RequestResponseEvent, RequestResponseMessage},
+    swarm::NetworkBehaviourEventProcess,
+    NetworkBehaviour,
+};
+use log::{debug, warn};
+
+#[derive(NetworkBehaviour)]
+#[behaviour(event_process = true)]
+pub struct MyBehaviour {
+    pub floodsub: Floodsub,
+    pub mdns: Mdns,
+    pub history_sync: HistorySync,
+    pub direct: Direct,
+    pub file_transfer: FileTransfer,
+    pub store_forward: StoreForward,
+    #[behaviour(ignore)]
+    pub chat: Chat,
+}
+
+impl MyBehaviour {
+    pub fn handle_line(&mut self, line: &str) {
+        self.chat.handle_line(&mut self.floodsub, line);
+        self.send_outbound();
+    }
+
+    /// Runs once on the way out.
+    pub fn shutdown(&mut self) {
+        self.chat.shutdown(&mut self.floodsub);
+    }
+
+    fn send_outbound(&mut self) {
+        for outbound in self.chat.take_outbound() {
+            match outbound {
+                Outbound::Sync(peer, request) => {
+                    debug!(
+                        "asking {} for {} since {}",
+                        peer, request.topic, request.since
+                    );
+                    self.history_sync.send_request(&peer, request);
+                }
+                Outbound::Dm(peer, request) => {
+                    let request_id = self.direct.send_request(&peer, request.clone());
+                    self.chat.track_dm(request_id, request);
+                }
+                Outbound::File(peer, request) => {
+                    let id = request.transfer_id().to_owned();
+                    let request_id = self.file_transfer.send_request(&peer, request);
+                    self.chat.transfers.track(request_id, id);
+                }
+                Outbound::Store(peer, request, pending) => {
+                    let request_id = self.store_forward.send_request(&peer, request);
+                    self.chat.track_store(request_id, pending);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<FloodsubEvent> for MyBehaviour {
+    // Called when `floodsub` produces an event.
+    fn inject_event(&mut self, message: FloodsubEvent) {
+        match message {
+            FloodsubEvent::Message(message) => self.chat.receive(&mut self.floodsub, message),
+            FloodsubEvent::Subscribed { peer_id, topic } => {
+                self.chat.subscribed(&mut self.floodsub, peer_id, &topic)
+            }
+            FloodsubEvent::Unsubscribed { peer_id, topic } => {
+                self.chat.unsubscribed(peer_id, &topic)
+            }
+        }
+        self.send_outbound();
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
+    // Called when `mdns` produces an event.
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer, _) in list {
+                    self.floodsub.add_node_to_partial_view(peer);
+                    self.chat.out.send(Output::Discovered(peer));
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer, _) in list {
+                    if !self.mdns.has_node(&peer) {
+                        self.floodsub.remove_node_from_partial_view(&peer);
+                        self.chat.out.send(Output::Expired(peer));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<SyncRequest, SyncResponse>> for MyBehaviour {
+    // Called when `history_sync` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<SyncRequest, SyncResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = self.chat.serve_sync(&request);
+                    if self.history_sync.send_response(channel, response).is_err() {
+                        debug!("{} went away before its history arrived", peer);
+                    }
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    self.chat.merge_sync(peer, response);
+                    self.send_outbound();
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                warn!("history sync with {} failed: {}", peer, error)
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("history sync from {} failed: {}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<DmRequest, DmResponse>> for MyBehaviour {
+    // Called when `direct` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<DmRequest, DmResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = self.chat.receive_dm(peer, &request);
+                    if self.direct.send_response(channel, response).is_err() {
+                        debug!("{} went away before we acknowledged its message", peer);
+                    }
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    if response.delivered {
+                        self.chat.dm_delivered(request_id)
+                    } else {
+                        self.chat.dm_refused(request_id, peer)
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => self.chat.dm_failed(request_id, peer, &error.to_string()),
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("direct message from {} failed: {}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+        self.send_outbound();
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<FileRequest, FileResponse>> for MyBehaviour {
+    // Called when `file_transfer` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<FileRequest, FileResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = self.chat.receive_file_request(peer, request);
+                    if self.file_transfer.send_response(channel, response).is_err() {
+                        debug!("{} went away during a file transfer", peer);
+                    }
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => self.chat.receive_file_response(peer, request_id, response),
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!("file request to {} failed: {}", peer, error);
+                self.chat.file_request_failed(request_id);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("file request from {} failed: {}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+        self.send_outbound();
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<StoreRequest, StoreResponse>>
+    for MyBehaviour
+{
+    // Called when `store_forward` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<StoreRequest, StoreResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = self.chat.receive_store_request(peer, request);
+                    if self.store_forward.send_response(channel, response).is_err() {
+                        debug!("{} went away before we answered its store request", peer);
+                    }
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => self.chat.receive_store_response(peer, request_id, response),
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => self
+                .chat
+                .store_request_failed(peer, request_id, &error.to_string()),
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("store request from {} failed: {}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+        self.send_outbound();
+    }
+}
@@ -0,0 +1,950 @@
+use crate::{
+    command::{Command, Scrollback},
+    dm::{DmError, DmKeys, DmRequest, DmResponse},
+    history::{self, Entry, History},
+    output::{Output, Outputs},
+    store::{store_topic, Mailbox, StoreRequest, StoreResponse},
+    sync::{SyncRequest, SyncResponse, MAX_ENTRIES},
+    transfer::{FileRequest, FileResponse, Transfers},
+};
+use libp2p::request_response::RequestId;
+use libp2p::{
+    floodsub::{Floodsub, FloodsubMessage, Topic},
+    PeerId,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::Path,
+};
+
+/// Direct messages in either direction are kept under this history topic.
+const DIRECT_HISTORY: &str = "dm";
+
+/// What peers send each other on the room and direct message topics.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    /// A line said in a room.
+    Chat {
+        id: String,
+        timestamp: u64,
+        nick: String,
+        room: String,
+        text: String,
+    },
+    /// A line for a single peer, sent on that peer's own topic.
+    Direct {
+        id: String,
+        timestamp: u64,
+        nick: String,
+        text: String,
+    },
+    /// Announces a newcomer, everyone in the room answers with `Presence`.
+    Join {
+        nick: String,
+        room: String,
+    },
+    /// Tells the room who is there, also sent after a nick change.
+    Presence {
+        nick: String,
+        room: String,
+    },
+    Leave {
+        nick: String,
+        room: String,
+    },
+}
+
+pub fn room_topic(room: &str) -> Topic {
+    Topic::new(format!("room/{}", room))
+}
+
+/// Every peer listens on its own topic for direct messages.
+pub fn direct_topic(peer: &PeerId) -> Topic {
+    Topic::new(format!("user/{}", peer.to_base58()))
+}
+
+/// Nickname, joined rooms and the members seen in each of them.
+pub struct Chat {
+    local_peer_id: PeerId,
+    nick: String,
+    current: String,
+    rooms: BTreeSet<String>,
+    members: HashMap<String, BTreeMap<PeerId, String>>,
+    history: Option<History>,
+    /// Timestamp used for the last message id we handed out.
+    last_sent: u64,
+    /// Remote peers by the topics they told floodsub they subscribe to.
+    subscribers: HashMap<String, HashSet<PeerId>>,
+    keys: Option<DmKeys>,
+    pub transfers: Transfers,
+    /// Requests for the behaviour to send.
+    outbound: Vec<Outbound>,
+    /// Everything meant for the user goes through here.
+    pub out: Outputs,
+    /// Direct messages on their way, kept for a store node should the
+    /// recipient turn out to be offline.
+    sending: HashMap<RequestId, DmRequest>,
+    /// Ids of direct messages opened already, a stored copy may arrive
+    /// more than once.
+    opened: HashSet<String>,
+    /// Set when we keep messages for offline peers.
+    mailbox: Option<Mailbox>,
+    /// What each outstanding store-and-forward request was about.
+    store_requests: HashMap<RequestId, StorePending>,
+}
+
+/// Something `Chat` wants sent over one of the request-response protocols.
+pub enum Outbound {
+    Sync(PeerId, SyncRequest),
+    Dm(PeerId, DmRequest),
+    File(PeerId, FileRequest),
+    Store(PeerId, StoreRequest, StorePending),
+}
+
+pub enum StorePending {
+    /// We asked a store node to hold a message for this peer.
+    Put(PeerId),
+    /// We handed a held message to its recipient, the key is where it is
+    /// kept in the mailbox.
+    Deliver(Vec<u8>),
+}
+
+impl Chat {
+    pub fn new(
+        local_peer_id: PeerId,
+        nick: String,
+        history: Option<History>,
+        keys: Option<DmKeys>,
+        transfers: Transfers,
+        out: Outputs,
+        mailbox: Option<Mailbox>,
+    ) -> Self {
+        Chat {
+            local_peer_id,
+            nick,
+            current: String::new(),
+            rooms: BTreeSet::new(),
+            members: HashMap::new(),
+            history,
+            last_sent: 0,
+            subscribers: HashMap::new(),
+            keys,
+            transfers,
+            outbound: Vec::new(),
+            out,
+            sending: HashMap::new(),
+            opened: HashSet::new(),
+            mailbox,
+            store_requests: HashMap::new(),
+        }
+    }
+
+    pub fn start(&mut self, floodsub: &mut Floodsub, room: String) {
+        floodsub.subscribe(direct_topic(&self.local_peer_id));
+        if self.mailbox.is_some() {
+            floodsub.subscribe(store_topic());
+        }
+        self.join(floodsub, room);
+    }
+
+    /// Handles one line typed by the user.
+    pub fn handle_line(&mut self, floodsub: &mut Floodsub, line: &str) {
+        let command = match Command::parse(line) {
+            Ok(command) => command,
+            Err(usage) => return self.out.line(usage),
+        };
+        match command {
+            Command::Say(text) if text.is_empty() => {}
+            Command::Say(text) => {
+                if self.current.is_empty() {
+                    return self.out.line("not in a room, /join one first");
+                }
+                let room = self.current.clone();
+                let (id, timestamp) = self.next_id();
+                self.record(Entry {
+                    id: id.clone(),
+                    topic: room_topic(&room).id().to_owned(),
+                    sender: self.local_peer_id.to_base58(),
+                    nick: self.nick.clone(),
+                    timestamp,
+                    text: text.clone(),
+                    via: None,
+                });
+                publish(
+                    floodsub,
+                    room_topic(&room),
+                    &Payload::Chat {
+                        id,
+                        timestamp,
+                        nick: self.nick.clone(),
+                        room,
+                        text,
+                    },
+                );
+            }
+            Command::Nick(nick) => {
+                self.out.line(format!("you are now known as {}", nick));
+                self.nick = nick;
+                for room in self.rooms.clone() {
+                    self.announce(floodsub, room);
+                }
+            }
+            Command::Join(room) => self.join(floodsub, room),
+            Command::Leave(room) => {
+                let room = room.unwrap_or_else(|| self.current.clone());
+                self.leave(floodsub, room);
+            }
+            Command::Rooms => {
+                for room in &self.rooms {
+                    let marker = if *room == self.current { "*" } else { " " };
+                    self.out.line(format!("{} #{}", marker, room));
+                }
+            }
+            Command::Who => match self.members.get(&self.current) {
+                Some(members) => {
+                    self.out
+                        .line(format!("in #{}: {} (you)", self.current, self.nick));
+                    for (peer, nick) in members {
+                        self.out.line(format!("  {} ({})", nick, peer));
+                    }
+                }
+                None => self.out.line("not in a room"),
+            },
+            Command::Msg { to, text } => match self.resolve(&to) {
+                Some(peer) => {
+                    let (id, timestamp) = self.next_id();
+                    self.record(Entry {
+                        id: id.clone(),
+                        topic: DIRECT_HISTORY.to_owned(),
+                        sender: self.local_peer_id.to_base58(),
+                        nick: self.nick.clone(),
+                        timestamp,
+                        text: text.clone(),
+                        via: None,
+                    });
+                    let direct = Payload::Direct {
+                        id,
+                        timestamp,
+                        nick: self.nick.clone(),
+                        text,
+                    };
+                    // We are not subscribed to the other peer's topic, which
+                    // `publish` would insist on.
+                    let data = serde_json::to_vec(&direct).expect("payload serializes to JSON");
+                    floodsub.publish_any(direct_topic(&peer), data);
+                }
+                None => self.out.line(format!("no peer known as {}, see /who", to)),
+            },
+            Command::Dm { to, text } => match self.resolve(&to) {
+                Some(peer) => self.send_dm(peer, text),
+                None => self.out.line(format!("no peer known as {}, see /who", to)),
+            },
+            Command::Send { to, path } => self.send_file(&to, &path),
+            Command::Transfers => {
+                let transfers = self.transfers.list();
+                if transfers.is_empty() {
+                    self.out.line("no transfers");
+                }
+                for transfer in transfers {
+                    self.out.line(format!("[file] {}", transfer));
+                }
+            }
+            Command::History { of, count } => self.show_history(of, count),
+        }
+    }
+
+    /// Leaves every room so peers see us go, and makes sure history is on
+    /// disk.
+    pub fn shutdown(&mut self, floodsub: &mut Floodsub) {
+        for room in self.rooms.clone() {
+            self.leave(floodsub, room);
+        }
+        if let Some(history) = &self.history {
+            if let Err(err) = history.flush() {
+                warn!("could not flush history: {}", err);
+            }
+        }
+    }
+
+    /// Handles a message published by another peer.
+    pub fn receive(&mut self, floodsub: &mut Floodsub, message: FloodsubMessage) {
+        let source = message.source;
+        let payload = match serde_json::from_slice::<Payload>(&message.data) {
+            Ok(payload) => payload,
+            Err(_) => {
+                return self.out.line(format!(
+                    "Received: '{:?}' from {:?}",
+                    String::from_utf8_lossy(&message.data),
+                    source
+                ))
+            }
+        };
+        let claimed = match &payload {
+            Payload::Chat { room, .. }
+            | Payload::Join { room, .. }
+            | Payload::Presence { room, .. }
+            | Payload::Leave { room, .. } => room_topic(room),
+            Payload::Direct { .. } => direct_topic(&self.local_peer_id),
+        };
+        // A payload speaks only for the topic it came on, or anyone could
+        // post into rooms they are not in.
+        if !message.topics.contains(&claimed) {
+            return warn!(
+                "{} sent a message for {} on another topic",
+                source,
+                claimed.id()
+            );
+        }
+        match payload {
+            Payload::Chat {
+                id,
+                timestamp,
+                nick,
+                room,
+                text,
+            } => {
+                let entry = Entry {
+                    id,
+                    topic: claimed.id().to_owned(),
+                    sender: source.to_base58(),
+                    nick,
+                    timestamp,
+                    text,
+                    via: None,
+                };
+                // The id has to be the sender's own, or it could take the
+                // place of someone else's message in history.
+                if !entry.is_valid() {
+                    return warn!("{} sent a message with id {}", source, entry.id);
+                }
+                self.seen(&room, source, &entry.nick);
+                self.out
+                    .room_line(&room, format!("<{}> {}", entry.nick, entry.text));
+                self.record(entry);
+            }
+            Payload::Direct {
+                id,
+                timestamp,
+                nick,
+                text,
+            } => {
+                let entry = Entry {
+                    id,
+                    topic: DIRECT_HISTORY.to_owned(),
+                    sender: source.to_base58(),
+                    nick,
+                    timestamp,
+                    text,
+                    via: None,
+                };
+                if !entry.is_valid() {
+                    return warn!("{} sent a message with id {}", source, entry.id);
+                }
+                self.out
+                    .line(format!("[dm] <{}> {}", entry.nick, entry.text));
+                self.record(entry);
+            }
+            Payload::Join { nick, room } => {
+                if self.seen(&room, source, &nick) {
+                    self.out.room_line(&room, format!("{} joined", nick));
+                }
+                self.announce(floodsub, room);
+            }
+            Payload::Presence { nick, room } => {
+                self.seen(&room, source, &nick);
+            }
+            Payload::Leave { nick, room } => {
+                if let Some(members) = self.members.get_mut(&room) {
+                    members.remove(&source);
+                }
+                self.out.room_line(&room, format!("{} left", nick));
+            }
+        }
+    }
+
+    /// A peer subscribed to a topic. If it is one of our rooms, tell it who
+    /// we are so its `/who` and `/msg` know about us, and ask it for
+    /// anything we missed there.
+    pub fn subscribed(&mut self, floodsub: &mut Floodsub, peer: PeerId, topic: &Topic) {
+        self.subscribers
+            .entry(topic.id().to_owned())
+            .or_default()
+            .insert(peer);
+        let room = self
+            .rooms
+            .iter()
+            .find(|room| room_topic(room).id() == topic.id())
+            .cloned();
+        if let Some(room) = room {
+            self.announce(floodsub, room);
+            self.request_sync(peer, topic.id());
+        }
+        // Floodsub tells us about every subscription of a peer that just
+        // (re)connected, a good moment to pick stalled downloads back up.
+        if topic.id() == direct_topic(&peer).id() {
+            for request in self.transfers.resume(peer) {
+                self.outbound.push(Outbound::File(peer, request));
+            }
+            self.deliver_held(peer);
+        }
+    }
+
+    pub fn unsubscribed(&mut self, peer: PeerId, topic: &Topic) {
+        if let Some(peers) = self.subscribers.get_mut(topic.id()) {
+            peers.remove(&peer);
+        }
+    }
+
+    /// Requests queued since the last call.
+    pub fn take_outbound(&mut self) -> Vec<Outbound> {
+        std::mem::take(&mut self.outbound)
+    }
+
+    /// Opens an encrypted direct message from `peer`.
+    pub fn receive_dm(&mut self, peer: PeerId, request: &DmRequest) -> DmResponse {
+        let opened = match &self.keys {
+            Some(keys) => keys.open(&peer, request),
+            None => Err(DmError::NoKey),
+        };
+        let payload = opened
+            .ok()
+            .and_then(|plaintext| serde_json::from_slice::<Payload>(&plaintext).ok());
+        match payload {
+            Some(Payload::Direct { id, .. }) if !self.opened.insert(id.clone()) => {
+                DmResponse { delivered: true }
+            }
+            Some(Payload::Direct {
+                id,
+                timestamp,
+                nick,
+                text,
+            }) => {
+                self.out.line(format!("[dm e2e] <{}> {}", nick, text));
+                self.record(Entry {
+                    id,
+                    topic: DIRECT_HISTORY.to_owned(),
+                    sender: peer.to_base58(),
+                    nick,
+                    timestamp,
+                    text,
+                    via: None,
+                });
+                DmResponse { delivered: true }
+            }
+            _ => {
+                warn!("dropping direct message from {} we could not open", peer);
+                DmResponse { delivered: false }
+            }
+        }
+    }
+
+    /// Handles an offer or a chunk request from `peer`.
+    pub fn receive_file_request(&mut self, peer: PeerId, request: FileRequest) -> FileResponse {
+        match request {
+            FileRequest::Offer(offer) => {
+                self.out.line(format!(
+                    "[file] receiving {} ({} bytes) from {}",
+                    offer.name,
+                    offer.size,
+                    self.display_name(&peer)
+                ));
+                let (response, next) = self.transfers.receive_offer(peer, offer);
+                match next {
+                    Ok(request) => self.outbound.push(Outbound::File(peer, request)),
+                    Err(Some(line)) => self.out.line(format!("[file] {}", line)),
+                    Err(None) => {}
+                }
+                if let FileResponse::Refused { reason } = &response {
+                    self.out.line(format!("[file] refused: {}", reason));
+                }
+                response
+            }
+            FileRequest::Chunk { id, index } => self.transfers.serve_chunk(peer, &id, index),
+        }
+    }
+
+    pub fn receive_file_response(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        response: FileResponse,
+    ) {
+        let (next, line) = self.transfers.receive_response(peer, request_id, response);
+        if let Some(request) = next {
+            self.outbound.push(Outbound::File(peer, request));
+        }
+        if let Some(line) = line {
+            self.out.line(format!("[file] {}", line));
+        }
+    }
+
+    pub fn file_request_failed(&mut self, request_id: RequestId) {
+        if let Some(line) = self.transfers.request_failed(request_id) {
+            self.out.line(format!("[file] {}", line));
+        }
+    }
+
+    /// Remembers a direct message we just sent, in case it needs storing.
+    pub fn track_dm(&mut self, request_id: RequestId, request: DmRequest) {
+        self.sending.insert(request_id, request);
+    }
+
+    pub fn dm_delivered(&mut self, request_id: RequestId) {
+        self.sending.remove(&request_id);
+    }
+
+    /// `peer` got the message but could not open it, storing it would not
+    /// help.
+    pub fn dm_refused(&mut self, request_id: RequestId, peer: PeerId) {
+        self.sending.remove(&request_id);
+        self.out.line(format!(
+            "[dm e2e] could not deliver to {}: they could not decrypt it",
+            peer
+        ));
+    }
+
+    /// `peer` could not be reached. Leaves the message with every store node
+    /// we know of, or gives up if there is none.
+    pub fn dm_failed(&mut self, request_id: RequestId, peer: PeerId, reason: &str) {
+        let request = match self.sending.remove(&request_id) {
+            Some(request) => request,
+            None => return,
+        };
+        let stores: Vec<PeerId> = self
+            .subscribers
+            .get(store_topic().id())
+            .map(|stores| {
+                stores
+                    .iter()
+                    .filter(|store| **store != peer)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+        if stores.is_empty() {
+            return self.out.line(format!(
+                "[dm e2e] could not deliver to {}: {}",
+                peer, reason
+            ));
+        }
+        self.out.line(format!(
+            "[dm e2e] {} is offline, leaving the message with {} store node(s)",
+            self.display_name(&peer),
+            stores.len()
+        ));
+        for store in stores {
+            let put = StoreRequest::Put {
+                to: peer.to_base58(),
+                message: request.clone(),
+            };
+            self.outbound
+                .push(Outbound::Store(store, put, StorePending::Put(peer)));
+        }
+    }
+
+    pub fn track_store(&mut self, request_id: RequestId, pending: StorePending) {
+        self.store_requests.insert(request_id, pending);
+    }
+
+    /// A peer asks us to hold a message, or a store node hands us one.
+    pub fn receive_store_request(&mut self, peer: PeerId, request: StoreRequest) -> StoreResponse {
+        match request {
+            StoreRequest::Put { to, message } => {
+                let mailbox = match &mut self.mailbox {
+                    Some(mailbox) => mailbox,
+                    None => {
+                        let reason = "not a store node".to_owned();
+                        return StoreResponse::Refused { reason };
+                    }
+                };
+                let to: PeerId = match to.parse() {
+                    Ok(to) => to,
+                    Err(_) => {
+                        let reason = "bad recipient".to_owned();
+                        return StoreResponse::Refused { reason };
+                    }
+                };
+                if let Err(err) = mailbox.put(&peer, &to, message) {
+                    warn!("could not store message for {}: {}", to, err);
+                    let reason = err.to_string();
+                    return StoreResponse::Refused { reason };
+                }
+                // It may have come back while the sender was still trying.
+                let online = self
+                    .subscribers
+                    .get(direct_topic(&to).id())
+                    .is_some_and(|peers| peers.contains(&to));
+                if online {
+                    self.deliver_held(to);
+                }
+                StoreResponse::Stored
+            }
+            StoreRequest::Deliver { from, message } => {
+                match from.parse() {
+                    Ok(from) => {
+                        self.receive_dm(from, &message);
+                    }
+                    Err(_) => warn!("dropping stored message with bad sender {}", from),
+                }
+                // Whether it opened or not, holding it longer will not help.
+                StoreResponse::Ack
+            }
+        }
+    }
+
+    pub fn receive_store_response(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        response: StoreResponse,
+    ) {
+        match (self.store_requests.remove(&request_id), response) {
+            (Some(StorePending::Put(to)), StoreResponse::Stored) => self.out.line(format!(
+                "[dm e2e] {} holds the message until {} is back",
+                peer,
+                self.display_name(&to)
+            )),
+            (Some(StorePending::Put(_)), StoreResponse::Refused { reason }) => self
+                .out
+                .line(format!("[dm e2e] store node {} refused: {}", peer, reason)),
+            (Some(StorePending::Deliver(key)), StoreResponse::Ack) => {
+                if let Some(mailbox) = &mut self.mailbox {
+                    if let Err(err) = mailbox.remove(&key) {
+                        warn!("could not forget delivered message: {}", err);
+                    }
+                }
+            }
+            (Some(_), response) => warn!("unexpected answer from {}: {:?}", peer, response),
+            (None, _) => {}
+        }
+    }
+
+    pub fn store_request_failed(&mut self, peer: PeerId, request_id: RequestId, reason: &str) {
+        // A failed delivery stays in the mailbox for the next time.
+        if let Some(StorePending::Put(to)) = self.store_requests.remove(&request_id) {
+            self.out.line(format!(
+                "[dm e2e] could not leave the message for {} with {}: {}",
+                self.display_name(&to),
+                peer,
+                reason
+            ));
+        }
+    }
+
+    /// Drops held messages that outlived the TTL.
+    pub fn purge_mailbox(&mut self) {
+        if let Some(mailbox) = &mut self.mailbox {
+            if let Err(err) = mailbox.purge() {
+                warn!("could not purge mailbox: {}", err);
+            }
+        }
+    }
+
+    /// Hands `peer` whatever we hold for it, skipping messages already on
+    /// their way.
+    fn deliver_held(&mut self, peer: PeerId) {
+        let held = match &self.mailbox {
+            Some(mailbox) => match mailbox.held_for(&peer) {
+                Ok(held) => held,
+                Err(err) => return warn!("could not read mailbox: {}", err),
+            },
+            None => return,
+        };
+        let in_flight: HashSet<&[u8]> = self
+            .store_requests
+            .values()
+            .filter_map(|pending| match pending {
+                StorePending::Deliver(key) => Some(key.as_slice()),
+                StorePending::Put(_) => None,
+            })
+            .collect();
+        let mut requests = Vec::new();
+        for (key, held) in held {
+            if in_flight.contains(key.as_slice()) {
+                continue;
+            }
+            let deliver = StoreRequest::Deliver {
+                from: held.from,
+                message: held.message,
+            };
+            requests.push(Outbound::Store(peer, deliver, StorePending::Deliver(key)));
+        }
+        self.outbound.extend(requests);
+    }
+
+    /// Answers a peer asking for room history. Direct messages are never
+    /// handed out.
+    pub fn serve_sync(&self, request: &SyncRequest) -> SyncResponse {
+        let history = match &self.history {
+            Some(history) if request.topic.starts_with("room/") => history,
+            _ => return SyncResponse::default(),
+        };
+        match history.since(&request.topic, request.since, MAX_ENTRIES) {
+            Ok(entries) => SyncResponse::page(entries),
+            Err(err) => {
+                warn!("could not read history: {}", err);
+                SyncResponse::default()
+            }
+        }
+    }
+
+    /// Merges what a peer sent back into local history, messages we hold
+    /// already are skipped by id. A full page is followed by a request for
+    /// the next one.
+    pub fn merge_sync(&mut self, peer: PeerId, response: SyncResponse) {
+        let next = response.next_page();
+        let from = peer.to_base58();
+        let mut merged = HashMap::<String, usize>::new();
+        for mut entry in response.entries {
+            let room = match entry.topic.strip_prefix("room/") {
+                Some(room) if self.rooms.contains(room) && entry.is_valid() => room.to_owned(),
+                _ => continue,
+            };
+            // Only its sender vouches for a message, anyone else could
+            // have made it up.
+            entry.via = (entry.sender != from).then(|| from.clone());
+            if self.record(entry) {
+                *merged.entry(room).or_default() += 1;
+            }
+        }
+        if let Some(request) = next {
+            let joined = request
+                .topic
+                .strip_prefix("room/")
+                .is_some_and(|room| self.rooms.contains(room));
+            // Timestamps are the peer's to choose, pages never go past now.
+            if joined && request.since <= history::now() {
+                self.outbound.push(Outbound::Sync(peer, request));
+            }
+        }
+        for (room, count) in merged {
+            self.out.room_line(
+                &room,
+                format!("{} earlier message(s) from {}, see /history", count, peer),
+            );
+        }
+    }
+
+    fn join(&mut self, floodsub: &mut Floodsub, room: String) {
+        if self.rooms.insert(room.clone()) {
+            floodsub.subscribe(room_topic(&room));
+            self.members.insert(room.clone(), BTreeMap::new());
+            let join = Payload::Join {
+                nick: self.nick.clone(),
+                room: room.clone(),
+            };
+            publish(floodsub, room_topic(&room), &join);
+            let topic = room_topic(&room);
+            let peers: Vec<_> = self
+                .subscribers
+                .get(topic.id())
+                .map(|peers| peers.iter().copied().collect())
+                .unwrap_or_default();
+            for peer in peers {
+                self.request_sync(peer, topic.id());
+            }
+        }
+        self.out.line(format!("now talking in #{}", room));
+        self.current = room;
+        self.show_rooms();
+    }
+
+    fn leave(&mut self, floodsub: &mut Floodsub, room: String) {
+        if !self.rooms.remove(&room) {
+            return self.out.line(format!("not in #{}", room));
+        }
+        let leave = Payload::Leave {
+            nick: self.nick.clone(),
+            room: room.clone(),
+        };
+        publish(floodsub, room_topic(&room), &leave);
+        floodsub.unsubscribe(room_topic(&room));
+        self.members.remove(&room);
+        self.out.line(format!("left #{}", room));
+        if self.current == room {
+            self.current = self.rooms.iter().next().cloned().unwrap_or_default();
+            if !self.current.is_empty() {
+                self.out.line(format!("now talking in #{}", self.current));
+            }
+        }
+        self.show_rooms();
+    }
+
+    fn show_rooms(&self) {
+        self.out.send(Output::Rooms {
+            rooms: self.rooms.iter().cloned().collect(),
+            current: self.current.clone(),
+        });
+    }
+
+    fn announce(&self, floodsub: &mut Floodsub, room: String) {
+        let presence = Payload::Presence {
+            nick: self.nick.clone(),
+            room: room.clone(),
+        };
+        publish(floodsub, room_topic(&room), &presence);
+    }
+
+    fn send_file(&mut self, to: &str, path: &str) {
+        let peers: Vec<PeerId> = match to.strip_prefix('#') {
+            Some(room) => self
+                .subscribers
+                .get(room_topic(room).id())
+                .map(|peers| peers.iter().copied().collect())
+                .unwrap_or_default(),
+            None => self.resolve(to).into_iter().collect(),
+        };
+        if peers.is_empty() {
+            return self
+                .out
+                .line(format!("nobody to send to at {}, see /who", to));
+        }
+        for peer in peers {
+            match self.transfers.offer(peer, Path::new(path)) {
+                Ok(offer) => {
+                    self.out.line(format!(
+                        "[file] offering {} ({} bytes) to {}",
+                        offer.name,
+                        offer.size,
+                        self.display_name(&peer)
+                    ));
+                    self.outbound
+                        .push(Outbound::File(peer, FileRequest::Offer(offer)));
+                }
+                Err(err) => return self.out.line(format!("cannot send {}: {}", path, err)),
+            }
+        }
+    }
+
+    /// The nickname we last saw `peer` use, or its id.
+    fn display_name(&self, peer: &PeerId) -> String {
+        self.members
+            .values()
+            .find_map(|members| members.get(peer).cloned())
+            .unwrap_or_else(|| peer.to_base58())
+    }
+
+    fn send_dm(&mut self, peer: PeerId, text: String) {
+        let (id, timestamp) = self.next_id();
+        let direct = Payload::Direct {
+            id: id.clone(),
+            timestamp,
+            nick: self.nick.clone(),
+            text: text.clone(),
+        };
+        let plaintext = serde_json::to_vec(&direct).expect("payload serializes to JSON");
+        let sealed = match &self.keys {
+            Some(keys) => keys.seal(&peer, &plaintext),
+            None => return self.out.line("/dm needs an ed25519 identity"),
+        };
+        match sealed {
+            Ok(request) => {
+                self.outbound.push(Outbound::Dm(peer, request));
+                self.record(Entry {
+                    id,
+                    topic: DIRECT_HISTORY.to_owned(),
+                    sender: self.local_peer_id.to_base58(),
+                    nick: self.nick.clone(),
+                    timestamp,
+                    text,
+                    via: None,
+                });
+            }
+            Err(err) => self
+                .out
+                .line(format!("cannot encrypt for {}: {}", peer, err)),
+        }
+    }
+
+    /// Message ids are the sender's peer id and a millisecond timestamp
+    /// that never repeats for this peer.
+    fn next_id(&mut self) -> (String, u64) {
+        let timestamp = history::now().max(self.last_sent + 1);
+        self.last_sent = timestamp;
+        let id = format!("{}/{}", self.local_peer_id.to_base58(), timestamp);
+        (id, timestamp)
+    }
+
+    /// Stores `entry` if history is on, returns whether it was new.
+    fn record(&self, entry: Entry) -> bool {
+        match &self.history {
+            Some(history) => history.record(&entry).unwrap_or_else(|err| {
+                warn!("could not store message: {}", err);
+                false
+            }),
+            None => false,
+        }
+    }
+
+    /// Asks `peer` for messages on `topic` within the sync horizon, which
+    /// fills gaps anywhere in it rather than only after the newest message
+    /// we hold. Without local history there is nowhere to put them.
+    fn request_sync(&mut self, peer: PeerId, topic: &str) {
+        if self.history.is_none() {
+            return;
+        }
+        let request = SyncRequest::within_horizon(topic, history::now());
+        self.outbound.push(Outbound::Sync(peer, request));
+    }
+
+    fn show_history(&self, of: Option<Scrollback>, count: usize) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => {
+                return self
+                    .out
+                    .line("history is off, start with --history-db <path>")
+            }
+        };
+        let (label, topic) = match of {
+            Some(Scrollback::Direct) => ("dm".to_owned(), DIRECT_HISTORY.to_owned()),
+            Some(Scrollback::Room(room)) => {
+                (format!("#{}", room), room_topic(&room).id().to_owned())
+            }
+            None if self.current.is_empty() => return self.out.line("not in a room"),
+            None => (
+                format!("#{}", self.current),
+                room_topic(&self.current).id().to_owned(),
+            ),
+        };
+        match history.recent(&topic, count) {
+            Ok(entries) if entries.is_empty() => self.out.line(format!("no history for {}", label)),
+            Ok(entries) => {
+                for entry in entries {
+                    self.out.line(format!("[{}] {}", label, entry));
+                }
+            }
+            Err(err) => self.out.line(format!("could not read history: {}", err)),
+        }
+    }
+
+    /// Records `nick` for `peer` in `room`, returns whether it is new there.
+    fn seen(&mut self, room: &str, peer: PeerId, nick: &str) -> bool {
+        let previous = match self.members.get_mut(room) {
+            Some(members) => members.insert(peer, nick.to_owned()),
+            None => return false,
+        };
+        if previous.as_deref() != Some(nick) {
+            self.out.send(Output::Nick(peer, nick.to_owned()));
+        }
+        previous.is_none()
+    }
+
+    /// Looks a target up by nickname first, then as a peer id.
+    fn resolve(&self, target: &str) -> Option<PeerId> {
+        self.members
+            .values()
+            .flat_map(|members| members.iter())
+            .find(|(_, nick)| nick.as_str() == target)
+            .map(|(peer, _)| *peer)
+            .or_else(|| target.parse().ok())
+    }
+}
+
+fn publish(floodsub: &mut Floodsub, topic: Topic, payload: &Payload) {
+    let data = serde_json::to_vec(payload).expect("payload serializes to JSON");
+    floodsub.publish(topic, data);
+}
@@ -0,0 +1,213 @@
+/// A line typed by the user, either a slash command or text for the
+/// current room.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Nick(String),
+    Join(String),
+    Leave(Option<String>),
+    Rooms,
+    Who,
+    Msg {
+        to: String,
+        text: String,
+    },
+    /// Like `Msg`, but end-to-end encrypted and sent straight to the peer.
+    Dm {
+        to: String,
+        text: String,
+    },
+    /// Offers a file to a peer, or to everyone in a room.
+    Send {
+        to: String,
+        path: String,
+    },
+    Transfers,
+    /// Scrollback for a room, the current one if not given.
+    History {
+        of: Option<Scrollback>,
+        count: usize,
+    },
+    Say(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Scrollback {
+    Room(String),
+    Direct,
+}
+
+/// Lines shown by `/history` without a count.
+const HISTORY_COUNT: usize = 20;
+
+pub const HELP: &str = "commands: /nick <name>, /join #room, /leave [#room], /rooms, /who, \
+                        /msg <nick|peer id> <text>, /dm <nick|peer id> <text>, \
+                        /send <nick|peer id|#room> <path>, /transfers, \
+                        /history [#room|dm] [count]";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        if !line.starts_with('/') {
+            return Ok(Command::Say(line.to_owned()));
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let command = parts.next().unwrap_or_default();
+        let rest = parts.next().unwrap_or_default().trim();
+        match command {
+            "/nick" if !rest.is_empty() && !rest.contains(char::is_whitespace) => {
+                Ok(Command::Nick(rest.to_owned()))
+            }
+            "/join" => room_name(rest)
+                .map(Command::Join)
+                .ok_or_else(|| "usage: /join #room".to_owned()),
+            "/leave" if rest.is_empty() => Ok(Command::Leave(None)),
+            "/leave" => room_name(rest)
+                .map(|room| Command::Leave(Some(room)))
+                .ok_or_else(|| "usage: /leave [#room]".to_owned()),
+            "/rooms" => Ok(Command::Rooms),
+            "/who" => Ok(Command::Who),
+            "/msg" => recipient_and_text(rest)
+                .map(|(to, text)| Command::Msg { to, text })
+                .ok_or_else(|| "usage: /msg <nick|peer id> <text>".to_owned()),
+            "/dm" => recipient_and_text(rest)
+                .map(|(to, text)| Command::Dm { to, text })
+                .ok_or_else(|| "usage: /dm <nick|peer id> <text>".to_owned()),
+            "/send" => recipient_and_text(rest)
+                .map(|(to, path)| Command::Send { to, path })
+                .ok_or_else(|| "usage: /send <nick|peer id|#room> <path>".to_owned()),
+            "/transfers" => Ok(Command::Transfers),
+            "/history" => {
+                let usage = || "usage: /history [#room|dm] [count]".to_owned();
+                let mut of = None;
+                let mut count = HISTORY_COUNT;
+                for arg in rest.split_whitespace() {
+                    if arg == "dm" {
+                        of = Some(Scrollback::Direct);
+                    } else if arg.starts_with('#') {
+                        of = Some(Scrollback::Room(room_name(arg).ok_or_else(usage)?));
+                    } else {
+                        count = arg.parse().map_err(|_| usage())?;
+                    }
+                }
+                Ok(Command::History { of, count })
+            }
+            _ => Err(HELP.to_owned()),
+        }
+    }
+}
+
+/// Rooms are written as `#name`, the `#` is optional when typing them.
+fn room_name(name: &str) -> Option<String> {
+    let name = name.trim_start_matches('#');
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+fn recipient_and_text(rest: &str) -> Option<(String, String)> {
+    let mut parts = rest.splitn(2, char::is_whitespace);
+    match (parts.next(), parts.next()) {
+        (Some(to), Some(text)) if !to.is_empty() && !text.trim().is_empty() => {
+            Some((to.to_owned(), text.trim().to_owned()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        Command::parse(line).unwrap()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse("/nick bob"), Command::Nick("bob".into()));
+        assert_eq!(parse("/join #rust"), Command::Join("rust".into()));
+        assert_eq!(parse("/join rust"), Command::Join("rust".into()));
+        assert_eq!(parse("/leave"), Command::Leave(None));
+        assert_eq!(parse("/leave #rust"), Command::Leave(Some("rust".into())));
+        assert_eq!(parse("/rooms"), Command::Rooms);
+        assert_eq!(parse("/who"), Command::Who);
+        assert_eq!(
+            parse("/msg bob hi  there "),
+            Command::Msg {
+                to: "bob".into(),
+                text: "hi  there".into()
+            }
+        );
+        assert_eq!(
+            parse("/dm bob hi"),
+            Command::Dm {
+                to: "bob".into(),
+                text: "hi".into()
+            }
+        );
+        assert_eq!(
+            parse("/send #rust notes.txt"),
+            Command::Send {
+                to: "#rust".into(),
+                path: "notes.txt".into()
+            }
+        );
+        assert_eq!(parse("/transfers"), Command::Transfers);
+        assert_eq!(
+            parse("/history"),
+            Command::History {
+                of: None,
+                count: HISTORY_COUNT
+            }
+        );
+        assert_eq!(
+            parse("/history #rust 5"),
+            Command::History {
+                of: Some(Scrollback::Room("rust".into())),
+                count: 5
+            }
+        );
+        assert_eq!(
+            parse("/history dm"),
+            Command::History {
+                of: Some(Scrollback::Direct),
+                count: HISTORY_COUNT
+            }
+        );
+    }
+
+    #[test]
+    fn missing_arguments_show_usage() {
+        for line in [
+            "/msg",
+            "/msg bob",
+            "/msg bob   ",
+            "/dm",
+            "/dm bob",
+            "/send",
+            "/send bob",
+        ] {
+            let err = Command::parse(line).unwrap_err();
+            assert!(err.starts_with("usage: "), "{}: {}", line, err);
+        }
+        assert!(Command::parse("/join").is_err());
+        assert!(Command::parse("/nick two words").is_err());
+        assert!(Command::parse("/history many").is_err());
+    }
+
+    #[test]
+    fn unknown_commands_show_help() {
+        assert_eq!(Command::parse("/frobnicate"), Err(HELP.to_owned()));
+    }
+
+    #[test]
+    fn plain_text_is_said() {
+        assert_eq!(parse("  hello world "), Command::Say("hello world".into()));
+        assert_eq!(
+            parse("a /msg in text"),
+            Command::Say("a /msg in text".into())
+        );
+    }
+}
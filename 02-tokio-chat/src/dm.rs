@@ -0,0 +1,267 @@
+use crate::codec::{read_json, write_json};
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{identity, upgrade::ProtocolName},
+    noise::{self, X25519},
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, io, iter};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Mixed into the shared secret so the derived key is only ever used here.
+const KEY_CONTEXT: &[u8] = b"/tokio-chat/dm/1.0.0 chacha20poly1305";
+
+/// Multihash code of the identity hash, which peer ids of ed25519 keys use
+/// to carry the key itself.
+const IDENTITY_HASH: u64 = 0;
+
+/// A direct message sealed for one peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DmRequest {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Sent back once a message was opened, or not.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DmResponse {
+    pub delivered: bool,
+}
+
+#[derive(Debug)]
+pub enum DmError {
+    /// The peer id does not embed an ed25519 key we could encrypt to.
+    NoKey,
+    /// Decryption failed, the message was tampered with or not for us.
+    Crypto,
+}
+
+impl fmt::Display for DmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmError::NoKey => write!(f, "peer id carries no ed25519 key"),
+            DmError::Crypto => write!(f, "could not decrypt message"),
+        }
+    }
+}
+
+/// X25519 secret derived from our ed25519 identity. Each pair of peers
+/// shares a key both sides can compute from the other's peer id alone.
+pub struct DmKeys {
+    secret: StaticSecret,
+}
+
+impl DmKeys {
+    /// Only ed25519 identities can be converted.
+    pub fn new(identity: &identity::Keypair) -> Option<Self> {
+        match identity {
+            identity::Keypair::Ed25519(keypair) => {
+                let secret = noise::SecretKey::<X25519>::from_ed25519(&keypair.secret());
+                let mut bytes = [0; 32];
+                bytes.copy_from_slice(secret.as_ref());
+                Some(DmKeys {
+                    secret: StaticSecret::from(bytes),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn seal(&self, peer: &PeerId, plaintext: &[u8]) -> Result<DmRequest, DmError> {
+        let cipher = self.cipher(peer)?;
+        let mut nonce = [0; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| DmError::Crypto)?;
+        Ok(DmRequest {
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    pub fn open(&self, peer: &PeerId, request: &DmRequest) -> Result<Vec<u8>, DmError> {
+        if request.nonce.len() != 12 {
+            return Err(DmError::Crypto);
+        }
+        self.cipher(peer)?
+            .decrypt(
+                Nonce::from_slice(&request.nonce),
+                request.ciphertext.as_slice(),
+            )
+            .map_err(|_| DmError::Crypto)
+    }
+
+    fn cipher(&self, peer: &PeerId) -> Result<ChaCha20Poly1305, DmError> {
+        let public = x25519_public(peer).ok_or(DmError::NoKey)?;
+        let shared = self.secret.diffie_hellman(&public);
+        let key = Sha256::new()
+            .chain(KEY_CONTEXT)
+            .chain(shared.as_bytes())
+            .finalize();
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+/// Recovers the X25519 public key of a peer from its ed25519 peer id.
+fn x25519_public(peer: &PeerId) -> Option<PublicKey> {
+    let multihash = peer.as_ref();
+    if multihash.code() != IDENTITY_HASH {
+        return None;
+    }
+    match identity::PublicKey::from_protobuf_encoding(multihash.digest()).ok()? {
+        identity::PublicKey::Ed25519(public) => {
+            let public = noise::PublicKey::<X25519>::from_ed25519(&public);
+            let mut bytes = [0; 32];
+            bytes.copy_from_slice(public.as_ref());
+            Some(PublicKey::from(bytes))
+        }
+        _ => None,
+    }
+}
+
+/// Request-response behaviour carrying sealed direct messages straight to
+/// the recipient instead of through floodsub.
+pub type Direct = RequestResponse<DmCodec>;
+
+pub fn direct() -> Direct {
+    RequestResponse::new(
+        DmCodec,
+        iter::once((DmProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct DmProtocol;
+
+impl ProtocolName for DmProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/tokio-chat/dm/1.0.0"
+    }
+}
+
+#[derive(Clone)]
+pub struct DmCodec;
+
+#[async_trait]
+impl RequestResponseCodec for DmCodec {
+    type Protocol = DmProtocol;
+    type Request = DmRequest;
+    type Response = DmResponse;
+
+    async fn read_request<T>(&mut self, _: &DmProtocol, io: &mut T) -> io::Result<DmRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &DmProtocol, io: &mut T) -> io::Result<DmResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &DmProtocol,
+        io: &mut T,
+        request: DmRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &DmProtocol,
+        io: &mut T,
+        response: DmResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> (DmKeys, PeerId) {
+        let identity = identity::Keypair::generate_ed25519();
+        let peer = identity.public().to_peer_id();
+        (DmKeys::new(&identity).unwrap(), peer)
+    }
+
+    #[test]
+    fn sealed_messages_open_on_the_other_side() {
+        let ((alice, alice_id), (bob, bob_id)) = (peer(), peer());
+        let sealed = alice.seal(&bob_id, b"hello").unwrap();
+        assert_ne!(sealed.ciphertext, b"hello");
+        assert_eq!(bob.open(&alice_id, &sealed).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn nobody_else_can_open_them() {
+        let ((alice, alice_id), (_, bob_id), (eve, _)) = (peer(), peer(), peer());
+        let sealed = alice.seal(&bob_id, b"hello").unwrap();
+        assert!(matches!(eve.open(&alice_id, &sealed), Err(DmError::Crypto)));
+    }
+
+    #[test]
+    fn tampering_is_noticed() {
+        let ((alice, alice_id), (bob, bob_id)) = (peer(), peer());
+        let sealed = alice.seal(&bob_id, b"hello").unwrap();
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(matches!(
+            bob.open(&alice_id, &tampered),
+            Err(DmError::Crypto)
+        ));
+
+        let mut tampered = sealed.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(matches!(
+            bob.open(&alice_id, &tampered),
+            Err(DmError::Crypto)
+        ));
+
+        let mut tampered = sealed;
+        tampered.nonce.pop();
+        assert!(matches!(
+            bob.open(&alice_id, &tampered),
+            Err(DmError::Crypto)
+        ));
+    }
+
+    #[test]
+    fn only_ed25519_peer_ids_carry_a_key() {
+        let (alice, _) = peer();
+        // An RSA key is too long to inline, its peer id is a sha256 hash.
+        let rsa: PeerId = "QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N"
+            .parse()
+            .unwrap();
+        assert!(x25519_public(&rsa).is_none());
+        assert!(matches!(alice.seal(&rsa, b"hello"), Err(DmError::NoKey)));
+        // Identity hashed, but not a key.
+        assert!(x25519_public(&PeerId::random()).is_none());
+    }
+}
@@ -0,0 +1,218 @@
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError},
+    Transactional,
+};
+use std::{
+    fmt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// One message as kept in the history database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Same on every peer that stores the message, see `Chat::next_id`.
+    pub id: String,
+    pub topic: String,
+    pub sender: String,
+    pub nick: String,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub text: String,
+    /// Peer a history sync got the message from when that was not its
+    /// sender, so nothing vouches for who wrote it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+}
+
+impl Entry {
+    /// `topic \0 timestamp sender`, so a prefix scan over a topic walks its
+    /// messages in time order.
+    fn key(&self) -> Vec<u8> {
+        let mut key = topic_prefix(&self.topic);
+        key.extend_from_slice(&self.timestamp.to_be_bytes());
+        key.extend_from_slice(self.sender.as_bytes());
+        key
+    }
+
+    /// Whether the id is the one `Chat::next_id` gives the sender's
+    /// message at that time.
+    pub fn is_valid(&self) -> bool {
+        !self.topic.is_empty()
+            && !self.sender.is_empty()
+            && self.id == format!("{}/{}", self.sender, self.timestamp)
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(time) = Local.timestamp_millis_opt(self.timestamp as i64).single() {
+            write!(f, "{} ", time.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        write!(f, "<{}> {}", self.nick, self.text)?;
+        if let Some(via) = &self.via {
+            write!(f, " (unverified, via {})", via)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sent and received messages, stored in a sled database.
+#[derive(Clone)]
+pub struct History {
+    db: sled::Db,
+    /// Message id to entry key, so a message is stored once however many
+    /// times it arrives.
+    ids: sled::Tree,
+}
+
+impl History {
+    pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        let ids = db.open_tree("ids")?;
+        Ok(History { db, ids })
+    }
+
+    /// Stores `entry` unless its id is known already, returns whether it was
+    /// new. The id and the entry are written together, a crash in between
+    /// must not leave an id pointing at nothing.
+    pub fn record(&self, entry: &Entry) -> sled::Result<bool> {
+        let key = entry.key();
+        let value = serde_json::to_vec(entry).expect("entry serializes to JSON");
+        let new = (&*self.db, &self.ids)
+            .transaction(|(db, ids)| -> ConflictableTransactionResult<bool> {
+                if ids.insert(entry.id.as_bytes(), key.as_slice())?.is_some() {
+                    return Ok(false);
+                }
+                db.insert(key.as_slice(), value.as_slice())?;
+                Ok(true)
+            })
+            .map_err(|err| match err {
+                TransactionError::Storage(err) => err,
+                TransactionError::Abort(()) => unreachable!("record never aborts"),
+            })?;
+        if new {
+            self.db.flush()?;
+        }
+        Ok(new)
+    }
+
+    pub fn flush(&self) -> sled::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Up to `limit` messages on `topic` from `timestamp` on, oldest first.
+    pub fn since(&self, topic: &str, timestamp: u64, limit: usize) -> sled::Result<Vec<Entry>> {
+        let mut start = topic_prefix(topic);
+        start.extend_from_slice(&timestamp.to_be_bytes());
+        let mut end = topic.as_bytes().to_vec();
+        end.push(1);
+        let mut entries = Vec::new();
+        for item in self.db.range(start..end).take(limit) {
+            let (_, value) = item?;
+            if let Ok(entry) = serde_json::from_slice(&value) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The last `limit` messages on `topic`, oldest first.
+    pub fn recent(&self, topic: &str, limit: usize) -> sled::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for item in self.db.scan_prefix(topic_prefix(topic)).rev().take(limit) {
+            let (_, value) = item?;
+            if let Ok(entry) = serde_json::from_slice(&value) {
+                entries.push(entry);
+            }
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+fn topic_prefix(topic: &str) -> Vec<u8> {
+    let mut prefix = topic.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// Milliseconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    /// A fresh database under the system temp dir.
+    fn open(name: &str) -> (History, PathBuf) {
+        let dir = env::temp_dir().join(format!("history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (History::open(&dir).unwrap(), dir)
+    }
+
+    fn entry(topic: &str, timestamp: u64, text: &str) -> Entry {
+        let sender = "sender".to_owned();
+        Entry {
+            id: format!("{}/{}", sender, timestamp),
+            topic: topic.to_owned(),
+            sender,
+            nick: "nick".to_owned(),
+            timestamp,
+            text: text.to_owned(),
+            via: None,
+        }
+    }
+
+    fn texts(entries: Vec<Entry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.text).collect()
+    }
+
+    #[test]
+    fn messages_are_stored_once() {
+        let (history, dir) = open("dedup");
+        assert!(history.record(&entry("room/a", 1, "first")).unwrap());
+        // The same message again, even changed, is not stored again.
+        assert!(!history.record(&entry("room/a", 1, "again")).unwrap());
+        assert_eq!(texts(history.recent("room/a", 10).unwrap()), ["first"]);
+        drop(history);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn since_and_recent_stay_in_their_topic() {
+        let (history, dir) = open("range");
+        for timestamp in [3, 1, 2] {
+            let text = timestamp.to_string();
+            history.record(&entry("room/a", timestamp, &text)).unwrap();
+        }
+        history.record(&entry("room/ab", 4, "other")).unwrap();
+        history.record(&entry("room", 5, "other")).unwrap();
+
+        assert_eq!(
+            texts(history.since("room/a", 0, 10).unwrap()),
+            ["1", "2", "3"]
+        );
+        assert_eq!(texts(history.since("room/a", 2, 10).unwrap()), ["2", "3"]);
+        assert_eq!(texts(history.since("room/a", 0, 2).unwrap()), ["1", "2"]);
+        assert!(history.since("room/a", 4, 10).unwrap().is_empty());
+
+        assert_eq!(texts(history.recent("room/a", 2).unwrap()), ["2", "3"]);
+        assert_eq!(
+            texts(history.recent("room/a", 10).unwrap()),
+            ["1", "2", "3"]
+        );
+        assert!(history.recent("room/b", 10).unwrap().is_empty());
+        drop(history);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,31 +1,120 @@
+mod behaviour;
+mod chat;
+#[path = "../../common/codec.rs"]
+mod codec;
+mod command;
+mod dm;
+mod history;
+mod output;
+mod screen;
+mod store;
+mod sync;
+#[path = "../../common/transfer.rs"]
+mod transfer;
+
+use behaviour::MyBehaviour;
+use chat::Chat;
+use dm::DmKeys;
 use futures::StreamExt;
+use history::History;
 use libp2p::{
     core::upgrade,
-    floodsub::{self, Floodsub, FloodsubEvent},
+    floodsub::Floodsub,
     identity,
-    mdns::{Mdns, MdnsEvent},
+    mdns::Mdns,
     mplex, noise,
-    swarm::{NetworkBehaviourEventProcess, SwarmBuilder, SwarmEvent},
+    swarm::{SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    Multiaddr, NetworkBehaviour, PeerId, Transport,
+    Multiaddr, PeerId, Transport,
+};
+use output::{Frontend, Output, Outputs};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
-use std::error::Error;
+use store::{Mailbox, Quota};
 use structopt::StructOpt;
-use tokio::io::{self, AsyncBufReadExt};
+use transfer::{AcceptFrom, ReceiveConfig, Transfers};
 
 #[derive(StructOpt, Debug)]
 struct Opt {
     #[structopt(short, long, default_value = "3000")]
     pub port: u16,
     pub dial: Option<Multiaddr>,
+    /// Name shown to other peers, defaults to anon-<end of peer id>.
+    #[structopt(long)]
+    pub nick: Option<String>,
+    /// Room joined on startup.
+    #[structopt(long, default_value = "general")]
+    pub room: String,
+    /// Keep sent and received messages in this database for /history.
+    #[structopt(long = "history-db")]
+    pub history_db: Option<PathBuf>,
+    /// Where files sent to us with /send end up.
+    #[structopt(long = "download-dir", default_value = "downloads")]
+    pub download_dir: PathBuf,
+    /// Peer whose files are accepted, `*` for anyone. Without any, every
+    /// offer is refused.
+    #[structopt(long = "accept-files-from")]
+    pub accept_files_from: Vec<AcceptFrom>,
+    /// Largest file accepted, in bytes.
+    #[structopt(long = "max-file-size", default_value = "1073741824")]
+    pub max_file_size: u64,
+    /// Full screen interface with room tabs and a peer list instead of
+    /// plain lines on stdin and stdout.
+    #[structopt(long)]
+    pub tui: bool,
+    /// Never read stdin, for running as a service. Output still goes to
+    /// stdout and the process stops on Ctrl-C.
+    #[structopt(long, conflicts_with = "tui")]
+    pub headless: bool,
+    /// Hold encrypted direct messages for peers that are offline and hand
+    /// them over once they are back. Room messages they missed come back
+    /// through history sync instead.
+    #[structopt(long = "store-forward")]
+    pub store_forward: bool,
+    /// Where held messages are kept, in memory if not given.
+    #[structopt(long = "store-db", requires = "store-forward")]
+    pub store_db: Option<PathBuf>,
+    /// Seconds a held message waits for its recipient.
+    #[structopt(long = "store-ttl", default_value = "86400")]
+    pub store_ttl: u64,
+    /// Most messages held from any one sender.
+    #[structopt(long = "store-max-per-sender", default_value = "100")]
+    pub store_max_per_sender: usize,
+    /// Most messages held from everyone together.
+    #[structopt(long = "store-max-total", default_value = "10000")]
+    pub store_max_total: usize,
+    /// Keep our key in this file so the peer id, which messages held for
+    /// us are addressed to, survives restarts.
+    #[structopt(long)]
+    pub identity: Option<PathBuf>,
 }
 
+/// How long leave messages get to reach peers on the way out.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// How often held messages past their TTL are dropped.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
-    let local_key = identity::Keypair::generate_ed25519();
+    let opt = Opt::from_args();
+    // Log lines on stderr would tear through the full screen interface.
+    if !opt.tui {
+        pretty_env_logger::init();
+    }
+    let (outputs, mut output) = Outputs::new();
+    let local_key = match &opt.identity {
+        Some(path) => load_identity(path)?,
+        None => identity::Keypair::generate_ed25519(),
+    };
     let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
+    outputs.line(format!("Local peer id: {:?}", local_peer_id));
 
     let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
         .into_authentic(&local_key)
@@ -37,58 +126,48 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .multiplex(mplex::MplexConfig::new())
         .boxed();
 
-    // Create a Floodsub topic
-    let floodsub_topic = floodsub::Topic::new("chat");
-
-    #[derive(NetworkBehaviour)]
-    #[behaviour(event_process = true)]
-    struct MyBehaviour {
-        floodsub: Floodsub,
-        mdns: Mdns,
-    }
-
-    impl NetworkBehaviourEventProcess<FloodsubEvent> for MyBehaviour {
-        // Called when `floodsub` produces an event.
-        fn inject_event(&mut self, message: FloodsubEvent) {
-            if let FloodsubEvent::Message(message) = message {
-                println!(
-                    "Received: '{:?}' from {:?}",
-                    String::from_utf8_lossy(&message.data),
-                    message.source
-                );
-            }
-        }
-    }
-
-    impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
-        // Called when `mdns` produces an event.
-        fn inject_event(&mut self, event: MdnsEvent) {
-            match event {
-                MdnsEvent::Discovered(list) => {
-                    for (peer, _) in list {
-                        self.floodsub.add_node_to_partial_view(peer);
-                    }
-                }
-                MdnsEvent::Expired(list) => {
-                    for (peer, _) in list {
-                        if !self.mdns.has_node(&peer) {
-                            self.floodsub.remove_node_from_partial_view(&peer);
-                        }
-                    }
-                }
-            }
-        }
-    }
-
     // Create a Swarm to manage peers and events.
     let mut swarm = {
         let mdns = Mdns::new(Default::default()).await?;
+        let nick = opt.nick.clone().unwrap_or_else(|| {
+            let id = local_peer_id.to_base58();
+            format!("anon-{}", &id[id.len() - 6..])
+        });
+        let history = opt.history_db.as_ref().map(History::open).transpose()?;
+        let mailbox = if opt.store_forward {
+            let ttl = Duration::from_secs(opt.store_ttl);
+            let quota = Quota {
+                per_sender: opt.store_max_per_sender,
+                total: opt.store_max_total,
+            };
+            Some(Mailbox::open(opt.store_db.as_deref(), ttl, quota)?)
+        } else {
+            None
+        };
         let mut behaviour = MyBehaviour {
-            floodsub: Floodsub::new(local_peer_id.clone()),
+            floodsub: Floodsub::new(local_peer_id),
             mdns,
+            history_sync: sync::history_sync(),
+            direct: dm::direct(),
+            file_transfer: transfer::file_transfer(b"/tokio-chat/file/1.0.0"),
+            store_forward: store::store_forward(),
+            chat: Chat::new(
+                local_peer_id,
+                nick,
+                history,
+                DmKeys::new(&local_key),
+                Transfers::new(ReceiveConfig {
+                    download_dir: opt.download_dir.clone(),
+                    accept_from: opt.accept_files_from.clone(),
+                    max_size: opt.max_file_size,
+                }),
+                outputs.clone(),
+                mailbox,
+            ),
         };
 
-        behaviour.floodsub.subscribe(floodsub_topic.clone());
+        let MyBehaviour { floodsub, chat, .. } = &mut behaviour;
+        chat.start(floodsub, opt.room.clone());
 
         SwarmBuilder::new(transport, behaviour, local_peer_id)
             // We want the connection background tasks to be spawned
@@ -100,30 +179,95 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     // Reach out to another node if specified
-    let opt = Opt::from_args();
     if let Some(to_dial) = opt.dial {
         swarm.dial(to_dial.clone())?;
-        println!("Dialed {:?}", to_dial)
+        outputs.line(format!("Dialed {:?}", to_dial));
     }
 
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut frontend = if opt.tui {
+        Frontend::screen()?
+    } else if opt.headless {
+        Frontend::Headless
+    } else {
+        Frontend::lines()
+    };
 
     // Listen on all interfaces and whatever port the OS assigns
     swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", opt.port).parse()?)?;
 
+    let mut connected = HashSet::new();
+    let mut purge = tokio::time::interval(PURGE_INTERVAL);
     // Kick it off
     loop {
         tokio::select! {
-            line = stdin.next_line() => {
-                let line = line?.expect("stdin closed");
-                swarm.behaviour_mut().floodsub.publish(floodsub_topic.clone(), line.as_bytes());
-            }
-            event = swarm.select_next_some() => {
-                if let SwarmEvent::NewListenAddr { address, .. } = event {
-                    println!("Listening on {:?}", address);
+            line = frontend.next_line() => match line? {
+                Some(line) => swarm.behaviour_mut().handle_line(&line),
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+            Some(shown) = output.recv() => frontend.show(shown)?,
+            _ = purge.tick() => swarm.behaviour_mut().chat.purge_mailbox(),
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    outputs.line(format!("Listening on {:?}", address));
                 }
-            }
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    connected.insert(peer_id);
+                    outputs.send(Output::Connected(peer_id));
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } => {
+                    connected.remove(&peer_id);
+                    outputs.send(Output::Disconnected(peer_id));
+                }
+                _ => {}
+            },
+        }
+    }
+
+    // Leave every room, let floodsub get the news out, then hang up.
+    swarm.behaviour_mut().shutdown();
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        loop {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+    for peer in connected {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    drop(frontend);
+    while let Ok(shown) = output.try_recv() {
+        if let Some(line) = shown.plain() {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the ed25519 key at `path`, creating it on first use.
+fn load_identity(path: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(mut bytes) => {
+            let keypair = identity::ed25519::Keypair::decode(&mut bytes)?;
+            return Ok(identity::Keypair::Ed25519(keypair));
         }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let keypair = identity::ed25519::Keypair::generate();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Nobody else gets to read the key, not even between creating and
+    // writing it.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
+    options.open(path)?.write_all(&keypair.encode())?;
+    Ok(identity::Keypair::Ed25519(keypair))
 }
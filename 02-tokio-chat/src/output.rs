@@ -0,0 +1,113 @@
+use crate::screen::Screen;
+use libp2p::PeerId;
+use tokio::{
+    io::{self, AsyncBufReadExt, BufReader, Lines, Stdin},
+    sync::mpsc,
+};
+
+/// Something to show the user. The plain line mode prints `Line`s and
+/// ignores the rest, the terminal UI also uses the others for its tabs and
+/// peer list.
+#[derive(Debug)]
+pub enum Output {
+    /// A line of text, for the tab of `room` when it is about one.
+    Line {
+        room: Option<String>,
+        text: String,
+    },
+    /// Joined rooms and the one we talk in, after every change.
+    Rooms {
+        rooms: Vec<String>,
+        current: String,
+    },
+    /// Nickname seen for a peer.
+    Nick(PeerId, String),
+    Discovered(PeerId),
+    Expired(PeerId),
+    Connected(PeerId),
+    Disconnected(PeerId),
+}
+
+impl Output {
+    /// The line as the plain mode prints it.
+    pub fn plain(&self) -> Option<String> {
+        match self {
+            Output::Line {
+                room: Some(room),
+                text,
+            } => Some(format!("[#{}] {}", room, text)),
+            Output::Line { room: None, text } => Some(text.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Cheap to clone, sending never blocks and only fails once the front end
+/// is gone, in which case nobody is looking anyway.
+#[derive(Clone)]
+pub struct Outputs(mpsc::UnboundedSender<Output>);
+
+impl Outputs {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Output>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Outputs(sender), receiver)
+    }
+
+    pub fn send(&self, output: Output) {
+        let _ = self.0.send(output);
+    }
+
+    pub fn line(&self, text: impl Into<String>) {
+        self.send(Output::Line {
+            room: None,
+            text: text.into(),
+        });
+    }
+
+    pub fn room_line(&self, room: &str, text: impl Into<String>) {
+        self.send(Output::Line {
+            room: Some(room.to_owned()),
+            text: text.into(),
+        });
+    }
+}
+
+/// Where lines are typed and output shown.
+pub enum Frontend {
+    /// Reads stdin line by line and prints output as it comes.
+    Lines(Lines<BufReader<Stdin>>),
+    Screen(Box<Screen>),
+    /// Reads nothing, output is printed like with `Lines`.
+    Headless,
+}
+
+impl Frontend {
+    pub fn lines() -> Self {
+        Frontend::Lines(BufReader::new(io::stdin()).lines())
+    }
+
+    pub fn screen() -> io::Result<Self> {
+        Ok(Frontend::Screen(Box::new(Screen::new()?)))
+    }
+
+    /// The next line typed by the user, `None` once there will be no more.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        match self {
+            Frontend::Lines(lines) => lines.next_line().await,
+            Frontend::Screen(screen) => screen.next_line().await,
+            Frontend::Headless => futures::future::pending().await,
+        }
+    }
+
+    pub fn show(&mut self, output: Output) -> io::Result<()> {
+        match self {
+            Frontend::Lines(_) | Frontend::Headless => {
+                if let Some(line) = output.plain() {
+                    println!("{}", line);
+                }
+                Ok(())
+            }
+            Frontend::Screen(screen) => screen.show(output),
+        }
+    }
+}
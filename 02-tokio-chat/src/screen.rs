@@ -0,0 +1,355 @@
+use crate::output::Output;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::StreamExt;
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, Stdout},
+};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Tabs},
+    Frame, Terminal,
+};
+
+/// Lines kept per tab.
+const SCROLLBACK: usize = 1000;
+
+/// Width of the peer list.
+const SIDEBAR: u16 = 30;
+
+/// Full screen front end: room tabs on top, messages with a peer list next
+/// to them, and an input line that incoming messages never run into.
+pub struct Screen {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    events: EventStream,
+    view: View,
+}
+
+impl Screen {
+    /// Takes over the terminal until dropped.
+    pub fn new() -> io::Result<Self> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let mut screen = Screen {
+            terminal: Terminal::new(CrosstermBackend::new(stdout))?,
+            events: EventStream::new(),
+            view: View::default(),
+        };
+        screen.draw()?;
+        Ok(screen)
+    }
+
+    /// Waits for the user to submit a line, `None` once they quit.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        while let Some(event) = self.events.next().await {
+            let key = match event? {
+                Event::Key(key) => key,
+                Event::Resize(..) => {
+                    self.draw()?;
+                    continue;
+                }
+                Event::Mouse(_) => continue,
+            };
+            let action = self.view.key(key);
+            self.draw()?;
+            match action {
+                Action::Line(line) => return Ok(Some(line)),
+                Action::Quit => return Ok(None),
+                Action::None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn show(&mut self, output: Output) -> io::Result<()> {
+        self.view.apply(output);
+        self.draw()
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let view = &self.view;
+        self.terminal.draw(|frame| view.render(frame))?;
+        Ok(())
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+enum Action {
+    Line(String),
+    Quit,
+    None,
+}
+
+#[derive(Default)]
+struct Peer {
+    nick: Option<String>,
+    discovered: bool,
+    connected: bool,
+}
+
+/// Everything on screen, kept apart from the terminal so drawing can
+/// borrow it while the terminal is borrowed mutably.
+#[derive(Default)]
+struct View {
+    /// Lines by room, the empty name holds what arrives while in none.
+    lines: HashMap<String, VecDeque<String>>,
+    rooms: Vec<String>,
+    current: String,
+    /// Rooms with lines that arrived while another tab was shown.
+    unread: HashSet<String>,
+    peers: BTreeMap<PeerId, Peer>,
+    input: Vec<char>,
+    cursor: usize,
+    /// Lines scrolled back from the bottom of the message pane.
+    scroll: usize,
+}
+
+impl View {
+    fn apply(&mut self, output: Output) {
+        match output {
+            Output::Line { room, text } => {
+                let room = match room {
+                    Some(room) if self.rooms.contains(&room) => room,
+                    _ => self.current.clone(),
+                };
+                if room != self.current {
+                    self.unread.insert(room.clone());
+                }
+                let lines = self.lines.entry(room).or_default();
+                if lines.len() == SCROLLBACK {
+                    lines.pop_front();
+                }
+                lines.push_back(text);
+            }
+            Output::Rooms { rooms, current } => {
+                self.lines
+                    .retain(|room, _| room.is_empty() || rooms.contains(room));
+                // What came before the first room, the peer id among it,
+                // stays readable in that room's tab.
+                if self.current.is_empty() && !current.is_empty() {
+                    if let Some(mut status) = self.lines.remove("") {
+                        let lines = self.lines.entry(current.clone()).or_default();
+                        status.extend(lines.drain(..));
+                        *lines = status;
+                    }
+                }
+                self.unread.remove(&current);
+                if current != self.current {
+                    self.scroll = 0;
+                }
+                self.rooms = rooms;
+                self.current = current;
+            }
+            Output::Nick(peer, nick) => self.peers.entry(peer).or_default().nick = Some(nick),
+            Output::Discovered(peer) => self.peers.entry(peer).or_default().discovered = true,
+            Output::Expired(peer) => self.update_peer(peer, |peer| peer.discovered = false),
+            Output::Connected(peer) => self.peers.entry(peer).or_default().connected = true,
+            Output::Disconnected(peer) => self.update_peer(peer, |peer| peer.connected = false),
+        }
+    }
+
+    /// Changes a known peer, forgetting it once it is neither discovered
+    /// nor connected.
+    fn update_peer(&mut self, peer: PeerId, update: impl FnOnce(&mut Peer)) {
+        if let Some(known) = self.peers.get_mut(&peer) {
+            update(known);
+            if !known.discovered && !known.connected {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent) -> Action {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if control => return Action::Quit,
+            KeyCode::Esc => return Action::Quit,
+            KeyCode::Enter if !self.input.is_empty() => {
+                let line = self.input.drain(..).collect();
+                self.cursor = 0;
+                self.scroll = 0;
+                return Action::Line(line);
+            }
+            KeyCode::Tab => return self.switch(1),
+            KeyCode::BackTab => return self.switch(self.rooms.len().saturating_sub(1)),
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        Action::None
+    }
+
+    /// Talking in another room is the same as joining it again, so tabs
+    /// switch by sending `/join`.
+    fn switch(&self, by: usize) -> Action {
+        if self.rooms.len() < 2 {
+            return Action::None;
+        }
+        let at = self
+            .rooms
+            .iter()
+            .position(|room| *room == self.current)
+            .unwrap_or_default();
+        Action::Line(format!(
+            "/join #{}",
+            self.rooms[(at + by) % self.rooms.len()]
+        ))
+    }
+
+    fn render<B: Backend>(&self, frame: &mut Frame<B>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(3),
+                Constraint::Length(3),
+            ])
+            .split(frame.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(20), Constraint::Length(SIDEBAR)])
+            .split(rows[1]);
+        frame.render_widget(self.tabs(), rows[0]);
+        frame.render_widget(self.messages(columns[0]), columns[0]);
+        frame.render_widget(self.peer_list(), columns[1]);
+        self.render_input(frame, rows[2]);
+    }
+
+    fn tabs(&self) -> Tabs<'_> {
+        let mut titles: Vec<Spans> = self
+            .rooms
+            .iter()
+            .map(|room| {
+                let style = if self.unread.contains(room) {
+                    Style::default().fg(Color::Yellow)
+                } else {
+                    Style::default()
+                };
+                Spans::from(Span::styled(format!("#{}", room), style))
+            })
+            .collect();
+        if titles.is_empty() {
+            titles.push(Spans::from("no room, /join one"));
+        }
+        let selected = self
+            .rooms
+            .iter()
+            .position(|room| *room == self.current)
+            .unwrap_or_default();
+        Tabs::new(titles)
+            .select(selected)
+            .block(Block::default().borders(Borders::ALL).title(" rooms "))
+            .highlight_style(Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED))
+    }
+
+    /// The bottom of the current room's lines, wrapped to fit and moved up
+    /// by `scroll`.
+    fn messages(&self, area: Rect) -> Paragraph<'_> {
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let height = area.height.saturating_sub(2) as usize;
+        let mut rows = Vec::new();
+        if let Some(lines) = self.lines.get(&self.current) {
+            for line in lines.iter().rev() {
+                let mut wrapped = wrap(line, width);
+                wrapped.reverse();
+                rows.extend(wrapped);
+                if rows.len() >= height + self.scroll {
+                    break;
+                }
+            }
+        }
+        let scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let rows: Vec<Spans> = rows
+            .into_iter()
+            .skip(scroll)
+            .take(height)
+            .rev()
+            .map(Spans::from)
+            .collect();
+        let title = match (self.current.is_empty(), scroll) {
+            (true, _) => " status ".to_owned(),
+            (false, 0) => format!(" #{} ", self.current),
+            (false, scroll) => format!(" #{} (scrolled up {}) ", self.current, scroll),
+        };
+        Paragraph::new(rows).block(Block::default().borders(Borders::ALL).title(title))
+    }
+
+    fn peer_list(&self) -> List<'_> {
+        let items: Vec<ListItem> = self
+            .peers
+            .iter()
+            .map(|(peer, known)| {
+                let id = peer.to_base58();
+                let name = match &known.nick {
+                    Some(nick) => format!("{} ({})", nick, &id[id.len() - 6..]),
+                    None => id,
+                };
+                let style = if known.connected {
+                    Style::default().fg(Color::Green)
+                } else {
+                    Style::default().fg(Color::DarkGray)
+                };
+                ListItem::new(Span::styled(name, style))
+            })
+            .collect();
+        let title = format!(" peers ({}) ", self.peers.len());
+        List::new(items).block(Block::default().borders(Borders::ALL).title(title))
+    }
+
+    fn render_input<B: Backend>(&self, frame: &mut Frame<B>, area: Rect) {
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        // Keep the cursor in view on lines longer than the box.
+        let skip = (self.cursor + 1).saturating_sub(width);
+        let shown: String = self.input.iter().skip(skip).take(width).collect();
+        let input = Paragraph::new(shown).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(" message, Tab switches rooms, Esc quits "),
+        );
+        frame.render_widget(input, area);
+        frame.set_cursor(area.x + 1 + (self.cursor - skip) as u16, area.y + 1);
+    }
+}
+
+/// Splits `text` into rows of at most `width` characters.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(width)
+        .map(|row| row.iter().collect())
+        .collect()
+}
@@ -0,0 +1,327 @@
+use crate::{
+    codec::{read_json, write_json},
+    dm::DmRequest,
+    history,
+};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::ProtocolName,
+    floodsub::Topic,
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, io, iter, path::Path, time::Duration};
+
+/// Store-and-forward nodes subscribe to this topic, which is how everyone
+/// else finds them.
+pub fn store_topic() -> Topic {
+    Topic::new("store-forward")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StoreRequest {
+    /// Keep a sealed direct message until `to` comes back.
+    Put { to: String, message: DmRequest },
+    /// A kept message, handed to its recipient by the store.
+    Deliver { from: String, message: DmRequest },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StoreResponse {
+    Stored,
+    Refused {
+        reason: String,
+    },
+    /// The recipient got the message, the store may forget it.
+    Ack,
+}
+
+/// A message waiting for its recipient. Only the recipient can open it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Held {
+    pub from: String,
+    /// Milliseconds since the unix epoch after which it is dropped.
+    pub expires: u64,
+    pub message: DmRequest,
+}
+
+/// How many messages a store node holds at most.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    /// From any one sender.
+    pub per_sender: usize,
+    /// From everyone together.
+    pub total: usize,
+}
+
+#[derive(Debug)]
+pub enum PutError {
+    /// The sender has as many messages held as it may.
+    SenderFull,
+    /// The store holds as many messages as it may.
+    Full,
+    Db(sled::Error),
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PutError::SenderFull => write!(f, "too many messages held for you already"),
+            PutError::Full => write!(f, "store is full"),
+            PutError::Db(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<sled::Error> for PutError {
+    fn from(err: sled::Error) -> Self {
+        PutError::Db(err)
+    }
+}
+
+/// Direct messages held for offline peers, in a sled database. Room
+/// messages are not held here, peers catch up on those through history
+/// sync once they are back.
+pub struct Mailbox {
+    db: sled::Db,
+    ttl: Duration,
+    quota: Quota,
+    /// Messages held from each sender, counted at open and kept up to date
+    /// from then on.
+    senders: HashMap<String, usize>,
+    total: usize,
+}
+
+impl Mailbox {
+    /// Opens the database at `path`, or a temporary one that goes away
+    /// with the process.
+    pub fn open(path: Option<&Path>, ttl: Duration, quota: Quota) -> sled::Result<Self> {
+        let db = match path {
+            Some(path) => sled::open(path)?,
+            None => sled::Config::new().temporary(true).open()?,
+        };
+        let mut mailbox = Mailbox {
+            db,
+            ttl,
+            quota,
+            senders: HashMap::new(),
+            total: 0,
+        };
+        mailbox.purge()?;
+        for item in mailbox.db.iter() {
+            let (_, value) = item?;
+            if let Ok(held) = serde_json::from_slice::<Held>(&value) {
+                *mailbox.senders.entry(held.from).or_default() += 1;
+                mailbox.total += 1;
+            }
+        }
+        Ok(mailbox)
+    }
+
+    pub fn put(&mut self, from: &PeerId, to: &PeerId, message: DmRequest) -> Result<(), PutError> {
+        let from = from.to_base58();
+        if self.senders.get(&from).copied().unwrap_or_default() >= self.quota.per_sender {
+            return Err(PutError::SenderFull);
+        }
+        if self.total >= self.quota.total {
+            return Err(PutError::Full);
+        }
+        let held = Held {
+            from,
+            expires: history::now() + self.ttl.as_millis() as u64,
+            message,
+        };
+        let mut key = recipient_prefix(to);
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        let value = serde_json::to_vec(&held).expect("held message serializes to JSON");
+        self.db.insert(key, value)?;
+        self.db.flush()?;
+        *self.senders.entry(held.from).or_default() += 1;
+        self.total += 1;
+        Ok(())
+    }
+
+    /// Everything still held for `to`, with the keys to `remove` it by.
+    pub fn held_for(&self, to: &PeerId) -> sled::Result<Vec<(Vec<u8>, Held)>> {
+        let now = history::now();
+        let mut held = Vec::new();
+        for item in self.db.scan_prefix(recipient_prefix(to)) {
+            let (key, value) = item?;
+            // Expired ones wait for the next purge.
+            match serde_json::from_slice::<Held>(&value) {
+                Ok(message) if message.expires >= now => held.push((key.to_vec(), message)),
+                _ => {}
+            }
+        }
+        Ok(held)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> sled::Result<()> {
+        if let Some(value) = self.db.remove(key)? {
+            self.forget(&value);
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Drops whatever outlived the TTL. Walks the whole database, so it
+    /// runs now and then rather than on every message.
+    pub fn purge(&mut self) -> sled::Result<()> {
+        let now = history::now();
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            let expired = serde_json::from_slice::<Held>(&value)
+                .map(|held| held.expires < now)
+                .unwrap_or(true);
+            if expired {
+                self.db.remove(key)?;
+                self.forget(&value);
+            }
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Takes a removed message off its sender's count.
+    fn forget(&mut self, value: &[u8]) {
+        let held = match serde_json::from_slice::<Held>(value) {
+            Ok(held) => held,
+            Err(_) => return,
+        };
+        if let Some(count) = self.senders.get_mut(&held.from) {
+            *count -= 1;
+            if *count == 0 {
+                self.senders.remove(&held.from);
+            }
+        }
+        self.total = self.total.saturating_sub(1);
+    }
+}
+
+fn recipient_prefix(to: &PeerId) -> Vec<u8> {
+    let mut prefix = to.to_base58().into_bytes();
+    prefix.push(0);
+    prefix
+}
+
+/// Request-response behaviour handing messages for offline peers to store
+/// nodes, and from there to the peers once they are back.
+pub type StoreForward = RequestResponse<StoreCodec>;
+
+pub fn store_forward() -> StoreForward {
+    RequestResponse::new(
+        StoreCodec,
+        iter::once((StoreProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreProtocol;
+
+impl ProtocolName for StoreProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/tokio-chat/store/1.0.0"
+    }
+}
+
+#[derive(Clone)]
+pub struct StoreCodec;
+
+#[async_trait]
+impl RequestResponseCodec for StoreCodec {
+    type Protocol = StoreProtocol;
+    type Request = StoreRequest;
+    type Response = StoreResponse;
+
+    async fn read_request<T>(&mut self, _: &StoreProtocol, io: &mut T) -> io::Result<StoreRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StoreProtocol, io: &mut T) -> io::Result<StoreResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StoreProtocol,
+        io: &mut T,
+        request: StoreRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StoreProtocol,
+        io: &mut T,
+        response: StoreResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> DmRequest {
+        DmRequest {
+            nonce: vec![0; 12],
+            ciphertext: b"sealed".to_vec(),
+        }
+    }
+
+    fn mailbox(ttl: Duration, per_sender: usize, total: usize) -> Mailbox {
+        let quota = Quota { per_sender, total };
+        Mailbox::open(None, ttl, quota).unwrap()
+    }
+
+    #[test]
+    fn quotas_limit_senders_and_the_store() {
+        let mut mailbox = mailbox(Duration::from_secs(60), 2, 3);
+        let (alice, bob, to) = (PeerId::random(), PeerId::random(), PeerId::random());
+        mailbox.put(&alice, &to, message()).unwrap();
+        mailbox.put(&alice, &to, message()).unwrap();
+        let err = mailbox.put(&alice, &to, message()).unwrap_err();
+        assert!(matches!(err, PutError::SenderFull));
+        mailbox.put(&bob, &to, message()).unwrap();
+        let err = mailbox.put(&bob, &to, message()).unwrap_err();
+        assert!(matches!(err, PutError::Full));
+        // Delivering one frees room for its sender.
+        let (key, _) = mailbox.held_for(&to).unwrap().remove(0);
+        mailbox.remove(&key).unwrap();
+        mailbox.put(&alice, &to, message()).unwrap();
+        assert_eq!(mailbox.held_for(&to).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn purge_drops_expired_messages() {
+        let mut mailbox = mailbox(Duration::ZERO, 1, 10);
+        let (from, to) = (PeerId::random(), PeerId::random());
+        mailbox.put(&from, &to, message()).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(mailbox.held_for(&to).unwrap().is_empty());
+        mailbox.purge().unwrap();
+        assert_eq!(mailbox.total, 0);
+        mailbox.put(&from, &to, message()).unwrap();
+    }
+}
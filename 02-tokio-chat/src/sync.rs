@@ -0,0 +1,231 @@
+use crate::{
+    codec::{read_json, write_json},
+    history::Entry,
+};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::ProtocolName,
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{io, iter, time::Duration};
+
+/// Most entries handed out for a single request, a full response is
+/// followed by a request for the next page.
+pub const MAX_ENTRIES: usize = 500;
+
+/// Most bytes of entries in one response, well under what the codec reads.
+pub const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+/// How far back a sync reaches, gaps in older history are not repaired.
+pub const HORIZON: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Asks a peer for what it has on `topic` from `since` (unix ms) on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub topic: String,
+    pub since: u64,
+}
+
+impl SyncRequest {
+    /// The first request for `topic`, reaching back `HORIZON` from `now`.
+    pub fn within_horizon(topic: &str, now: u64) -> Self {
+        SyncRequest {
+            topic: topic.to_owned(),
+            since: now.saturating_sub(HORIZON.as_millis() as u64),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub entries: Vec<Entry>,
+    /// Whether the page was cut short, by count or by size.
+    #[serde(default)]
+    pub more: bool,
+}
+
+impl SyncResponse {
+    /// A page of the entries read from history, at most `MAX_ENTRIES` of
+    /// them, cut short before they take up more than `MAX_PAGE_BYTES`.
+    /// Entries too big to ever fit are left out.
+    pub fn page(entries: Vec<Entry>) -> Self {
+        let mut more = entries.len() >= MAX_ENTRIES;
+        let mut size = 0;
+        let mut page = Vec::new();
+        for entry in entries.into_iter().take(MAX_ENTRIES) {
+            let len = serde_json::to_vec(&entry).map_or(usize::MAX, |json| json.len() + 1);
+            if len > MAX_PAGE_BYTES {
+                continue;
+            }
+            if size + len > MAX_PAGE_BYTES {
+                more = true;
+                break;
+            }
+            size += len;
+            page.push(entry);
+        }
+        SyncResponse {
+            entries: page,
+            more,
+        }
+    }
+
+    /// The request for the page after this one, `None` if it was the last.
+    pub fn next_page(&self) -> Option<SyncRequest> {
+        if !self.more {
+            return None;
+        }
+        let (first, last) = (self.entries.first()?, self.entries.last()?);
+        // Pages start at a millisecond, not a message. A page all from one
+        // millisecond would be asked for again and again, so the rest of
+        // that millisecond is skipped.
+        let since = if first.timestamp == last.timestamp {
+            last.timestamp + 1
+        } else {
+            last.timestamp
+        };
+        Some(SyncRequest {
+            topic: last.topic.clone(),
+            since,
+        })
+    }
+}
+
+/// Request-response behaviour peers use to fill in room history they
+/// missed while they were away.
+pub type HistorySync = RequestResponse<SyncCodec>;
+
+pub fn history_sync() -> HistorySync {
+    RequestResponse::new(
+        SyncCodec,
+        iter::once((SyncProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/tokio-chat/history-sync/1.0.0"
+    }
+}
+
+/// Length prefixed JSON both ways.
+#[derive(Clone)]
+pub struct SyncCodec;
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        request: SyncRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        response: SyncResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, text: &str) -> Entry {
+        let sender = "sender".to_owned();
+        Entry {
+            id: format!("{}/{}", sender, timestamp),
+            topic: "room/test".to_owned(),
+            sender,
+            nick: "nick".to_owned(),
+            timestamp,
+            text: text.to_owned(),
+            via: None,
+        }
+    }
+
+    #[test]
+    fn syncs_reach_back_to_the_horizon() {
+        let horizon = HORIZON.as_millis() as u64;
+        let request = SyncRequest::within_horizon("room/test", horizon + 5);
+        assert_eq!((request.topic.as_str(), request.since), ("room/test", 5));
+        assert_eq!(SyncRequest::within_horizon("room/test", 5).since, 0);
+    }
+
+    #[test]
+    fn short_pages_are_the_last() {
+        let response = SyncResponse::page((0..10).map(|t| entry(t, "hi")).collect());
+        assert_eq!(response.entries.len(), 10);
+        assert!(response.next_page().is_none());
+    }
+
+    #[test]
+    fn full_pages_continue() {
+        let response =
+            SyncResponse::page((0..MAX_ENTRIES as u64).map(|t| entry(t, "hi")).collect());
+        let next = response.next_page().unwrap();
+        assert_eq!(
+            (next.topic.as_str(), next.since),
+            ("room/test", MAX_ENTRIES as u64 - 1)
+        );
+
+        // A page all from one millisecond moves past it.
+        let response = SyncResponse::page(vec![entry(7, "hi"); MAX_ENTRIES]);
+        assert_eq!(response.next_page().unwrap().since, 8);
+    }
+
+    #[test]
+    fn pages_are_capped_by_size() {
+        let text = "x".repeat(100 * 1024);
+        let response = SyncResponse::page((0..10).map(|t| entry(t, &text)).collect());
+        assert!(response.entries.len() < 10);
+        assert!(serde_json::to_vec(&response).unwrap().len() <= MAX_PAGE_BYTES + 64);
+        let next = response.next_page().unwrap();
+        assert_eq!(next.since, response.entries.last().unwrap().timestamp);
+
+        // One that could never fit is left out rather than blocking the rest.
+        let huge = "x".repeat(MAX_PAGE_BYTES);
+        let response = SyncResponse::page(vec![entry(0, &huge), entry(1, "hi")]);
+        assert_eq!(response.entries.len(), 1);
+        assert_eq!(response.entries[0].timestamp, 1);
+        assert!(response.next_page().is_none());
+    }
+}
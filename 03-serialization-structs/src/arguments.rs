@@ -1,4 +1,5 @@
 use libp2p::Multiaddr;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -6,4 +7,10 @@ pub struct Opt {
     #[structopt(short, long, default_value = "3000")]
     pub port: u16,
     pub dial: Option<Multiaddr>,
+    /// Keep sent and received messages in this database for /history.
+    #[structopt(long = "history-db")]
+    pub history_db: Option<PathBuf>,
+    /// Never read stdin, for running as a service. Stops on Ctrl-C.
+    #[structopt(long)]
+    pub headless: bool,
 }
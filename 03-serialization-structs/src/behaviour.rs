@@ -1,15 +1,31 @@
+use crate::history::{Entry, History};
 use libp2p::{
     floodsub::{Floodsub, FloodsubEvent},
     mdns::{Mdns, MdnsEvent},
     swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour,
+    NetworkBehaviour, PeerId,
 };
+use log::warn;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = true)]
 pub struct MyBehaviour {
     pub floodsub: Floodsub,
     pub mdns: Mdns,
+    #[behaviour(ignore)]
+    pub history: Option<History>,
+}
+
+impl MyBehaviour {
+    pub fn record(&self, topic: &str, sender: &PeerId, data: &[u8]) {
+        if let Some(history) = &self.history {
+            let text = String::from_utf8_lossy(data).into_owned();
+            let entry = Entry::new(topic.to_owned(), sender.to_base58(), text);
+            if let Err(err) = history.record(&entry) {
+                warn!("could not store message: {}", err);
+            }
+        }
+    }
 }
 
 impl NetworkBehaviourEventProcess<FloodsubEvent> for MyBehaviour {
@@ -21,6 +37,9 @@ impl NetworkBehaviourEventProcess<FloodsubEvent> for MyBehaviour {
                 String::from_utf8_lossy(&message.data),
                 message.source
             );
+            for topic in &message.topics {
+                self.record(topic.id(), &message.source, &message.data);
+            }
         }
     }
 }
@@ -0,0 +1,156 @@
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// One message as kept in the history database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub topic: String,
+    pub sender: String,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub text: String,
+}
+
+impl Entry {
+    pub fn new(topic: String, sender: String, text: String) -> Self {
+        Entry {
+            topic,
+            sender,
+            timestamp: now(),
+            text,
+        }
+    }
+
+    /// `topic \0 timestamp id sender`, so a prefix scan over a topic walks
+    /// its messages in time order. The id, unique in the database, keeps
+    /// messages from one sender within a millisecond apart.
+    fn key(&self, id: u64) -> Vec<u8> {
+        let mut key = topic_prefix(&self.topic);
+        key.extend_from_slice(&self.timestamp.to_be_bytes());
+        key.extend_from_slice(&id.to_be_bytes());
+        key.extend_from_slice(self.sender.as_bytes());
+        key
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(time) = Local.timestamp_millis_opt(self.timestamp as i64).single() {
+            write!(f, "{} ", time.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        write!(f, "<{}> {}", self.sender, self.text)
+    }
+}
+
+/// Sent and received messages, stored in a sled database.
+#[derive(Clone)]
+pub struct History {
+    db: sled::Db,
+}
+
+impl History {
+    pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<Self> {
+        Ok(History {
+            db: sled::open(path)?,
+        })
+    }
+
+    pub fn record(&self, entry: &Entry) -> sled::Result<()> {
+        let value = serde_json::to_vec(entry).expect("entry serializes to JSON");
+        self.db.insert(entry.key(self.db.generate_id()?), value)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> sled::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// The last `limit` messages on `topic`, oldest first.
+    pub fn recent(&self, topic: &str, limit: usize) -> sled::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for item in self.db.scan_prefix(topic_prefix(topic)).rev().take(limit) {
+            let (_, value) = item?;
+            if let Ok(entry) = serde_json::from_slice(&value) {
+                entries.push(entry);
+            }
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+fn topic_prefix(topic: &str) -> Vec<u8> {
+    let mut prefix = topic.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        History {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+
+    fn entry(topic: &str, timestamp: u64, text: &str) -> Entry {
+        Entry {
+            topic: topic.to_owned(),
+            sender: "peer".to_owned(),
+            timestamp,
+            text: text.to_owned(),
+        }
+    }
+
+    fn texts(entries: Vec<Entry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.text).collect()
+    }
+
+    #[test]
+    fn recent_is_in_time_order() {
+        let history = history();
+        history.record(&entry("chat", 2_000, "second")).unwrap();
+        history.record(&entry("chat", 1_000, "first")).unwrap();
+        history.record(&entry("chat", 256, "zeroth")).unwrap();
+        history.record(&entry("chat", 3_000, "third")).unwrap();
+        let recent = history.recent("chat", 10).unwrap();
+        assert_eq!(texts(recent), ["zeroth", "first", "second", "third"]);
+        let recent = history.recent("chat", 2).unwrap();
+        assert_eq!(texts(recent), ["second", "third"]);
+    }
+
+    #[test]
+    fn same_millisecond_and_sender_are_both_kept() {
+        let history = history();
+        history.record(&entry("chat", 1_000, "one")).unwrap();
+        history.record(&entry("chat", 1_000, "two")).unwrap();
+        let recent = history.recent("chat", 10).unwrap();
+        assert_eq!(texts(recent), ["one", "two"]);
+    }
+
+    #[test]
+    fn topics_do_not_mix() {
+        let history = history();
+        history.record(&entry("chat", 1_000, "chat")).unwrap();
+        // A prefix of "chat", only the separator tells them apart.
+        history.record(&entry("cha", 1_000, "cha")).unwrap();
+        assert_eq!(texts(history.recent("chat", 10).unwrap()), ["chat"]);
+        assert_eq!(texts(history.recent("cha", 10).unwrap()), ["cha"]);
+    }
+}
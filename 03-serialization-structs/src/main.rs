@@ -3,14 +3,21 @@
 //cargo run -- -p 4000 /ip4/127.0.0.1/tcp/3001/ws
 mod arguments;
 mod behaviour;
+mod history;
 mod node;
 use futures::StreamExt;
 use libp2p::swarm::SwarmEvent;
-use std::error::Error;
+use std::{error::Error, time::Duration};
 use structopt::StructOpt;
-use tokio::io::{self, AsyncBufReadExt};
+use tokio::io::{self, AsyncBufReadExt, BufReader, Lines, Stdin};
 
-use crate::node::*;
+use crate::{history::History, node::*};
+
+/// Lines shown by `/history` without a count.
+const HISTORY_COUNT: usize = 20;
+
+/// How long peers get to hear that we unsubscribed on the way out.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -23,10 +30,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let topic = generate_floodsub_topic("chat");
     let transport = config_transport(&local_key);
     let ws_transport = config_ws_transport(&local_key);
+    let history = opt.history_db.as_ref().map(History::open).transpose()?;
 
-    let behaviour = node::floodsub_behaviour(local_peer_id.clone(), mdns, topic.clone()).await;
-    let ws_behaviour =
-        node::floodsub_behaviour(local_peer_id.clone(), ws_mdns, topic.clone()).await;
+    let behaviour = node::floodsub_behaviour(
+        local_peer_id.clone(),
+        mdns,
+        topic.clone(),
+        history.clone(),
+    )
+    .await;
+    let ws_behaviour = node::floodsub_behaviour(
+        local_peer_id.clone(),
+        ws_mdns,
+        topic.clone(),
+        history.clone(),
+    )
+    .await;
 
     let mut swarm = node::swarm_config(
         opt.dial.clone(),
@@ -47,17 +66,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
         local_peer_id,
     )
     .await?;
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    // Read full lines from stdin, unless running headless
+    let mut stdin = (!opt.headless).then(|| BufReader::new(io::stdin()).lines());
     // Kick it off
     loop {
         tokio::select! {
-            line = stdin.next_line() => {
-                let mut line = line?.expect("stdin closed");
+            line = next_line(&mut stdin) => {
+                let mut line = match line? {
+                    Some(line) => line,
+                    None => break,
+                };
+                if let Some(count) = line.strip_prefix("/history") {
+                    show_history(&history, topic.id(), count.trim());
+                    continue;
+                }
                 if let Some(_) = line.find("ws:") {
                     line = line.strip_prefix("ws:").unwrap().to_string();
+                    ws_swarm.behaviour_mut().record(topic.id(), &local_peer_id, line.as_bytes());
                     ws_swarm.behaviour_mut().floodsub.publish(topic.clone(), line.as_bytes());
                 }else{
+                    swarm.behaviour_mut().record(topic.id(), &local_peer_id, line.as_bytes());
                     swarm.behaviour_mut().floodsub.publish(topic.clone(), line.as_bytes());
                 }
             }
@@ -71,6 +99,56 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     println!("Listening on {:?}", address);
                 }
             }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // Tell peers we are gone and give floodsub a moment to send it, the
+    // connections close when the swarms are dropped.
+    swarm.behaviour_mut().floodsub.unsubscribe(topic.clone());
+    ws_swarm.behaviour_mut().floodsub.unsubscribe(topic.clone());
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        loop {
+            tokio::select! {
+                _ = swarm.select_next_some() => {}
+                _ = ws_swarm.select_next_some() => {}
+            }
+        }
+    })
+    .await;
+    if let Some(history) = &history {
+        history.flush()?;
+    }
+    Ok(())
+}
+
+/// The next line from stdin, `None` at its end. Without stdin this never
+/// completes.
+async fn next_line(stdin: &mut Option<Lines<BufReader<Stdin>>>) -> io::Result<Option<String>> {
+    match stdin {
+        Some(lines) => lines.next_line().await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn show_history(history: &Option<History>, topic: &str, count: &str) {
+    let history = match history {
+        Some(history) => history,
+        None => return println!("history is off, start with --history-db <path>"),
+    };
+    let count = match count {
+        "" => HISTORY_COUNT,
+        count => match count.parse() {
+            Ok(count) => count,
+            Err(_) => return println!("usage: /history [count]"),
+        },
+    };
+    match history.recent(topic, count) {
+        Ok(entries) => {
+            for entry in entries {
+                println!("[{}] {}", topic, entry);
+            }
         }
+        Err(err) => println!("could not read history: {}", err),
     }
 }
@@ -1,4 +1,4 @@
-use crate::behaviour::MyBehaviour;
+use crate::{behaviour::MyBehaviour, history::History};
 use libp2p::{
     core::{muxing::StreamMuxerBox, transport::Boxed, upgrade},
     floodsub::{self, Floodsub},
@@ -62,10 +62,12 @@ pub async fn floodsub_behaviour(
     local_peer_id: PeerId,
     mdns: Mdns,
     topic: floodsub::Topic,
+    history: Option<History>,
 ) -> MyBehaviour {
     let mut behaviour = MyBehaviour {
         floodsub: Floodsub::new(local_peer_id.clone()),
         mdns,
+        history,
     };
     behaviour.floodsub.subscribe(topic.clone());
     behaviour
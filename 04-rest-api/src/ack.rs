@@ -0,0 +1,352 @@
+use crate::codec::{read_json, write_json};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::ProtocolName,
+    identity::{Keypair, PublicKey},
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io, iter,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Published and received messages remembered for acks, the oldest are
+/// forgotten first.
+const KEPT: usize = 1000;
+
+/// How far a message got with one receiver. `Read` implies `Delivered`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckKind {
+    Delivered,
+    Read,
+}
+
+/// Sent by a receiver straight to the publisher of a message, signed with
+/// the receiver's identity key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack {
+    pub message_id: String,
+    pub kind: AckKind,
+    /// Milliseconds since the unix epoch.
+    pub at: u64,
+    /// Protobuf encoded public key of the receiver.
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Ack {
+    fn sign(keypair: &Keypair, message_id: &str, kind: AckKind) -> Result<Self, String> {
+        let at = now();
+        let signature = keypair
+            .sign(&signed_bytes(message_id, kind, at))
+            .map_err(|err| err.to_string())?;
+        Ok(Ack {
+            message_id: message_id.to_owned(),
+            kind,
+            at,
+            public_key: keypair.public().to_protobuf_encoding(),
+            signature,
+        })
+    }
+
+    /// Whether `peer` signed this ack.
+    pub fn verify(&self, peer: &PeerId) -> bool {
+        match PublicKey::from_protobuf_encoding(&self.public_key) {
+            Ok(key) => {
+                key.to_peer_id() == *peer
+                    && key.verify(
+                        &signed_bytes(&self.message_id, self.kind, self.at),
+                        &self.signature,
+                    )
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// What the signature covers. The prefix keeps it from being mistaken for
+/// a signature over anything else made with the same key.
+fn signed_bytes(message_id: &str, kind: AckKind, at: u64) -> Vec<u8> {
+    let kind = match kind {
+        AckKind::Delivered => "delivered",
+        AckKind::Read => "read",
+    };
+    format!("p2p-node-ack:{}:{}:{}", message_id, kind, at).into_bytes()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AckResponse {
+    Recorded,
+    Refused { reason: String },
+}
+
+/// The furthest ack one receiver sent for a message.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub peer: String,
+    pub kind: AckKind,
+    pub at: u64,
+}
+
+/// One row of `p2p_messageAcks`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageAcks {
+    pub message_id: String,
+    pub topics: Vec<String>,
+    pub published_at: u64,
+    pub receipts: Vec<Receipt>,
+}
+
+struct Published {
+    topics: Vec<String>,
+    at: u64,
+    receipts: BTreeMap<PeerId, (AckKind, u64)>,
+}
+
+/// Acks for the messages we published, and the sources of the ones we
+/// received so they can be acked. Nothing here touches the network,
+/// callers send the returned acks.
+pub struct Acks {
+    keypair: Keypair,
+    /// Whether we ack what we receive. Acks for our own messages are
+    /// recorded either way.
+    enabled: bool,
+    published: HashMap<String, Published>,
+    published_order: VecDeque<String>,
+    received: HashMap<String, PeerId>,
+    received_order: VecDeque<String>,
+}
+
+impl Acks {
+    pub fn new(keypair: Keypair, enabled: bool) -> Self {
+        Acks {
+            keypair,
+            enabled,
+            published: HashMap::new(),
+            published_order: VecDeque::new(),
+            received: HashMap::new(),
+            received_order: VecDeque::new(),
+        }
+    }
+
+    /// Starts collecting acks for a message we published.
+    pub fn published(&mut self, message_id: String, topics: Vec<String>) {
+        if self.published.contains_key(&message_id) {
+            return;
+        }
+        if self.published_order.len() == KEPT {
+            if let Some(oldest) = self.published_order.pop_front() {
+                self.published.remove(&oldest);
+            }
+        }
+        self.published_order.push_back(message_id.clone());
+        self.published.insert(
+            message_id,
+            Published {
+                topics,
+                at: now(),
+                receipts: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// Remembers where a received message came from and returns the
+    /// delivered ack to send there, unless acks are off or it was seen
+    /// before.
+    pub fn received(&mut self, message_id: &str, source: PeerId) -> Option<(PeerId, Ack)> {
+        if self.received.contains_key(message_id) {
+            return None;
+        }
+        if self.received_order.len() == KEPT {
+            if let Some(oldest) = self.received_order.pop_front() {
+                self.received.remove(&oldest);
+            }
+        }
+        self.received_order.push_back(message_id.to_owned());
+        self.received.insert(message_id.to_owned(), source);
+        if !self.enabled {
+            return None;
+        }
+        Ack::sign(&self.keypair, message_id, AckKind::Delivered)
+            .ok()
+            .map(|ack| (source, ack))
+    }
+
+    /// The read ack for a received message and the peer to send it to.
+    pub fn read(&self, message_id: &str) -> Result<(PeerId, Ack), String> {
+        if !self.enabled {
+            return Err("acks are disabled on this node".into());
+        }
+        let source = self
+            .received
+            .get(message_id)
+            .ok_or_else(|| format!("no received message {}", message_id))?;
+        Ok((
+            *source,
+            Ack::sign(&self.keypair, message_id, AckKind::Read)?,
+        ))
+    }
+
+    /// Records an ack `peer` sent for one of our messages.
+    pub fn receive(&mut self, peer: PeerId, ack: Ack) -> AckResponse {
+        if !ack.verify(&peer) {
+            return AckResponse::Refused {
+                reason: "bad signature".into(),
+            };
+        }
+        let published = match self.published.get_mut(&ack.message_id) {
+            Some(published) => published,
+            None => {
+                return AckResponse::Refused {
+                    reason: "unknown message".into(),
+                }
+            }
+        };
+        let receipt = published.receipts.entry(peer).or_insert((ack.kind, ack.at));
+        if ack.kind > receipt.0 {
+            *receipt = (ack.kind, ack.at);
+        }
+        AckResponse::Recorded
+    }
+
+    /// Ack status of one published message, or of all we remember, newest
+    /// first.
+    pub fn list(&self, message_id: Option<&str>) -> Vec<MessageAcks> {
+        self.published_order
+            .iter()
+            .rev()
+            .filter(|id| message_id.is_none_or(|wanted| wanted == id.as_str()))
+            .filter_map(|id| {
+                let published = self.published.get(id)?;
+                Some(MessageAcks {
+                    message_id: id.clone(),
+                    topics: published.topics.clone(),
+                    published_at: published.at,
+                    receipts: published
+                        .receipts
+                        .iter()
+                        .map(|(peer, (kind, at))| Receipt {
+                            peer: peer.to_base58(),
+                            kind: *kind,
+                            at: *at,
+                        })
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Request-response behaviour carrying acks back to publishers.
+pub type AckExchange = RequestResponse<AckCodec>;
+
+pub fn ack_exchange() -> AckExchange {
+    RequestResponse::new(
+        AckCodec,
+        iter::once((AckProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct AckProtocol;
+
+impl ProtocolName for AckProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-node/ack/1.0.0"
+    }
+}
+
+#[derive(Clone)]
+pub struct AckCodec;
+
+#[async_trait]
+impl RequestResponseCodec for AckCodec {
+    type Protocol = AckProtocol;
+    type Request = Ack;
+    type Response = AckResponse;
+
+    async fn read_request<T>(&mut self, _: &AckProtocol, io: &mut T) -> io::Result<Ack>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &AckProtocol, io: &mut T) -> io::Result<AckResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &AckProtocol, io: &mut T, ack: Ack) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &ack).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &AckProtocol,
+        io: &mut T,
+        response: AckResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_verifies_only_for_its_signer() {
+        let receiver = Keypair::generate_ed25519();
+        let ack = Ack::sign(&receiver, "m1", AckKind::Read).unwrap();
+        assert!(ack.verify(&receiver.public().to_peer_id()));
+        assert!(!ack.verify(&Keypair::generate_ed25519().public().to_peer_id()));
+    }
+
+    #[test]
+    fn tampered_ack_is_refused() {
+        let receiver = Keypair::generate_ed25519();
+        let peer = receiver.public().to_peer_id();
+        let mut acks = Acks::new(Keypair::generate_ed25519(), true);
+        acks.published("m1".into(), vec!["chat".into()]);
+
+        let mut ack = Ack::sign(&receiver, "m1", AckKind::Delivered).unwrap();
+        ack.kind = AckKind::Read;
+        assert!(!ack.verify(&peer));
+        assert!(matches!(
+            acks.receive(peer, ack),
+            AckResponse::Refused { .. }
+        ));
+        assert!(acks.list(Some("m1"))[0].receipts.is_empty());
+
+        let ack = Ack::sign(&receiver, "m1", AckKind::Read).unwrap();
+        assert!(matches!(acks.receive(peer, ack), AckResponse::Recorded));
+        assert_eq!(acks.list(Some("m1"))[0].receipts[0].kind, AckKind::Read);
+    }
+}
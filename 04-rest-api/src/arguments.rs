@@ -1,11 +1,14 @@
 use crate::{
-    node::{Node, NodeConfig},
+    dht::DhtConfig,
+    node::{AuthConfig, HttpOptions, Node, NodeConfig},
     p2p::P2PConfigBuilder,
+    telemetry::{self, LogFormat},
+    transfer::{AcceptFrom, ReceiveConfig},
 };
-use async_std::task;
 use libp2p::Multiaddr;
-use std::{error::Error, time::Duration};
+use std::{error::Error, num::NonZeroUsize, path::PathBuf, time::Duration};
 use structopt::StructOpt;
+use tracing::{error, info};
 
 #[derive(StructOpt, Debug)]
 pub struct Opt {
@@ -14,34 +17,177 @@ pub struct Opt {
     pub http_host: String,
     #[structopt(long = "http-port", default_value = "8585")]
     pub http_port: u16,
+    /// Require a bearer token from this file for RPC calls, one per line,
+    /// optionally followed by the namespaces it grants, e.g. `s3cr3t p2p,debug`.
+    #[structopt(long = "rpc-token-file")]
+    pub rpc_token_file: Option<PathBuf>,
+    /// Accept bearer JWTs signed with HS256 and the secret in this file for
+    /// RPC calls, a `namespaces` claim limits what they grant.
+    #[structopt(long = "rpc-jwt-secret")]
+    pub rpc_jwt_secret: Option<PathBuf>,
+    /// Origin browsers may call the RPC server from, `*` for any.
+    #[structopt(long = "rpc-cors")]
+    pub rpc_cors: Vec<String>,
+    /// Host header accepted besides the listen address, e.g.
+    /// node.example.com:8585, `*` for any.
+    #[structopt(long = "rpc-allowed-host")]
+    pub rpc_allowed_hosts: Vec<String>,
+    /// Largest JSON-RPC request body accepted, in bytes.
+    #[structopt(long = "rpc-max-body-size", default_value = "5242880")]
+    pub rpc_max_body_size: usize,
+    /// Threads serving RPC requests.
+    #[structopt(long = "rpc-threads", default_value = "1")]
+    pub rpc_threads: NonZeroUsize,
+    /// Also serve JSON-RPC over WebSocket on this port, with subscriptions to
+    /// messages, peers and state changes.
+    #[structopt(long = "ws-port")]
+    pub ws_port: Option<u16>,
+    /// Also serve JSON-RPC, admin methods included, on this Unix socket.
+    #[structopt(long = "ipc-path")]
+    pub ipc_path: Option<PathBuf>,
+    /// Permission bits of the IPC socket, in octal.
+    #[structopt(long = "ipc-mode", default_value = "600", parse(try_from_str = parse_mode))]
+    pub ipc_mode: u16,
     #[structopt(long = "p2p-port", default_value = "8500")]
     pub p2p_port: u16,
+    /// Relay connections for other peers (circuit relay v2 server).
+    #[structopt(long = "relay-server")]
+    pub relay_server: bool,
+    /// Listen through a relay, e.g. /ip4/127.0.0.1/tcp/8500/p2p/<relay peer id>.
+    #[structopt(long = "relay")]
+    pub relays: Vec<Multiaddr>,
+    /// Address other peers reach this node at, advertised to them besides
+    /// the one AutoNAT confirms, e.g. /ip4/127.0.0.1/tcp/8500 for a relay on
+    /// localhost.
+    #[structopt(long = "external-addr")]
+    pub external_addrs: Vec<Multiaddr>,
+    /// Let other peers register and discover each other here.
+    #[structopt(long = "rendezvous-server")]
+    pub rendezvous_server: bool,
+    /// Register with a rendezvous point and discover peers there, e.g.
+    /// /ip4/127.0.0.1/tcp/8500/p2p/<rendezvous peer id>.
+    #[structopt(long = "rendezvous")]
+    pub rendezvous_points: Vec<Multiaddr>,
+    /// Namespace to register under and discover peers in, by default the
+    /// communication topic.
+    #[structopt(long = "rendezvous-namespace")]
+    pub rendezvous_namespaces: Vec<String>,
+    /// Where files sent by other peers are saved.
+    #[structopt(long = "download-dir", default_value = "downloads")]
+    pub download_dir: PathBuf,
+    /// Peer whose files are accepted, `*` for anyone. Without any, every
+    /// offer is refused.
+    #[structopt(long = "accept-files-from")]
+    pub accept_files_from: Vec<AcceptFrom>,
+    /// Largest file accepted, in bytes.
+    #[structopt(long = "max-file-size", default_value = "1073741824")]
+    pub max_file_size: u64,
+    /// The only directory `p2p_sendFile` sends files from, paths are
+    /// relative to it.
+    #[structopt(long = "outbox-dir", default_value = "outbox")]
+    pub outbox_dir: PathBuf,
+    /// Do not read stdin, for running as a service.
+    #[structopt(long)]
+    pub headless: bool,
+    /// Send signed delivered acks for received messages to their publishers,
+    /// read acks go out through `p2p_markRead`.
+    #[structopt(long)]
+    pub acks: bool,
+    /// Milliseconds a received message may wait for causally earlier ones
+    /// before it is handled out of order.
+    #[structopt(long = "max-order-delay", default_value = "1000")]
+    pub max_order_delay: u64,
+    /// Number of peers each DHT record is stored on.
+    #[structopt(long = "dht-replication", default_value = "20")]
+    pub dht_replication: NonZeroUsize,
+    /// Seconds a DHT record lives on other peers unless republished.
+    #[structopt(long = "dht-record-ttl", default_value = "129600")]
+    pub dht_record_ttl: u64,
+    /// Seconds between republishing our own DHT records.
+    #[structopt(long = "dht-republish", default_value = "86400")]
+    pub dht_republish: u64,
+    /// Where DHT records are kept across restarts.
+    #[structopt(long = "dht-store", default_value = "dht")]
+    pub dht_store: PathBuf,
+    /// Where content-addressed blocks are stored.
+    #[structopt(long = "blocks-dir", default_value = "blocks")]
+    pub blocks_dir: PathBuf,
+    /// Log output: human, json or otlp-file.
+    #[structopt(long = "log-format", default_value = "human")]
+    pub log_format: LogFormat,
+    /// Where spans go with `--log-format otlp-file`.
+    #[structopt(long = "otlp-file", default_value = "traces.otlp.json")]
+    pub otlp_file: PathBuf,
 }
 
 fn node_config_from_args(opt: &Opt) -> NodeConfig {
-    let mut p2p_config = P2PConfigBuilder::default().set_port(opt.p2p_port).build();
+    let mut p2p_config = P2PConfigBuilder::default()
+        .set_port(opt.p2p_port)
+        .set_relay_server(opt.relay_server)
+        .set_relays(opt.relays.clone())
+        .set_external_addrs(opt.external_addrs.clone())
+        .set_rendezvous_server(opt.rendezvous_server)
+        .set_rendezvous_points(opt.rendezvous_points.clone())
+        .set_rendezvous_namespaces(opt.rendezvous_namespaces.clone())
+        .set_transfers(ReceiveConfig {
+            download_dir: opt.download_dir.clone(),
+            accept_from: opt.accept_files_from.clone(),
+            max_size: opt.max_file_size,
+        })
+        .set_outbox(opt.outbox_dir.clone())
+        .set_headless(opt.headless)
+        .set_acks(opt.acks)
+        .set_max_order_delay(Duration::from_millis(opt.max_order_delay))
+        .set_dht(DhtConfig {
+            replication_factor: opt.dht_replication,
+            record_ttl: Duration::from_secs(opt.dht_record_ttl),
+            republish_interval: Duration::from_secs(opt.dht_republish),
+            store_path: opt.dht_store.clone(),
+        })
+        .set_blocks_dir(opt.blocks_dir.clone())
+        .build();
     if let Some(p) = &opt.peer {
         p2p_config.add_peer(p.clone());
     }
-    NodeConfig::new(
+    let mut config = NodeConfig::new(
         opt.http_host.clone(),
         opt.http_port,
         p2p_config, //p2p_config
-    )
+    );
+    config.rpc_auth = AuthConfig {
+        token_file: opt.rpc_token_file.clone(),
+        jwt_secret_file: opt.rpc_jwt_secret.clone(),
+    };
+    config.http_options = HttpOptions {
+        cors_origins: opt.rpc_cors.clone(),
+        allowed_hosts: opt.rpc_allowed_hosts.clone(),
+        max_body_size: opt.rpc_max_body_size,
+        threads: opt.rpc_threads,
+    };
+    config.ws_port = opt.ws_port;
+    config.ipc_path = opt.ipc_path.clone();
+    config.ipc_mode = opt.ipc_mode;
+    config
+}
+
+fn parse_mode(mode: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(mode, 8)
 }
 
 pub async fn init_using_args() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
+    telemetry::init(opt.log_format, &opt.otlp_file)?;
     let config = node_config_from_args(&opt);
     let mut node = Node::new(config)?;
     let t = node.start().await;
     match t {
-        Ok(_) => println!("node running"),
-        Err(_) => eprintln!("node failed to start"),
+        Ok(_) => info!("node running"),
+        Err(err) => error!(%err, "node failed to start"),
+    }
+    tokio::select! {
+        signal = tokio::signal::ctrl_c() => signal?,
+        _ = node.closed() => {}
     }
-    tokio::signal::ctrl_c().await?;
-    //Todo: make start return instantly currently execution is stuck there
-    //and is not printing below line.
     node.stop().await;
     Ok(())
 }
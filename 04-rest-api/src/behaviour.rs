@@ -1,35 +1,387 @@
+use crate::{
+    ack::{Ack, AckExchange, AckResponse, Acks},
+    blocks::{BlockExchange, BlockFetches, BlockStore, BlocksResponse, Cid, WantList},
+    causal::CausalOrder,
+    dht::{DhtQueries, SledStore, KAD_PROTOCOL},
+    events::{Events, NodeEvent, ReceivedMessage},
+    metrics::Metrics,
+    rendezvous::Rendezvous,
+    state::{state_topic, Delta, Entry, SharedState, StateRequest, StateResponse, StateSync},
+    transfer::{FileRequest, FileResponse, FileTransfer, Offer, Transfers},
+};
+use futures::channel::oneshot;
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, Topic},
+    autonat,
+    floodsub::{Floodsub, FloodsubEvent, FloodsubMessage, Topic},
+    identify::{Identify, IdentifyEvent},
+    kad::{GetProvidersError, GetProvidersOk, Kademlia, KademliaEvent, QueryResult},
     mdns::{Mdns, MdnsEvent},
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour,
+    ping,
+    relay::v2::{
+        client::{self, Client},
+        relay::{self, Relay},
+    },
+    rendezvous,
+    request_response::{RequestId, RequestResponseEvent, RequestResponseMessage},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviourEventProcess},
+    NetworkBehaviour, PeerId,
 };
+use rand::seq::SliceRandom;
 use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    sync::Arc,
+};
+use tracing::{debug, info, info_span, warn, Span};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = true)]
 pub struct P2PBehaviour {
     pub floodsub: Floodsub,
     pub mdns: Mdns,
+    pub relay_client: Client,
+    pub relay: Toggle<Relay>,
+    pub rendezvous_client: rendezvous::client::Behaviour,
+    pub rendezvous_server: Toggle<rendezvous::server::Behaviour>,
+    pub identify: Identify,
+    pub autonat: autonat::Behaviour,
+    pub ping: ping::Behaviour,
+    pub kademlia: Kademlia<SledStore>,
+    pub file_transfer: FileTransfer,
+    pub ack_exchange: AckExchange,
+    pub state_sync: StateSync,
+    pub block_exchange: BlockExchange,
+    #[behaviour(ignore)]
+    pub transfers: Transfers,
+    #[behaviour(ignore)]
+    pub acks: Acks,
+    #[behaviour(ignore)]
+    pub causal: CausalOrder,
+    #[behaviour(ignore)]
+    pub state: SharedState,
+    #[behaviour(ignore)]
+    pub dht_queries: DhtQueries,
+    #[behaviour(ignore)]
+    pub rendezvous: Rendezvous,
+    #[behaviour(ignore)]
+    pub blocks: BlockStore,
+    #[behaviour(ignore)]
+    pub block_fetches: BlockFetches,
+    /// Peers known to be subscribed to each floodsub topic.
+    #[behaviour(ignore)]
+    pub subscribers: HashMap<String, HashSet<PeerId>>,
+    /// Topics we subscribed to, the only ones metrics are labelled with.
+    #[behaviour(ignore)]
+    pub subscribed: HashSet<String>,
+    /// Handed to RPC subscribers.
+    #[behaviour(ignore)]
+    pub events: Events,
+    #[behaviour(ignore)]
+    pub metrics: Arc<Metrics>,
+    #[behaviour(ignore)]
+    pub local_peer_id: PeerId,
+}
+
+impl P2PBehaviour {
+    pub fn subscribe(&mut self, topic: Topic) {
+        self.subscribed.insert(topic.id().to_owned());
+        self.floodsub.subscribe(topic);
+    }
+
+    /// Offers the file at `path` to `peer`, which then pulls it chunk by
+    /// chunk.
+    pub fn send_file(&mut self, peer: PeerId, path: &Path) -> io::Result<Offer> {
+        let offer = self.transfers.offer(peer, path)?;
+        self.send_file_request(peer, FileRequest::Offer(offer.clone()));
+        Ok(offer)
+    }
+
+    /// Picks up downloads from `peer` that stalled when it went away.
+    pub fn resume_transfers(&mut self, peer: PeerId) {
+        for request in self.transfers.resume(peer) {
+            info!(peer_id = %peer, transfer_id = request.transfer_id(), "resuming transfer");
+            self.send_file_request(peer, request);
+        }
+    }
+
+    /// Tells the publisher of a received message that it was read.
+    pub fn mark_read(&mut self, message_id: &str) -> Result<(), String> {
+        let (peer, ack) = self.acks.read(message_id)?;
+        self.ack_exchange.send_request(&peer, ack);
+        Ok(())
+    }
+
+    /// Hands on received messages that waited too long for earlier ones.
+    pub fn release_held(&mut self) {
+        for message in self.causal.expire() {
+            self.deliver(message);
+        }
+        self.metrics.messages_held.set(self.causal.pending() as i64);
+    }
+
+    /// Writes a key of the shared state and publishes the change.
+    pub fn set_state(&mut self, key: String, value: Value) -> Entry {
+        let delta = self.state.set(key, value);
+        let data = serde_json::to_vec(&delta).expect("delta serializes to JSON");
+        self.floodsub.publish(state_topic(), data);
+        delta
+            .entries
+            .into_values()
+            .next()
+            .expect("a write changes one entry")
+    }
+
+    /// Runs an anti-entropy round with a random neighbour sharing state,
+    /// which repairs deltas either side missed.
+    pub fn sync_state(&mut self) {
+        let peers = self.topic_peers(state_topic().id());
+        if let Some(peer) = peers.choose(&mut rand::thread_rng()) {
+            self.sync_state_with(*peer);
+        }
+    }
+
+    /// Stores a block and announces us as its provider.
+    pub fn put_block(&mut self, data: &[u8]) -> Result<Cid, String> {
+        let cid = self.blocks.put(data)?;
+        self.provide_block(&cid);
+        Ok(cid)
+    }
+
+    /// Answers with the block from the local store, or looks up its
+    /// providers and fetches it from the first one that has it.
+    pub fn get_block(&mut self, cid: Cid, sender: oneshot::Sender<Result<Vec<u8>, String>>) {
+        if let Some(data) = self.blocks.get(&cid) {
+            let _ = sender.send(Ok(data));
+            return;
+        }
+        if self.block_fetches.wait(cid.clone(), sender) {
+            debug!(%cid, "looking up block providers");
+            let id = self.kademlia.get_providers(cid.key());
+            self.block_fetches.searching(id, cid);
+        }
+    }
+
+    /// Announces every stored block, the provider records of a previous
+    /// run are gone.
+    pub fn provide_blocks(&mut self) {
+        for cid in self.blocks.cids() {
+            self.provide_block(&cid);
+        }
+    }
+
+    /// Registers with a rendezvous point we just connected to and asks it
+    /// for the peers registered there.
+    pub fn rendezvous_connected(&mut self, point: PeerId) {
+        if self.rendezvous.is_point(&point) {
+            self.rendezvous.connected(&point);
+            self.refresh_rendezvous(point);
+        }
+    }
+
+    /// Renews registrations at a connected rendezvous point that are about
+    /// to run out, and discovers peers registered since the last time.
+    pub fn refresh_rendezvous(&mut self, point: PeerId) {
+        for namespace in self.rendezvous.due(&point) {
+            debug!(peer_id = %point, %namespace, "registering with rendezvous point");
+            self.rendezvous_client.register(namespace, point, None);
+        }
+        for namespace in self.rendezvous.namespaces().to_vec() {
+            let cookie = self.rendezvous.cookie(point, &namespace);
+            self.rendezvous_client
+                .discover(Some(namespace), cookie, None, point);
+        }
+    }
+
+    pub fn topic_peers(&self, topic: &str) -> Vec<PeerId> {
+        self.subscribers
+            .get(topic)
+            .map(|peers| peers.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn send_file_request(&mut self, peer: PeerId, request: FileRequest) {
+        let id = request.transfer_id().to_owned();
+        let request_id = self.file_transfer.send_request(&peer, request);
+        self.transfers.track(request_id, id);
+    }
+
+    fn provide_block(&mut self, cid: &Cid) {
+        if let Err(err) = self.kademlia.start_providing(cid.key()) {
+            warn!(%cid, ?err, "cannot announce block");
+        }
+    }
+
+    /// Asks the next provider of a block we are fetching, or gives up when
+    /// none is left.
+    fn want_block(&mut self, cid: Cid) {
+        match self.block_fetches.next_provider(&cid) {
+            Some(peer) => {
+                debug!(peer_id = %peer, %cid, "wanting block");
+                let wants = WantList {
+                    cids: vec![cid.to_string()],
+                };
+                let request_id = self.block_exchange.send_request(&peer, wants);
+                self.block_fetches.asked(request_id, cid);
+            }
+            None => self.block_fetches.fail(&cid, "no provider has the block"),
+        }
+    }
+
+    fn receive_blocks(&mut self, peer: PeerId, request_id: RequestId, response: BlocksResponse) {
+        for block in response.blocks {
+            let cid = match block.cid.parse::<Cid>() {
+                Ok(cid) if cid.verify(&block.data) => cid,
+                _ => {
+                    warn!(peer_id = %peer, cid = %block.cid, "block does not match its CID");
+                    continue;
+                }
+            };
+            info!(peer_id = %peer, %cid, size = block.data.len(), "block received");
+            match self.blocks.put(&block.data) {
+                // Whoever fetched a block serves it from now on as well.
+                Ok(_) => self.provide_block(&cid),
+                Err(err) => warn!(peer_id = %peer, %cid, %err, "cannot store block"),
+            }
+            self.block_fetches.done(&cid, &block.data);
+        }
+        if let Some(cid) = self.block_fetches.answered(request_id) {
+            self.want_block(cid);
+        }
+    }
+
+    fn sync_state_with(&mut self, peer: PeerId) {
+        debug!(peer_id = %peer, "state anti-entropy");
+        let digest = self.state.digest();
+        self.state_sync
+            .send_request(&peer, StateRequest::Sync { digest });
+    }
+
+    fn merge_delta(&mut self, message: FloodsubMessage) {
+        if message.source == self.local_peer_id {
+            return;
+        }
+        match serde_json::from_slice::<Delta>(&message.data) {
+            Ok(delta) => {
+                let merged = self.state.merge(delta.entries);
+                debug!(peer_id = %message.source, merged, "state delta");
+            }
+            Err(err) => warn!(peer_id = %message.source, %err, "malformed state delta"),
+        }
+    }
+
+    /// Local messages are delivered back to us only so that the id floodsub
+    /// assigned to them can be logged and acked.
+    fn published(&mut self, message: FloodsubMessage) {
+        let id = message_id(&message);
+        let _enter = message_span(&message, &id).entered();
+        info!("message published");
+        let topics = message.topics.iter().map(|topic| topic.id().to_owned());
+        self.acks.published(id, topics.collect());
+    }
+
+    /// Handles a received message once it is in causal order.
+    fn deliver(&mut self, message: FloodsubMessage) {
+        let id = message_id(&message);
+        let _enter = message_span(&message, &id).entered();
+        if let Some((peer, ack)) = self.acks.received(&id, message.source) {
+            self.ack_exchange.send_request(&peer, ack);
+        }
+        let topics: Vec<&str> = message.topics.iter().map(Topic::id).collect();
+        for topic in &topics {
+            self.metrics
+                .messages_received
+                .with_label_values(&[self.topic_label(topic)])
+                .inc();
+        }
+        let payload = String::from_utf8_lossy(&message.data);
+        for topic in &topics {
+            let _ = self.events.send(NodeEvent::Message(ReceivedMessage {
+                topic: (*topic).to_owned(),
+                message_id: id.clone(),
+                peer: message.source.to_base58(),
+                payload: payload.clone().into_owned(),
+            }));
+        }
+        match serde_json::from_str::<Value>(&payload) {
+            Ok(v) => {
+                info!(payload = %v, "message received");
+            }
+            Err(_) => {
+                warn!(%payload, "incorrect format received");
+                for topic in &topics {
+                    self.metrics
+                        .validation_failures
+                        .with_label_values(&[self.topic_label(topic)])
+                        .inc();
+                }
+            }
+        }
+    }
+
+    /// Senders choose a message's topics, so those we are not subscribed
+    /// to share one label rather than each getting a series.
+    fn topic_label<'a>(&self, topic: &'a str) -> &'a str {
+        if self.subscribed.contains(topic) {
+            topic
+        } else {
+            "other"
+        }
+    }
+}
+
+/// Identifies a pubsub message the same way on every node: the publisher
+/// followed by the sequence number floodsub assigned to it.
+pub fn message_id(message: &FloodsubMessage) -> String {
+    let seqno: String = message
+        .sequence_number
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}{}", message.source.to_base58(), seqno)
+}
+
+fn message_span(message: &FloodsubMessage, id: &str) -> Span {
+    let topics: Vec<&str> = message.topics.iter().map(Topic::id).collect();
+    info_span!(
+        "pubsub_message",
+        peer_id = %message.source,
+        topic = %topics.join(","),
+        message_id = %id,
+    )
 }
 
 impl NetworkBehaviourEventProcess<FloodsubEvent> for P2PBehaviour {
     // Called when `floodsub` produces an event.
     fn inject_event(&mut self, message: FloodsubEvent) {
         match message {
+            FloodsubEvent::Message(message) if message.topics.contains(&state_topic()) => {
+                self.merge_delta(message)
+            }
+            FloodsubEvent::Message(message) if message.source == self.local_peer_id => {
+                self.published(message)
+            }
             FloodsubEvent::Message(message) => {
-                let message = String::from_utf8_lossy(&message.data);
-                println!("Received: {:?}", message);
-                match serde_json::from_str::<Value>(&message) {
-                    Ok(v) => {
-                        println!("Received: {:?}", v);
-                    }
-                    Err(_) => {
-                        println!("Incorrect format received");
-                    }
+                for message in self.causal.receive(message) {
+                    self.deliver(message);
+                }
+                self.metrics.messages_held.set(self.causal.pending() as i64);
+            }
+            FloodsubEvent::Subscribed { peer_id, topic } => {
+                if topic == state_topic() {
+                    self.sync_state_with(peer_id);
+                }
+                self.subscribers
+                    .entry(topic.id().to_owned())
+                    .or_default()
+                    .insert(peer_id);
+            }
+            FloodsubEvent::Unsubscribed { peer_id, topic } => {
+                if let Some(peers) = self.subscribers.get_mut(topic.id()) {
+                    peers.remove(&peer_id);
                 }
             }
-            _ => {}
         }
     }
 }
@@ -39,8 +391,9 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for P2PBehaviour {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(list) => {
-                for (peer, _) in list {
+                for (peer, addr) in list {
                     self.floodsub.add_node_to_partial_view(peer);
+                    self.kademlia.add_address(&peer, addr);
                 }
             }
             MdnsEvent::Expired(list) => {
@@ -53,3 +406,399 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for P2PBehaviour {
         }
     }
 }
+
+impl NetworkBehaviourEventProcess<client::Event> for P2PBehaviour {
+    // Called when the relay client produces an event.
+    fn inject_event(&mut self, event: client::Event) {
+        match event {
+            client::Event::ReservationReqAccepted { relay_peer_id, .. } => {
+                info!(peer_id = %relay_peer_id, "reservation accepted by relay");
+            }
+            client::Event::ReservationReqFailed {
+                relay_peer_id,
+                error,
+                ..
+            } => {
+                warn!(peer_id = %relay_peer_id, ?error, "reservation failed on relay");
+            }
+            client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                info!(peer_id = %relay_peer_id, "circuit established through relay");
+            }
+            client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                info!(peer_id = %src_peer_id, "inbound circuit");
+            }
+            event => debug!(?event, "relay client"),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<relay::Event> for P2PBehaviour {
+    // Called when the relay server produces an event.
+    fn inject_event(&mut self, event: relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                info!(peer_id = %src_peer_id, "accepted reservation");
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                info!(peer_id = %src_peer_id, dst_peer_id = %dst_peer_id, "relaying circuit");
+            }
+            event => debug!(?event, "relay"),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<rendezvous::client::Event> for P2PBehaviour {
+    // Called when the rendezvous client produces an event.
+    fn inject_event(&mut self, event: rendezvous::client::Event) {
+        match event {
+            rendezvous::client::Event::Discovered {
+                rendezvous_node,
+                registrations,
+                cookie,
+            } => {
+                self.rendezvous.discovered(rendezvous_node, cookie);
+                for registration in registrations {
+                    let peer = registration.record.peer_id();
+                    if peer == self.local_peer_id {
+                        continue;
+                    }
+                    info!(
+                        peer_id = %peer,
+                        namespace = %registration.namespace,
+                        "discovered peer through rendezvous"
+                    );
+                    // Same as for peers found by mdns, floodsub dials them
+                    // at the addresses they registered with.
+                    for addr in registration.record.addresses() {
+                        self.kademlia.add_address(&peer, addr.clone());
+                    }
+                    self.floodsub.add_node_to_partial_view(peer);
+                }
+            }
+            rendezvous::client::Event::DiscoverFailed {
+                rendezvous_node,
+                namespace,
+                error,
+            } => {
+                warn!(
+                    peer_id = %rendezvous_node,
+                    ?namespace,
+                    ?error,
+                    "rendezvous discovery failed"
+                );
+            }
+            rendezvous::client::Event::Registered {
+                rendezvous_node,
+                ttl,
+                namespace,
+            } => {
+                info!(
+                    peer_id = %rendezvous_node,
+                    %namespace,
+                    ttl,
+                    "registered with rendezvous point"
+                );
+                self.rendezvous.registered(rendezvous_node, namespace, ttl);
+            }
+            rendezvous::client::Event::RegisterFailed(error) => {
+                warn!(%error, "rendezvous registration failed");
+            }
+            rendezvous::client::Event::Expired { peer } => {
+                if !self.mdns.has_node(&peer) {
+                    self.floodsub.remove_node_from_partial_view(&peer);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<rendezvous::server::Event> for P2PBehaviour {
+    // Called when the rendezvous point produces an event.
+    fn inject_event(&mut self, event: rendezvous::server::Event) {
+        match event {
+            rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                info!(peer_id = %peer, namespace = %registration.namespace, "peer registered");
+            }
+            rendezvous::server::Event::PeerNotRegistered {
+                peer,
+                namespace,
+                error,
+            } => {
+                warn!(peer_id = %peer, %namespace, ?error, "registration declined");
+            }
+            event => debug!(?event, "rendezvous point"),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for P2PBehaviour {
+    // Called when `identify` produces an event.
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            debug!(
+                peer_id = %peer_id,
+                agent = %info.agent_version,
+                observed_addr = %info.observed_addr,
+                "identified peer"
+            );
+            // Peers reached by dialing or through a relay join the DHT
+            // routing table at the addresses they listen on.
+            if info.protocols.iter().any(|p| p.as_bytes() == KAD_PROTOCOL) {
+                for addr in info.listen_addrs {
+                    self.kademlia.add_address(&peer_id, addr);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<autonat::Event> for P2PBehaviour {
+    // Called when `autonat` produces an event.
+    fn inject_event(&mut self, event: autonat::Event) {
+        if let autonat::Event::StatusChanged { old, new } = event {
+            info!(?old, ?new, "NAT status changed");
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<ping::Event> for P2PBehaviour {
+    // Called when `ping` produces an event.
+    fn inject_event(&mut self, event: ping::Event) {
+        if let Ok(ping::Success::Ping { rtt }) = event.result {
+            self.metrics.ping_rtt.observe(rtt.as_secs_f64());
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<FileRequest, FileResponse>>
+    for P2PBehaviour
+{
+    // Called when the file transfer protocol produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<FileRequest, FileResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = match request {
+                        FileRequest::Offer(offer) => {
+                            info!(
+                                peer_id = %peer,
+                                transfer_id = %offer.id,
+                                name = %offer.name,
+                                size = offer.size,
+                                "file offered"
+                            );
+                            let (response, next) = self.transfers.receive_offer(peer, offer);
+                            match next {
+                                Ok(request) => self.send_file_request(peer, request),
+                                Err(Some(outcome)) => info!(peer_id = %peer, "{}", outcome),
+                                Err(None) => {}
+                            }
+                            response
+                        }
+                        FileRequest::Chunk { id, index } => {
+                            self.transfers.serve_chunk(peer, &id, index)
+                        }
+                    };
+                    if let FileResponse::Refused { reason } = &response {
+                        warn!(peer_id = %peer, %reason, "file request refused");
+                    }
+                    let _ = self.file_transfer.send_response(channel, response);
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    let (next, outcome) =
+                        self.transfers.receive_response(peer, request_id, response);
+                    if let Some(outcome) = outcome {
+                        info!(peer_id = %peer, "{}", outcome);
+                    }
+                    if let Some(request) = next {
+                        self.send_file_request(peer, request);
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!(peer_id = %peer, ?error, "file request failed");
+                if let Some(outcome) = self.transfers.request_failed(request_id) {
+                    info!(peer_id = %peer, "{}", outcome);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!(peer_id = %peer, ?error, "inbound file request failed");
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<Ack, AckResponse>> for P2PBehaviour {
+    // Called when the ack protocol produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<Ack, AckResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let message_id = request.message_id.clone();
+                    let kind = request.kind;
+                    let response = self.acks.receive(peer, request);
+                    match &response {
+                        AckResponse::Recorded => {
+                            info!(peer_id = %peer, %message_id, ?kind, "message acked")
+                        }
+                        AckResponse::Refused { reason } => {
+                            warn!(peer_id = %peer, %message_id, %reason, "ack refused")
+                        }
+                    }
+                    let _ = self.ack_exchange.send_response(channel, response);
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    if let AckResponse::Refused { reason } = response {
+                        debug!(peer_id = %peer, %reason, "publisher refused our ack");
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                debug!(peer_id = %peer, ?error, "ack not delivered");
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!(peer_id = %peer, ?error, "inbound ack failed");
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<StateRequest, StateResponse>>
+    for P2PBehaviour
+{
+    // Called when the state anti-entropy protocol produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<StateRequest, StateResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = match request {
+                        StateRequest::Sync { digest } => {
+                            let (entries, wanted) = self.state.diff(&digest);
+                            StateResponse::Sync { entries, wanted }
+                        }
+                        StateRequest::Push { entries } => {
+                            let merged = self.state.merge(entries);
+                            debug!(peer_id = %peer, merged, "state pushed");
+                            StateResponse::Merged
+                        }
+                    };
+                    let _ = self.state_sync.send_response(channel, response);
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    if let StateResponse::Sync { entries, wanted } = response {
+                        let merged = self.state.merge(entries);
+                        debug!(peer_id = %peer, merged, wanted = wanted.len(), "state synced");
+                        if !wanted.is_empty() {
+                            let entries = self.state.pick(&wanted);
+                            self.state_sync
+                                .send_request(&peer, StateRequest::Push { entries });
+                        }
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                debug!(peer_id = %peer, ?error, "state sync failed");
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!(peer_id = %peer, ?error, "inbound state sync failed");
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for P2PBehaviour {
+    // Called when `kademlia` produces an event.
+    fn inject_event(&mut self, event: KademliaEvent) {
+        match event {
+            KademliaEvent::OutboundQueryCompleted { id, result, .. } => match result {
+                QueryResult::PutRecord(result) => self.dht_queries.put_done(id, result),
+                QueryResult::GetRecord(result) => self.dht_queries.get_done(id, result),
+                QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. }))
+                | QueryResult::GetProviders(Err(GetProvidersError::Timeout {
+                    providers, ..
+                })) => {
+                    let local_peer_id = self.local_peer_id;
+                    let providers = providers.into_iter().filter(|p| *p != local_peer_id);
+                    if let Some(cid) = self.block_fetches.providers_found(id, providers) {
+                        self.want_block(cid);
+                    }
+                }
+                QueryResult::RepublishRecord(Err(err)) => {
+                    debug!(?err, "record not republished")
+                }
+                result => debug!(?result, "dht query completed"),
+            },
+            KademliaEvent::RoutingUpdated {
+                peer, is_new_peer, ..
+            } => {
+                debug!(peer_id = %peer, "dht routing updated");
+                // Provider records announced while we knew no other peer
+                // went nowhere, announce them again now that we know one.
+                let known: usize = self.kademlia.kbuckets().map(|b| b.num_entries()).sum();
+                if is_new_peer && known == 1 {
+                    self.provide_blocks();
+                }
+            }
+            event => debug!(?event, "dht"),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<WantList, BlocksResponse>> for P2PBehaviour {
+    // Called when the block exchange protocol produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<WantList, BlocksResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = self.blocks.serve(&request);
+                    debug!(
+                        peer_id = %peer,
+                        sent = response.blocks.len(),
+                        missing = response.missing.len(),
+                        "blocks wanted"
+                    );
+                    let _ = self.block_exchange.send_response(channel, response);
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => self.receive_blocks(peer, request_id, response),
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!(peer_id = %peer, ?error, "block request failed");
+                if let Some(cid) = self.block_fetches.answered(request_id) {
+                    self.want_block(cid);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!(peer_id = %peer, ?error, "inbound block request failed");
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use crate::codec::{read_json, read_json_max, write_json};
+use async_trait::async_trait;
+use data_encoding::BASE32_NOPAD;
+use futures::{channel::oneshot, AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::ProtocolName,
+    kad::{record::Key, QueryId},
+    multihash::{Code, Multihash, MultihashDigest},
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, io, iter, path::Path, str::FromStr};
+use tracing::warn;
+
+/// Largest block we store or hand out, bigger blobs have to be split by
+/// whoever puts them.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Blocks travel as JSON byte arrays, which take up to four bytes per byte.
+const MAX_RESPONSE_SIZE: usize = 5 * MAX_BLOCK_SIZE;
+
+/// Multicodec codes making up a CID.
+const CID_V1: u8 = 0x01;
+const RAW: u8 = 0x55;
+const SHA2_256: u64 = 0x12;
+
+/// Content identifier of a block: a CIDv1 with the raw codec over the
+/// SHA-256 multihash of its bytes, written in lower case base32 like IPFS
+/// does, e.g. `bafkrei...`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cid(Vec<u8>);
+
+impl Cid {
+    pub fn of(data: &[u8]) -> Self {
+        let mut bytes = vec![CID_V1, RAW];
+        bytes.extend(Code::Sha2_256.digest(data).to_bytes());
+        Cid(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        match bytes {
+            [CID_V1, RAW, multihash @ ..] => {
+                let multihash = Multihash::from_bytes(multihash).map_err(|err| err.to_string())?;
+                if multihash.code() != SHA2_256 {
+                    return Err("only sha2-256 CIDs are supported".into());
+                }
+                Ok(Cid(bytes.to_vec()))
+            }
+            _ => Err("only raw CIDv1 is supported".into()),
+        }
+    }
+
+    /// The DHT key provider records for the block are kept under.
+    pub fn key(&self) -> Key {
+        Key::from(self.0.clone())
+    }
+
+    /// Whether `data` is the block this CID names.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        Cid::of(data) == *self
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "b{}", BASE32_NOPAD.encode(&self.0).to_ascii_lowercase())
+    }
+}
+
+impl FromStr for Cid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let base32 = s
+            .strip_prefix('b')
+            .ok_or_else(|| format!("{}: expected a base32 CID", s))?;
+        let bytes = BASE32_NOPAD
+            .decode(base32.to_ascii_uppercase().as_bytes())
+            .map_err(|err| format!("{}: {}", s, err))?;
+        Cid::from_bytes(&bytes).map_err(|err| format!("{}: {}", s, err))
+    }
+}
+
+/// Blocks kept in sled by CID. A block is only ever written under the hash
+/// of its bytes, so entries never change once there.
+pub struct BlockStore {
+    blocks: sled::Tree,
+}
+
+impl BlockStore {
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(BlockStore {
+            blocks: db.open_tree("blocks")?,
+        })
+    }
+
+    /// Stores `data` and returns its CID.
+    pub fn put(&self, data: &[u8]) -> Result<Cid, String> {
+        if data.len() > MAX_BLOCK_SIZE {
+            return Err(format!(
+                "block of {} bytes is over the limit of {}",
+                data.len(),
+                MAX_BLOCK_SIZE
+            ));
+        }
+        let cid = Cid::of(data);
+        self.blocks
+            .insert(&cid.0, data)
+            .map_err(|err| err.to_string())?;
+        Ok(cid)
+    }
+
+    pub fn get(&self, cid: &Cid) -> Option<Vec<u8>> {
+        match self.blocks.get(&cid.0) {
+            Ok(data) => data.map(|data| data.to_vec()),
+            Err(err) => {
+                warn!(%err, %cid, "cannot read block");
+                None
+            }
+        }
+    }
+
+    /// Every block we have, to announce as their provider.
+    pub fn cids(&self) -> Vec<Cid> {
+        self.blocks
+            .iter()
+            .keys()
+            .filter_map(|key| key.ok())
+            .filter_map(|key| Cid::from_bytes(&key).ok())
+            .collect()
+    }
+
+    /// Answers a want-list with the blocks we have, as many as fit in one
+    /// response. Those left out are neither sent nor missing, the peer asks
+    /// for them again.
+    pub fn serve(&self, wants: &WantList) -> BlocksResponse {
+        let mut response = BlocksResponse {
+            blocks: Vec::new(),
+            missing: Vec::new(),
+        };
+        let mut size = 0;
+        for wanted in &wants.cids {
+            let data = wanted.parse().ok().and_then(|cid| self.get(&cid));
+            match data {
+                Some(data) if response.blocks.is_empty() || size + data.len() <= MAX_BLOCK_SIZE => {
+                    size += data.len();
+                    response.blocks.push(Block {
+                        cid: wanted.clone(),
+                        data,
+                    });
+                }
+                Some(_) => {}
+                None => response.missing.push(wanted.clone()),
+            }
+        }
+        response
+    }
+}
+
+/// CIDs a peer asks a provider for.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WantList {
+    pub cids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Block {
+    pub cid: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlocksResponse {
+    pub blocks: Vec<Block>,
+    /// Wanted blocks the provider does not have.
+    pub missing: Vec<String>,
+}
+
+struct Fetch {
+    waiters: Vec<oneshot::Sender<Result<Vec<u8>, String>>>,
+    /// Providers not asked yet.
+    providers: Vec<PeerId>,
+}
+
+/// `blocks_get` calls for blocks we do not have, from the provider lookup
+/// until one provider hands the block over or all of them failed.
+#[derive(Default)]
+pub struct BlockFetches {
+    fetches: HashMap<Cid, Fetch>,
+    queries: HashMap<QueryId, Cid>,
+    requests: HashMap<RequestId, Cid>,
+}
+
+impl BlockFetches {
+    /// Adds a caller waiting for `cid`, returns whether the block has to
+    /// be looked up, i.e. nobody was waiting for it already.
+    pub fn wait(&mut self, cid: Cid, sender: oneshot::Sender<Result<Vec<u8>, String>>) -> bool {
+        match self.fetches.get_mut(&cid) {
+            Some(fetch) => {
+                fetch.waiters.push(sender);
+                false
+            }
+            None => {
+                self.fetches.insert(
+                    cid,
+                    Fetch {
+                        waiters: vec![sender],
+                        providers: Vec::new(),
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    pub fn searching(&mut self, id: QueryId, cid: Cid) {
+        self.queries.insert(id, cid);
+    }
+
+    /// Providers a lookup found, returns the block they were looked up for.
+    pub fn providers_found(
+        &mut self,
+        id: QueryId,
+        providers: impl IntoIterator<Item = PeerId>,
+    ) -> Option<Cid> {
+        let cid = self.queries.remove(&id)?;
+        let fetch = self.fetches.get_mut(&cid)?;
+        fetch.providers.extend(providers);
+        Some(cid)
+    }
+
+    pub fn next_provider(&mut self, cid: &Cid) -> Option<PeerId> {
+        self.fetches.get_mut(cid)?.providers.pop()
+    }
+
+    pub fn asked(&mut self, id: RequestId, cid: Cid) {
+        self.requests.insert(id, cid);
+    }
+
+    /// The block a want-list went out for, if it is still being fetched.
+    pub fn answered(&mut self, id: RequestId) -> Option<Cid> {
+        self.requests
+            .remove(&id)
+            .filter(|cid| self.fetches.contains_key(cid))
+    }
+
+    pub fn done(&mut self, cid: &Cid, data: &[u8]) {
+        for waiter in self.fetches.remove(cid).into_iter().flat_map(|f| f.waiters) {
+            let _ = waiter.send(Ok(data.to_vec()));
+        }
+    }
+
+    pub fn fail(&mut self, cid: &Cid, reason: &str) {
+        for waiter in self.fetches.remove(cid).into_iter().flat_map(|f| f.waiters) {
+            let _ = waiter.send(Err(reason.to_owned()));
+        }
+    }
+}
+
+/// Request-response behaviour fetching blocks from their providers.
+pub type BlockExchange = RequestResponse<BlockCodec>;
+
+pub fn block_exchange() -> BlockExchange {
+    RequestResponse::new(
+        BlockCodec,
+        iter::once((BlockProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockProtocol;
+
+impl ProtocolName for BlockProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-node/blocks/1.0.0"
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockCodec;
+
+#[async_trait]
+impl RequestResponseCodec for BlockCodec {
+    type Protocol = BlockProtocol;
+    type Request = WantList;
+    type Response = BlocksResponse;
+
+    async fn read_request<T>(&mut self, _: &BlockProtocol, io: &mut T) -> io::Result<WantList>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &BlockProtocol,
+        io: &mut T,
+    ) -> io::Result<BlocksResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json_max(io, MAX_RESPONSE_SIZE).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &BlockProtocol,
+        io: &mut T,
+        wants: WantList,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &wants).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &BlockProtocol,
+        io: &mut T,
+        response: BlocksResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cid_matches_ipfs_and_round_trips() {
+        let cid = Cid::of(b"hello world");
+        let text = cid.to_string();
+        assert_eq!(
+            text,
+            "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e"
+        );
+        assert_eq!(text.parse::<Cid>(), Ok(cid.clone()));
+        assert!(cid.verify(b"hello world"));
+        assert!(!cid.verify(b"hello world!"));
+    }
+
+    #[test]
+    fn other_cids_are_refused() {
+        // CIDv0, a dag-pb CIDv1, and text that is not base32.
+        for text in [
+            "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4",
+            "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+            "b1234",
+        ] {
+            assert!(text.parse::<Cid>().is_err(), "{}", text);
+        }
+    }
+}
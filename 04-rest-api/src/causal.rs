@@ -0,0 +1,285 @@
+use libp2p::{floodsub::FloodsubMessage, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Messages published by each peer, by base58 peer id.
+pub type VectorClock = BTreeMap<String, u64>;
+
+/// Version of the envelope below, bumped whenever its fields change.
+const ENVELOPE_VERSION: u32 = 1;
+
+/// What goes out on a topic: the payload along with the publisher's clock
+/// at the time, which names everything it had seen before.
+///
+/// On the wire this is the JSON object
+/// `{"v":1,"clock":{"<peer id>":<count>,..},"payload":"<text>"}`. Nodes
+/// without causal ordering show it as is, and data that is not an envelope
+/// of our version is passed on untouched.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    v: u32,
+    clock: VectorClock,
+    payload: String,
+}
+
+struct Pending {
+    source: String,
+    clock: VectorClock,
+    message: FloodsubMessage,
+    since: Instant,
+}
+
+impl Pending {
+    /// Number of the message among those its source published.
+    fn count(&self) -> u64 {
+        self.clock.get(&self.source).copied().unwrap_or_default()
+    }
+}
+
+/// The clock of one topic and the messages waiting on it.
+#[derive(Default)]
+struct TopicClock {
+    clock: VectorClock,
+    /// In order of arrival.
+    pending: Vec<Pending>,
+}
+
+impl TopicClock {
+    fn seen(&self, peer: &str) -> Option<u64> {
+        self.clock.get(peer).copied()
+    }
+
+    /// Whether the message `source` numbered `count` was delivered or given
+    /// up on already, or is waiting.
+    fn known(&self, source: &str, count: u64) -> bool {
+        self.seen(source).is_some_and(|seen| count <= seen)
+            || self
+                .pending
+                .iter()
+                .any(|pending| pending.source == source && pending.count() == count)
+    }
+
+    /// Whether everything `pending` depends on was delivered. Our own
+    /// messages always were, and messages from its source that we skipped
+    /// earlier no longer hold it up.
+    fn deliverable(&self, local: &str, pending: &Pending) -> bool {
+        pending.clock.iter().all(|(peer, &count)| {
+            if peer == local {
+                true
+            } else if *peer == pending.source {
+                count <= self.seen(peer).unwrap_or_default() + 1
+            } else {
+                count <= self.seen(peer).unwrap_or_default()
+            }
+        })
+    }
+
+    fn deliver(&mut self, pending: Pending) -> FloodsubMessage {
+        for (peer, count) in pending.clock {
+            let seen = self.clock.entry(peer).or_default();
+            *seen = (*seen).max(count);
+        }
+        pending.message
+    }
+
+    /// Delivers whatever can be, in causal order.
+    fn drain(&mut self, local: &str) -> Vec<FloodsubMessage> {
+        let mut delivered = Vec::new();
+        while let Some(at) = self
+            .pending
+            .iter()
+            .position(|pending| self.deliverable(local, pending))
+        {
+            let pending = self.pending.remove(at);
+            delivered.push(self.deliver(pending));
+        }
+        delivered
+    }
+}
+
+/// Puts messages received on a topic in causal order, so a reply is never
+/// handed on before the message it answers. Each message carries the
+/// vector clock of its publisher and waits until everything it depends on
+/// arrived, or until it waited `max_delay`, whichever comes first.
+///
+/// Messages are ordered within their first topic. Payloads published by
+/// nodes without ordering are passed on as they arrive.
+pub struct CausalOrder {
+    local: String,
+    max_delay: Duration,
+    topics: HashMap<String, TopicClock>,
+}
+
+impl CausalOrder {
+    pub fn new(local_peer_id: &PeerId, max_delay: Duration) -> Self {
+        CausalOrder {
+            local: local_peer_id.to_base58(),
+            max_delay,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// Wraps a payload about to be published on `topic` with our clock.
+    pub fn stamp(&mut self, topic: &str, payload: String) -> Vec<u8> {
+        let topic = self.topics.entry(topic.to_owned()).or_default();
+        *topic.clock.entry(self.local.clone()).or_default() += 1;
+        let envelope = Envelope {
+            v: ENVELOPE_VERSION,
+            clock: topic.clock.clone(),
+            payload,
+        };
+        serde_json::to_vec(&envelope).expect("envelope serializes to JSON")
+    }
+
+    /// Takes a received message and returns it, with its payload
+    /// unwrapped, along with whatever it unblocked. Returns nothing while
+    /// it waits for earlier messages, and drops messages seen before or
+    /// ones that arrive after we stopped waiting for them.
+    pub fn receive(&mut self, mut message: FloodsubMessage) -> Vec<FloodsubMessage> {
+        let envelope = match serde_json::from_slice::<Envelope>(&message.data) {
+            Ok(envelope) if envelope.v == ENVELOPE_VERSION => envelope,
+            _ => return vec![message],
+        };
+        let topic = match message.topics.first() {
+            Some(topic) => topic.id().to_owned(),
+            None => return vec![message],
+        };
+        message.data = envelope.payload.into_bytes();
+        let source = message.source.to_base58();
+        let count = envelope.clock.get(&source).copied().unwrap_or_default();
+        let topic = self.topics.entry(topic).or_default();
+        if count == 0 || topic.known(&source, count) {
+            return Vec::new();
+        }
+        // Whatever a peer published before we first heard from it is not
+        // coming, so start counting from just before this message.
+        if topic.seen(&source).is_none() {
+            topic.clock.insert(source.clone(), count - 1);
+        }
+        topic.pending.push(Pending {
+            source,
+            clock: envelope.clock,
+            message,
+            since: Instant::now(),
+        });
+        topic.drain(&self.local)
+    }
+
+    /// Stops waiting for the dependencies of messages held longer than the
+    /// maximum delay, and returns them along with whatever they unblocked.
+    pub fn expire(&mut self) -> Vec<FloodsubMessage> {
+        let mut delivered = Vec::new();
+        for topic in self.topics.values_mut() {
+            while let Some(at) = topic
+                .pending
+                .iter()
+                .position(|pending| pending.since.elapsed() >= self.max_delay)
+            {
+                let pending = topic.pending.remove(at);
+                delivered.push(topic.deliver(pending));
+                delivered.extend(topic.drain(&self.local));
+            }
+        }
+        delivered
+    }
+
+    /// Messages held back, over all topics.
+    pub fn pending(&self) -> usize {
+        self.topics.values().map(|topic| topic.pending.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::floodsub::Topic;
+
+    fn message(source: PeerId, data: Vec<u8>) -> FloodsubMessage {
+        FloodsubMessage {
+            source,
+            data,
+            sequence_number: Vec::new(),
+            topics: vec![Topic::new("chat")],
+        }
+    }
+
+    fn payloads(messages: Vec<FloodsubMessage>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|message| String::from_utf8(message.data).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reply_waits_for_what_it_answers() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut alice_order = CausalOrder::new(&alice, Duration::from_secs(60));
+        let mut bob_order = CausalOrder::new(&bob, Duration::from_secs(60));
+        let mut order = CausalOrder::new(&PeerId::random(), Duration::from_secs(60));
+
+        let hello = message(alice, alice_order.stamp("chat", "hello".into()));
+        let first = message(alice, alice_order.stamp("chat", "anyone?".into()));
+        bob_order.receive(message(alice, hello.data.clone()));
+        bob_order.receive(message(alice, first.data.clone()));
+        let reply = message(bob, bob_order.stamp("chat", "hi".into()));
+
+        assert_eq!(payloads(order.receive(hello)), ["hello"]);
+        assert!(order.receive(reply).is_empty());
+        assert_eq!(order.pending(), 1);
+        assert_eq!(payloads(order.receive(first)), ["anyone?", "hi"]);
+        assert_eq!(order.pending(), 0);
+    }
+
+    #[test]
+    fn duplicates_and_old_messages_are_dropped() {
+        let alice = PeerId::random();
+        let mut alice_order = CausalOrder::new(&alice, Duration::from_secs(60));
+        let mut order = CausalOrder::new(&PeerId::random(), Duration::from_secs(60));
+
+        let first = alice_order.stamp("chat", "one".into());
+        let second = alice_order.stamp("chat", "two".into());
+        let third = alice_order.stamp("chat", "three".into());
+        assert_eq!(
+            payloads(order.receive(message(alice, first.clone()))),
+            ["one"]
+        );
+        assert!(order.receive(message(alice, first)).is_empty());
+
+        assert!(order.receive(message(alice, third.clone())).is_empty());
+        assert!(order.receive(message(alice, third)).is_empty());
+        assert_eq!(order.pending(), 1);
+        assert_eq!(
+            payloads(order.receive(message(alice, second.clone()))),
+            ["two", "three"]
+        );
+        assert!(order.receive(message(alice, second)).is_empty());
+    }
+
+    #[test]
+    fn late_messages_are_dropped_after_expiry() {
+        let alice = PeerId::random();
+        let mut alice_order = CausalOrder::new(&alice, Duration::from_secs(60));
+        let mut order = CausalOrder::new(&PeerId::random(), Duration::ZERO);
+
+        let first = alice_order.stamp("chat", "one".into());
+        let second = alice_order.stamp("chat", "two".into());
+        let third = alice_order.stamp("chat", "three".into());
+        order.receive(message(alice, first));
+        assert!(order.receive(message(alice, third)).is_empty());
+        assert_eq!(payloads(order.expire()), ["three"]);
+        assert!(order.receive(message(alice, second)).is_empty());
+    }
+
+    #[test]
+    fn other_data_is_passed_on() {
+        let alice = PeerId::random();
+        let mut order = CausalOrder::new(&PeerId::random(), Duration::from_secs(60));
+        let future = br#"{"v":2,"clock":{},"payload":"hi"}"#.to_vec();
+        for data in [b"plain text".to_vec(), future] {
+            assert_eq!(order.receive(message(alice, data.clone()))[0].data, data);
+        }
+    }
+}
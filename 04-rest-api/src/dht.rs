@@ -0,0 +1,365 @@
+use futures::channel::oneshot;
+use libp2p::{
+    kad::{
+        record::{
+            store::{self, Error, MemoryStore, MemoryStoreConfig, RecordStore},
+            Key, ProviderRecord, Record,
+        },
+        GetRecordError, GetRecordResult, KademliaConfig, PutRecordError, PutRecordResult, QueryId,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    vec,
+};
+use tracing::warn;
+
+/// Our DHT, kept apart from the public IPFS one.
+pub const KAD_PROTOCOL: &[u8] = b"/p2p-node/kad/1.0.0";
+
+/// Largest value a record may hold.
+const MAX_VALUE_BYTES: usize = 65 * 1024;
+
+/// Most records kept, our own and those stored for others.
+const MAX_RECORDS: usize = 16 * 1024;
+
+/// Most keys we announce ourselves as provider of, one per stored block.
+const MAX_PROVIDED_KEYS: usize = 16 * 1024;
+
+/// How the node takes part in the Kademlia DHT.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Number of peers closest to a key that a record is stored on.
+    pub replication_factor: NonZeroUsize,
+    /// How long a record lives on other peers unless republished.
+    pub record_ttl: Duration,
+    /// How often records we published are published again.
+    pub republish_interval: Duration,
+    /// Where records are kept across restarts.
+    pub store_path: PathBuf,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            replication_factor: NonZeroUsize::new(20).expect("20 is not zero"),
+            record_ttl: Duration::from_secs(36 * 60 * 60),
+            republish_interval: Duration::from_secs(24 * 60 * 60),
+            store_path: "dht".into(),
+        }
+    }
+}
+
+impl DhtConfig {
+    pub fn kademlia_config(&self) -> KademliaConfig {
+        let mut config = KademliaConfig::default();
+        config
+            .set_protocol_name(KAD_PROTOCOL)
+            .set_replication_factor(self.replication_factor)
+            .set_record_ttl(Some(self.record_ttl))
+            .set_publication_interval(Some(self.republish_interval));
+        config
+    }
+}
+
+/// A record as kept on disk. Expiry is wall clock time there, as an
+/// `Instant` means nothing after a restart.
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    value: Vec<u8>,
+    publisher: Option<String>,
+    /// Milliseconds since the unix epoch.
+    expires: Option<u64>,
+}
+
+impl StoredRecord {
+    fn new(record: &Record) -> Self {
+        StoredRecord {
+            value: record.value.clone(),
+            publisher: record.publisher.as_ref().map(PeerId::to_base58),
+            expires: record.expires.map(|expires| {
+                let left = expires.saturating_duration_since(Instant::now());
+                unix_millis() + left.as_millis() as u64
+            }),
+        }
+    }
+
+    /// `None` once it expired.
+    fn into_record(self, key: Key) -> Option<Record> {
+        let expires = match self.expires {
+            Some(expires) => {
+                let left = expires.checked_sub(unix_millis())?;
+                Some(Instant::now() + Duration::from_millis(left))
+            }
+            None => None,
+        };
+        Some(Record {
+            key,
+            value: self.value,
+            publisher: self.publisher.and_then(|peer| peer.parse().ok()),
+            expires,
+        })
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Record store keeping value records in sled, so a node still serves and
+/// republishes them after a restart. Provider records are short lived and
+/// announced again by their providers, they stay in memory.
+pub struct SledStore {
+    records: sled::Tree,
+    /// Number of records, `sled::Tree::len` walks the whole tree.
+    len: usize,
+    providers: MemoryStore,
+}
+
+impl SledStore {
+    pub fn open(local_peer_id: PeerId, path: &Path) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        let records = db.open_tree("records")?;
+        Ok(SledStore {
+            len: records.len(),
+            records,
+            providers: MemoryStore::with_config(
+                local_peer_id,
+                MemoryStoreConfig {
+                    max_provided_keys: MAX_PROVIDED_KEYS,
+                    ..Default::default()
+                },
+            ),
+        })
+    }
+
+    fn load(&self, key: Key, value: &[u8]) -> Option<Record> {
+        let stored: StoredRecord = serde_json::from_slice(value).ok()?;
+        stored.into_record(key)
+    }
+}
+
+impl<'a> RecordStore<'a> for SledStore {
+    type RecordsIter = vec::IntoIter<Cow<'a, Record>>;
+    type ProvidedIter = <MemoryStore as RecordStore<'a>>::ProvidedIter;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'a, Record>> {
+        match self.records.get(k) {
+            Ok(value) => self.load(k.clone(), &value?).map(Cow::Owned),
+            Err(err) => {
+                warn!(%err, "cannot read dht record");
+                None
+            }
+        }
+    }
+
+    fn put(&'a mut self, r: Record) -> store::Result<()> {
+        if r.value.len() > MAX_VALUE_BYTES {
+            return Err(Error::ValueTooLarge);
+        }
+        if self.len >= MAX_RECORDS && !self.records.contains_key(&r.key).unwrap_or(false) {
+            return Err(Error::MaxRecords);
+        }
+        let value = serde_json::to_vec(&StoredRecord::new(&r)).expect("record serializes to JSON");
+        // The store errors have no room for I/O, and a record we failed to
+        // keep must not be reported as stored, so say we are full.
+        let replaced = self.records.insert(&r.key, value).map_err(|err| {
+            warn!(%err, "cannot write dht record");
+            Error::MaxRecords
+        })?;
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        match self.records.remove(k) {
+            Ok(Some(_)) => self.len -= 1,
+            Ok(None) => {}
+            Err(err) => warn!(%err, "cannot remove dht record"),
+        }
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        let mut records = Vec::new();
+        for item in self.records.iter() {
+            match item {
+                Ok((key, value)) => {
+                    if let Some(record) = self.load(Key::from(key.to_vec()), &value) {
+                        records.push(Cow::Owned(record));
+                    }
+                }
+                Err(err) => warn!(%err, "cannot read dht records"),
+            }
+        }
+        records.into_iter()
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> store::Result<()> {
+        self.providers.add_provider(record)
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.providers.providers(key)
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.providers.provided()
+    }
+
+    fn remove_provider(&'a mut self, k: &Key, p: &PeerId) {
+        self.providers.remove_provider(k, p)
+    }
+}
+
+/// How a `dht_put` went. The record is stored locally either way.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PutOutcome {
+    pub key: String,
+    /// Whether at least one other peer took a copy.
+    pub replicated: bool,
+}
+
+/// How a `dht_remove` went. Only our copy goes, peers holding replicas keep
+/// serving the record until its TTL runs out.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveOutcome {
+    pub key: String,
+    /// Whether there was a local copy to remove.
+    pub removed: bool,
+    /// Always true, there is no way to delete a record from other peers.
+    pub local_only: bool,
+}
+
+enum Pending {
+    Put(oneshot::Sender<Result<PutOutcome, String>>),
+    Get(oneshot::Sender<Option<Vec<u8>>>),
+}
+
+/// RPC calls waiting for their Kademlia query to finish.
+#[derive(Default)]
+pub struct DhtQueries {
+    pending: HashMap<QueryId, Pending>,
+}
+
+impl DhtQueries {
+    pub fn put(&mut self, id: QueryId, sender: oneshot::Sender<Result<PutOutcome, String>>) {
+        self.pending.insert(id, Pending::Put(sender));
+    }
+
+    pub fn get(&mut self, id: QueryId, sender: oneshot::Sender<Option<Vec<u8>>>) {
+        self.pending.insert(id, Pending::Get(sender));
+    }
+
+    pub fn put_done(&mut self, id: QueryId, result: PutRecordResult) {
+        if let Some(Pending::Put(sender)) = self.pending.remove(&id) {
+            let (key, replicated) = match result {
+                Ok(ok) => (ok.key, true),
+                Err(PutRecordError::QuorumFailed { key, success, .. })
+                | Err(PutRecordError::Timeout { key, success, .. }) => (key, !success.is_empty()),
+            };
+            let key = String::from_utf8_lossy(key.as_ref()).into_owned();
+            let _ = sender.send(Ok(PutOutcome { key, replicated }));
+        }
+    }
+
+    pub fn get_done(&mut self, id: QueryId, result: GetRecordResult) {
+        if let Some(Pending::Get(sender)) = self.pending.remove(&id) {
+            let records = match result {
+                Ok(ok) => ok.records,
+                Err(GetRecordError::QuorumFailed { records, .. })
+                | Err(GetRecordError::Timeout { records, .. }) => records,
+                Err(GetRecordError::NotFound { .. }) => Vec::new(),
+            };
+            let value = records.into_iter().next().map(|found| found.record.value);
+            let _ = sender.send(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A fresh directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dht-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(key: &str, value: &[u8]) -> Record {
+        Record::new(Key::new(&key), value.to_vec())
+    }
+
+    #[test]
+    fn expiry_survives_storing() {
+        let mut stored = record("key", b"value");
+        stored.expires = Some(Instant::now() + Duration::from_secs(60));
+        let loaded = StoredRecord::new(&stored)
+            .into_record(stored.key.clone())
+            .unwrap();
+        let left = loaded.expires.unwrap() - Instant::now();
+        assert!(left > Duration::from_secs(55) && left <= Duration::from_secs(60));
+        assert_eq!(loaded.value, b"value");
+
+        let expired = StoredRecord {
+            value: b"value".to_vec(),
+            publisher: None,
+            expires: Some(unix_millis() - 1000),
+        };
+        assert!(expired.into_record(stored.key).is_none());
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = temp_dir("reopen");
+        let peer = PeerId::random();
+        let mut store = SledStore::open(peer, &dir).unwrap();
+        store.put(record("kept", b"value")).unwrap();
+        drop(store);
+        let store = SledStore::open(peer, &dir).unwrap();
+        let loaded = store.get(&Key::new(&"kept")).unwrap();
+        assert_eq!(loaded.value, b"value");
+        assert_eq!(store.records().count(), 1);
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let dir = temp_dir("limits");
+        let mut store = SledStore::open(PeerId::random(), &dir).unwrap();
+        assert!(store.put(record("largest", &[0; MAX_VALUE_BYTES])).is_ok());
+        assert!(matches!(
+            store.put(record("too-large", &[0; MAX_VALUE_BYTES + 1])),
+            Err(Error::ValueTooLarge)
+        ));
+        for i in 1..MAX_RECORDS {
+            store.put(record(&i.to_string(), b"")).unwrap();
+        }
+        assert!(matches!(
+            store.put(record("one-more", b"")),
+            Err(Error::MaxRecords)
+        ));
+        // Replacing a record we have is still fine.
+        assert!(store.put(record("largest", b"smaller")).is_ok());
+        store.remove(&Key::new(&"1"));
+        assert!(store.put(record("one-more", b"")).is_ok());
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+use crate::state::Change;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events buffered for each subscriber, one that falls further behind
+/// misses the oldest.
+const EVENT_BUFFER: usize = 1024;
+
+/// A message delivered on a topic, in causal order.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMessage {
+    pub topic: String,
+    pub message_id: String,
+    pub peer: String,
+    pub payload: String,
+}
+
+/// Something that happened on the node, for RPC clients to subscribe to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    Message(ReceivedMessage),
+    PeerConnected { peer: String, address: String },
+    PeerDisconnected { peer: String },
+    StateChanged(Change),
+}
+
+pub type Events = broadcast::Sender<NodeEvent>;
+
+pub fn events() -> Events {
+    broadcast::channel(EVENT_BUFFER).0
+}
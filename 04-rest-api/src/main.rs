@@ -1,59 +1,39 @@
 //Follwing wil create two process, second only subscribed to ws
 //cargo run
 //cargo run -- -p 4000 /ip4/127.0.0.1/tcp/3001/ws
+//
+//Relaying on localhost, a relay and two nodes dialing each other through it:
+//cargo run -- --relay-server --external-addr /ip4/127.0.0.1/tcp/8500
+//cargo run -- --http-port 8586 --p2p-port 8501 --relay /ip4/127.0.0.1/tcp/8500/p2p/<RELAY_ID>
+//cargo run -- --http-port 8587 --p2p-port 8502 /ip4/127.0.0.1/tcp/8500/p2p/<RELAY_ID>/p2p-circuit/p2p/<NODE_ID>
+//
+//Rendezvous on localhost, a point and two nodes finding each other through it:
+//cargo run -- --rendezvous-server
+//cargo run -- --http-port 8586 --p2p-port 8501 --external-addr /ip4/127.0.0.1/tcp/8501 --rendezvous /ip4/127.0.0.1/tcp/8500/p2p/<POINT_ID>
+//cargo run -- --http-port 8587 --p2p-port 8502 --external-addr /ip4/127.0.0.1/tcp/8502 --rendezvous /ip4/127.0.0.1/tcp/8500/p2p/<POINT_ID>
+mod ack;
 mod api;
 mod arguments;
 mod behaviour;
+mod blocks;
+mod causal;
+#[path = "../../common/codec.rs"]
+mod codec;
+mod dht;
+mod events;
+mod metrics;
 mod node;
 mod p2p;
+mod rendezvous;
+mod state;
+mod telemetry;
+#[path = "../../common/transfer.rs"]
+mod transfer;
 use arguments::*;
-use tokio::sync::oneshot;
 use std::error::Error;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
-    //demo().await?;
     init_using_args().await?;
     Ok(())
 }
-
-async fn demo() -> Result<(), Box<dyn Error>> {
-    use futures::prelude::*;
-    use libp2p::swarm::{Swarm, SwarmEvent};
-    use libp2p::{identity, ping, Multiaddr, PeerId};
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
-
-    let transport = libp2p::development_transport(local_key).await?;
-
-    // Create a ping network behaviour.
-    //
-    // For illustrative purposes, the ping protocol is configured to
-    // keep the connection alive, so a continuous sequence of pings
-    // can be observed.
-    let behaviour = ping::Behaviour::new(ping::Config::new().with_keep_alive(true));
-
-    let mut swarm = Swarm::new(transport, behaviour, local_peer_id);
-
-    // Tell the swarm to listen on all interfaces and a random, OS-assigned
-    // port.
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-
-    // Dial the peer identified by the multi-address given as the second
-    // command-line argument, if any.
-    if let Some(addr) = std::env::args().nth(1) {
-        let remote: Multiaddr = addr.parse()?;
-        swarm.dial_addr(remote)?;
-        println!("Dialed {}", addr)
-    }
-
-    loop {
-        match swarm.select_next_some().await {
-            SwarmEvent::NewListenAddr { address, .. } => println!("Listening on {:?}", address),
-            SwarmEvent::Behaviour(event) => println!("{:?}", event),
-            _ => {}
-        }
-    }
-}
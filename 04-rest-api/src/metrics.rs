@@ -0,0 +1,142 @@
+use libp2p::bandwidth::BandwidthSinks;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::sync::{Arc, Mutex};
+
+/// Prometheus metrics shared by the p2p server and the rpc server, served
+/// on `/metrics` of the http endpoint.
+pub struct Metrics {
+    registry: Registry,
+    pub connected_peers: IntGauge,
+    pub connection_events: IntCounterVec,
+    pub messages_published: IntCounterVec,
+    pub messages_received: IntCounterVec,
+    pub validation_failures: IntCounterVec,
+    pub messages_held: IntGauge,
+    pub bytes_received: IntCounter,
+    pub bytes_sent: IntCounter,
+    pub ping_rtt: Histogram,
+    pub rpc_requests: IntCounterVec,
+    pub rpc_latency: HistogramVec,
+    bandwidth: Mutex<Option<Arc<BandwidthSinks>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            connected_peers: IntGauge::new("p2p_connected_peers", "Number of connected peers")
+                .unwrap(),
+            connection_events: IntCounterVec::new(
+                Opts::new("p2p_connection_events_total", "Connection events by kind"),
+                &["event"],
+            )
+            .unwrap(),
+            messages_published: IntCounterVec::new(
+                Opts::new(
+                    "p2p_messages_published_total",
+                    "Messages published per topic",
+                ),
+                &["topic"],
+            )
+            .unwrap(),
+            messages_received: IntCounterVec::new(
+                Opts::new("p2p_messages_received_total", "Messages received per topic"),
+                &["topic"],
+            )
+            .unwrap(),
+            validation_failures: IntCounterVec::new(
+                Opts::new(
+                    "p2p_pubsub_validation_failures_total",
+                    "Received messages rejected as malformed, per topic",
+                ),
+                &["topic"],
+            )
+            .unwrap(),
+            messages_held: IntGauge::new(
+                "p2p_messages_held",
+                "Received messages waiting for causally earlier ones",
+            )
+            .unwrap(),
+            bytes_received: IntCounter::new("p2p_bytes_received_total", "Bytes read from peers")
+                .unwrap(),
+            bytes_sent: IntCounter::new("p2p_bytes_sent_total", "Bytes written to peers").unwrap(),
+            ping_rtt: Histogram::with_opts(
+                HistogramOpts::new("p2p_ping_rtt_seconds", "Ping round trip time")
+                    .buckets(exponential_buckets(0.0005, 2.0, 14).unwrap()),
+            )
+            .unwrap(),
+            rpc_requests: IntCounterVec::new(
+                Opts::new("rpc_requests_total", "JSON-RPC calls per method"),
+                &["method"],
+            )
+            .unwrap(),
+            rpc_latency: HistogramVec::new(
+                HistogramOpts::new("rpc_request_duration_seconds", "JSON-RPC call latency"),
+                &["method"],
+            )
+            .unwrap(),
+            bandwidth: Mutex::new(None),
+            registry,
+        };
+        metrics.register();
+        metrics
+    }
+
+    fn register(&self) {
+        let registry = &self.registry;
+        registry
+            .register(Box::new(self.connected_peers.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.connection_events.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.messages_published.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.messages_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.validation_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.messages_held.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.bytes_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.bytes_sent.clone()))
+            .unwrap();
+        registry.register(Box::new(self.ping_rtt.clone())).unwrap();
+        registry
+            .register(Box::new(self.rpc_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(self.rpc_latency.clone()))
+            .unwrap();
+    }
+
+    /// Byte counters are read from the transport when metrics are scraped.
+    pub fn set_bandwidth(&self, sinks: Arc<BandwidthSinks>) {
+        *self.bandwidth.lock().unwrap() = Some(sinks);
+    }
+
+    /// Returns all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        if let Some(sinks) = self.bandwidth.lock().unwrap().as_ref() {
+            self.bytes_received
+                .inc_by(sinks.total_inbound() - self.bytes_received.get());
+            self.bytes_sent
+                .inc_by(sinks.total_outbound() - self.bytes_sent.get());
+        }
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use data_encoding::BASE64URL_NOPAD;
+use hmac::{Hmac, Mac, NewMac};
+use jsonrpc_http_server::{
+    hyper::{header, HeaderMap},
+    jsonrpc_core::{
+        futures::{
+            future::{self, Either},
+            Future,
+        },
+        Call, Error, ErrorCode, Metadata, Middleware, Output, Response,
+    },
+};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use super::error::NodeResult;
+
+/// Shortest JWT secret accepted, HS256 wants at least as many bits as the
+/// hash has.
+const MIN_SECRET_LEN: usize = 32;
+
+/// Missing or invalid credentials.
+pub const UNAUTHORIZED: i64 = -32001;
+/// Valid credentials without access to the method's namespace.
+pub const FORBIDDEN: i64 = -32002;
+
+/// Groups of RPC methods, access is granted per namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    /// Messaging, files, shared state, the DHT and blocks.
+    P2p,
+    /// Inspecting and managing the node itself.
+    Admin,
+    /// Metrics and diagnostics.
+    Debug,
+}
+
+impl Namespace {
+    pub const ALL: [Namespace; 3] = [Namespace::P2p, Namespace::Admin, Namespace::Debug];
+
+    /// The namespace a method belongs to, by the prefix of its name. Only
+    /// `hello` is open to everyone, methods of no known namespace are
+    /// treated as admin ones.
+    pub fn of(method: &str) -> Option<Namespace> {
+        let prefix = method.split_once('_').map_or(method, |(prefix, _)| prefix);
+        match prefix {
+            "hello" => None,
+            "p2p" | "state" | "dht" | "blocks" => Some(Namespace::P2p),
+            "debug" => Some(Namespace::Debug),
+            _ => Some(Namespace::Admin),
+        }
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Namespace::P2p => write!(f, "p2p"),
+            Namespace::Admin => write!(f, "admin"),
+            Namespace::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+impl FromStr for Namespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "p2p" => Ok(Namespace::P2p),
+            "admin" => Ok(Namespace::Admin),
+            "debug" => Ok(Namespace::Debug),
+            _ => Err(format!(
+                "{}: unknown namespace, expected p2p, admin or debug",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the RPC server takes its credentials from. With neither set it
+/// is open to anyone who can reach it.
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// Bearer tokens, one per line, each optionally followed by the
+    /// namespaces it grants, e.g. `s3cr3t p2p,debug`. A token without
+    /// namespaces grants all of them.
+    pub token_file: Option<PathBuf>,
+    /// Secret bearer JWTs are signed with using HS256. A `namespaces`
+    /// claim limits what a JWT grants, without it it grants everything.
+    pub jwt_secret_file: Option<PathBuf>,
+}
+
+/// What the caller of a request may do, worked out from its credentials
+/// before any method runs.
+#[derive(Debug, Clone)]
+pub enum Access {
+    Granted(HashSet<Namespace>),
+    Denied(String),
+}
+
+impl Metadata for Access {}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Denied("no credentials".to_owned())
+    }
+}
+
+impl AsRef<Access> for Access {
+    fn as_ref(&self) -> &Access {
+        self
+    }
+}
+
+impl Access {
+    pub fn all() -> Self {
+        Access::Granted(Namespace::ALL.into_iter().collect())
+    }
+
+    /// Whether calls in `namespace` are let through, the error to answer
+    /// with if not.
+    pub fn allows(&self, namespace: Namespace) -> Result<(), Error> {
+        match self {
+            Access::Granted(namespaces) if namespaces.contains(&namespace) => Ok(()),
+            Access::Granted(_) => Err(Error {
+                code: ErrorCode::ServerError(FORBIDDEN),
+                message: format!("no access to the {} namespace", namespace),
+                data: None,
+            }),
+            Access::Denied(reason) => Err(Error {
+                code: ErrorCode::ServerError(UNAUTHORIZED),
+                message: format!("unauthorized: {}", reason),
+                data: None,
+            }),
+        }
+    }
+
+    pub fn allows_method(&self, method: &str) -> Result<(), Error> {
+        Namespace::of(method).map_or(Ok(()), |namespace| self.allows(namespace))
+    }
+}
+
+/// Checks the `Authorization: Bearer ...` header of RPC requests, or the
+/// `bearer.<token>` subprotocol of WebSocket ones, against the configured
+/// tokens and JWT secret.
+pub struct Authenticator {
+    tokens: Vec<(String, HashSet<Namespace>)>,
+    jwt_secret: Option<Vec<u8>>,
+}
+
+impl Authenticator {
+    pub fn load(config: &AuthConfig) -> NodeResult<Self> {
+        let tokens = match &config.token_file {
+            Some(path) => parse_tokens(&fs::read_to_string(path)?)
+                .map_err(|err| format!("{}: {}", path.display(), err))?,
+            None => Vec::new(),
+        };
+        let jwt_secret = match &config.jwt_secret_file {
+            Some(path) => {
+                let secret = fs::read_to_string(path)?.trim().as_bytes().to_vec();
+                if secret.len() < MIN_SECRET_LEN {
+                    let reason = format!("JWT secret needs at least {} bytes", MIN_SECRET_LEN);
+                    return Err(format!("{}: {}", path.display(), reason).into());
+                }
+                Some(secret)
+            }
+            None => None,
+        };
+        Ok(Authenticator { tokens, jwt_secret })
+    }
+
+    /// Whether any credentials are configured, without them every caller
+    /// gets full access.
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.jwt_secret.is_some()
+    }
+
+    pub fn access(&self, headers: &HeaderMap) -> Access {
+        if !self.enabled() {
+            return Access::all();
+        }
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match bearer {
+            Some(bearer) => self.check(bearer.trim()),
+            None => Access::default(),
+        }
+    }
+
+    /// Browsers cannot set headers on a WebSocket handshake, so there the
+    /// credentials come as a `bearer.<token>` subprotocol.
+    pub fn protocol_access(&self, protocols: &[String]) -> Access {
+        if !self.enabled() {
+            return Access::all();
+        }
+        let bearer = protocols
+            .iter()
+            .find_map(|protocol| protocol.strip_prefix("bearer."));
+        match bearer {
+            Some(bearer) => self.check(bearer),
+            None => Access::default(),
+        }
+    }
+
+    fn check(&self, bearer: &str) -> Access {
+        let token = self
+            .tokens
+            .iter()
+            .find(|(token, _)| constant_time_eq(token.as_bytes(), bearer.as_bytes()));
+        if let Some((_, namespaces)) = token {
+            return Access::Granted(namespaces.clone());
+        }
+        match &self.jwt_secret {
+            Some(secret) if bearer.matches('.').count() == 2 => match verify_jwt(secret, bearer) {
+                Ok(namespaces) => Access::Granted(namespaces),
+                Err(reason) => Access::Denied(reason),
+            },
+            _ => Access::Denied("unknown token".to_owned()),
+        }
+    }
+}
+
+fn parse_tokens(file: &str) -> Result<Vec<(String, HashSet<Namespace>)>, String> {
+    let mut tokens = Vec::new();
+    for line in file.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let token = fields.next().unwrap_or_default().to_owned();
+        let namespaces = match fields.next() {
+            Some(namespaces) => namespaces
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            None => Namespace::ALL.into_iter().collect(),
+        };
+        tokens.push((token, namespaces));
+    }
+    Ok(tokens)
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    /// Seconds since the unix epoch.
+    exp: Option<u64>,
+    nbf: Option<u64>,
+    namespaces: Option<Vec<String>>,
+}
+
+/// Checks the signature and time claims of a JWT, returns the namespaces
+/// it grants.
+fn verify_jwt(secret: &[u8], jwt: &str) -> Result<HashSet<Namespace>, String> {
+    let (signed, signature) = jwt.rsplit_once('.').ok_or("malformed JWT")?;
+    let (header, claims) = signed.split_once('.').ok_or("malformed JWT")?;
+    let header: JwtHeader = decode_part(header)?;
+    // Anything else, `none` in particular, would let the caller pick how
+    // the token is checked.
+    if header.alg != "HS256" {
+        return Err(format!("JWT algorithm {} is not HS256", header.alg));
+    }
+    let signature = BASE64URL_NOPAD
+        .decode(signature.as_bytes())
+        .map_err(|_| "malformed JWT signature")?;
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes keys of any length");
+    mac.update(signed.as_bytes());
+    mac.verify(&signature).map_err(|_| "bad JWT signature")?;
+
+    let claims: Claims = decode_part(claims)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if claims.exp.is_some_and(|exp| exp <= now) {
+        return Err("JWT expired".to_owned());
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now) {
+        return Err("JWT not valid yet".to_owned());
+    }
+    match claims.namespaces {
+        Some(namespaces) => namespaces.iter().map(|ns| ns.parse()).collect(),
+        None => Ok(Namespace::ALL.into_iter().collect()),
+    }
+}
+
+fn decode_part<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, String> {
+    let json = BASE64URL_NOPAD
+        .decode(part.as_bytes())
+        .map_err(|_| "malformed JWT")?;
+    serde_json::from_slice(&json).map_err(|err| format!("malformed JWT: {}", err))
+}
+
+/// Compares without returning early, so response times tell nothing about
+/// how much of a token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Rejects calls to methods outside the namespaces the caller has access
+/// to, before they run.
+pub struct Authorize;
+
+impl<M: Metadata + AsRef<Access>> Middleware<M> for Authorize {
+    type Future = future::Ready<Option<Response>>;
+    type CallFuture = future::Ready<Option<Output>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: M, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, M) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let access = meta.as_ref();
+        let denied = match &call {
+            Call::MethodCall(call) => access
+                .allows_method(&call.method)
+                .err()
+                .map(|error| Some(Output::from(Err(error), call.id.clone(), call.jsonrpc))),
+            // Nobody gets an answer to a notification, it is just dropped.
+            Call::Notification(notification) => access
+                .allows_method(&notification.method)
+                .err()
+                .map(|_| None),
+            Call::Invalid { .. } => None,
+        };
+        match denied {
+            Some(output) => Either::Left(future::ready(output)),
+            None => Either::Right(next(call, meta)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn jwt(alg: &str, claims: Value) -> String {
+        let header =
+            BASE64URL_NOPAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string().as_bytes());
+        let claims = BASE64URL_NOPAD.encode(claims.to_string().as_bytes());
+        let signed = format!("{}.{}", header, claims);
+        let mut mac = Hmac::<Sha256>::new_varkey(SECRET).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = BASE64URL_NOPAD.encode(&mac.finalize().into_bytes());
+        format!("{}.{}", signed, signature)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn jwt_grants_its_namespaces() {
+        let token = jwt("HS256", json!({ "exp": now() + 60, "namespaces": ["p2p"] }));
+        assert_eq!(
+            verify_jwt(SECRET, &token),
+            Ok(HashSet::from([Namespace::P2p]))
+        );
+        let token = jwt("HS256", json!({}));
+        assert_eq!(verify_jwt(SECRET, &token).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn jwt_outside_its_lifetime_is_refused() {
+        let expired = jwt("HS256", json!({ "exp": now() - 1 }));
+        assert_eq!(verify_jwt(SECRET, &expired), Err("JWT expired".to_owned()));
+        let early = jwt("HS256", json!({ "nbf": now() + 60 }));
+        assert_eq!(
+            verify_jwt(SECRET, &early),
+            Err("JWT not valid yet".to_owned())
+        );
+    }
+
+    #[test]
+    fn jwt_with_other_algorithm_or_key_is_refused() {
+        let token = jwt("HS256", json!({}));
+        let (unsigned, _) = token.rsplit_once('.').unwrap();
+        let header = BASE64URL_NOPAD.encode(br#"{"alg":"none"}"#);
+        let (_, claims) = unsigned.split_once('.').unwrap();
+        let none = format!("{}.{}.", header, claims);
+        assert!(verify_jwt(SECRET, &none).is_err());
+        assert!(verify_jwt(&SECRET[1..], &token).is_err());
+        assert!(verify_jwt(SECRET, &format!("{}.AAAA", unsigned)).is_err());
+    }
+
+    #[test]
+    fn tokens_without_namespaces_grant_all() {
+        let tokens = parse_tokens("# comment\nfull\nlimited p2p,debug\n").unwrap();
+        assert_eq!(
+            tokens[0],
+            ("full".to_owned(), Namespace::ALL.into_iter().collect())
+        );
+        assert_eq!(
+            tokens[1].1,
+            HashSet::from([Namespace::P2p, Namespace::Debug])
+        );
+        assert!(parse_tokens("bad nope").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+use super::{
+    error::NodeResult,
+    rpc_server::{add_methods, internal_error, RpcMetrics},
+};
+use crate::{metrics::Metrics, p2p::P2PClient};
+use jsonrpc_ipc_server::{
+    jsonrpc_core::{Error, MetaIoHandler, Metadata, Middleware, Params, Value},
+    SecurityAttributes, Server, ServerBuilder,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::Deserialize;
+use tracing::{info, instrument, warn};
+
+/// Serves JSON-RPC on a Unix socket for local tooling. The socket's file
+/// permissions decide who may connect, whoever can gets every method,
+/// admin ones included.
+pub struct IpcServer {
+    internal_server: Option<Server>,
+    path: PathBuf,
+    /// Permission bits of the socket file, e.g. `0o600` for its owner only.
+    mode: u16,
+    metrics: Arc<Metrics>,
+}
+
+impl IpcServer {
+    pub fn new(path: PathBuf, mode: u16, metrics: Arc<Metrics>) -> Self {
+        IpcServer {
+            internal_server: None,
+            path,
+            mode,
+            metrics,
+        }
+    }
+
+    #[instrument(name = "ipc_enable", skip(self, p2p), fields(path = %self.path.display()))]
+    pub async fn enable(&mut self, p2p: P2PClient) -> NodeResult<()> {
+        let path = self
+            .path
+            .to_str()
+            .ok_or_else(|| format!("{}: socket path is not UTF-8", self.path.display()))?;
+        let mut methods = MetaIoHandler::<()>::default();
+        add_methods(&mut methods, &p2p);
+        add_admin_methods(&mut methods, &p2p);
+        let rpc_metrics = RpcMetrics::new(self.metrics.clone(), &methods);
+        let mut io = MetaIoHandler::with_middleware(rpc_metrics);
+        io.extend_with(methods);
+        // The socket gets its mode while nobody else can reach it, and only
+        // then moves to its path, replacing one left behind by an earlier run.
+        let attributes = SecurityAttributes::empty().set_mode(self.mode)?;
+        let bind_dir = private_dir(&self.path)?;
+        let bound = bind_dir.join("socket");
+        let mut server = ServerBuilder::new(io)
+            .set_security_attributes(attributes)
+            .start(&bound.to_string_lossy());
+        if let Ok(started) = server {
+            server = match fs::rename(&bound, &self.path) {
+                Ok(()) => Ok(started),
+                Err(err) => {
+                    close(started).await;
+                    Err(err)
+                }
+            };
+        }
+        let _ = fs::remove_dir(&bind_dir);
+        let server = server?;
+        info!(path, mode = %format!("{:o}", self.mode), "ipc server listening");
+        self.internal_server = Some(server);
+        Ok(())
+    }
+
+    /// Closing removes the socket file.
+    pub async fn stop(&mut self) {
+        if let Some(server) = self.internal_server.take() {
+            close(server).await;
+            let _ = fs::remove_file(&self.path);
+            info!("ipc server shut down");
+        }
+    }
+}
+
+/// A new directory next to `path` that only we may enter.
+fn private_dir(path: &Path) -> std::io::Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Stops the server's own runtime, which must not happen on ours.
+async fn close(server: Server) {
+    if let Err(err) = tokio::task::spawn_blocking(|| server.close()).await {
+        warn!(%err, "ipc server did not close cleanly");
+    }
+}
+
+/// Methods never served over HTTP or WebSocket, reaching the socket is
+/// all the authentication they get.
+fn add_admin_methods<M: Metadata, S: Middleware<M>>(io: &mut MetaIoHandler<M, S>, p2p: &P2PClient) {
+    let client = p2p.clone();
+    io.add_method("admin_nodeInfo", move |_params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let info = p2p.node_info().await.map_err(internal_error)?;
+            serde_json::to_value(info).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("admin_peers", move |_params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let peers = p2p.peers().await.map_err(internal_error)?;
+            serde_json::to_value(peers).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("admin_addPeer", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: AddrParams = params.parse()?;
+            p2p.add_peer(params.addr()?).await.map_err(internal_error)?;
+            Ok(Value::Bool(true))
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("admin_removePeer", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: AddrParams = params.parse()?;
+            let removed = p2p
+                .remove_peer(params.peer()?)
+                .await
+                .map_err(internal_error)?;
+            Ok(Value::Bool(removed))
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("admin_listenOn", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: ListenParams = params.parse()?;
+            let addr = p2p
+                .listen_on(params.host, params.port)
+                .await
+                .map_err(internal_error)?;
+            Ok(Value::String(addr))
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("admin_removeListener", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: AddrParams = params.parse()?;
+            let removed = p2p
+                .remove_listener(params.addr()?)
+                .await
+                .map_err(internal_error)?;
+            Ok(Value::Bool(removed))
+        }
+    });
+    let client = p2p.clone();
+    // The node notices the p2p side is gone and stops the rest.
+    io.add_method("admin_shutdown", move |_params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            p2p.shutdown().await.map_err(internal_error)?;
+            Ok(Value::Bool(true))
+        }
+    });
+}
+
+/// `{"addr": "<multiaddr>"}`, for `admin_removePeer` ending in
+/// `/p2p/<peer id>`, for `admin_removeListener` as `admin_listenOn`
+/// answered.
+#[derive(Deserialize)]
+struct AddrParams {
+    addr: String,
+}
+
+impl AddrParams {
+    fn addr(&self) -> Result<Multiaddr, Error> {
+        self.addr
+            .parse()
+            .map_err(|err: libp2p::multiaddr::Error| Error::invalid_params(err.to_string()))
+    }
+
+    fn peer(&self) -> Result<PeerId, Error> {
+        match self.addr()?.pop() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+                .map_err(|_| Error::invalid_params("invalid peer id in addr")),
+            _ => Err(Error::invalid_params(
+                "expected an addr ending in /p2p/<peer id>",
+            )),
+        }
+    }
+}
+
+/// `{"host": "0.0.0.0", "port": 8501}`, listened on over TCP.
+#[derive(Deserialize)]
+struct ListenParams {
+    host: String,
+    port: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(addr: &str) -> AddrParams {
+        AddrParams {
+            addr: addr.to_owned(),
+        }
+    }
+
+    #[test]
+    fn addr_must_parse() {
+        assert!(params("/ip4/127.0.0.1/tcp/8500").addr().is_ok());
+        assert!(params("127.0.0.1:8500").addr().is_err());
+    }
+
+    #[test]
+    fn peer_comes_from_the_p2p_suffix() {
+        let peer = PeerId::random();
+        let addr = format!("/ip4/127.0.0.1/tcp/8500/p2p/{}", peer);
+        assert_eq!(params(&addr).peer().unwrap(), peer);
+        assert_eq!(params(&format!("/p2p/{}", peer)).peer().unwrap(), peer);
+        assert!(params("/ip4/127.0.0.1/tcp/8500").peer().is_err());
+        assert!(params("not an addr").peer().is_err());
+    }
+}
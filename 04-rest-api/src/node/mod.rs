@@ -1,24 +1,38 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
-use crate::p2p::{P2PConfig, P2PServer};
+use crate::{
+    metrics::Metrics,
+    p2p::{P2PConfig, P2PServer},
+};
+mod auth;
 mod error;
+mod ipc_server;
 mod rpc_server;
+mod ws_server;
+pub use auth::AuthConfig;
+use auth::Authenticator;
 use error::*;
+use ipc_server::IpcServer;
+pub use rpc_server::HttpOptions;
 use rpc_server::*;
-use tokio::sync::{oneshot, Mutex};
-
-enum NodeSignal {
-    StopHttp,
-}
-
-pub enum TransportType {
-    Tcp,
-    Ws,
-}
+use tracing::{info, instrument};
+use ws_server::WsServer;
 
 pub struct NodeConfig {
     pub http_host: String,
     pub http_port: u16,
+    /// Credentials RPC callers have to present.
+    pub rpc_auth: AuthConfig,
+    /// CORS origins, allowed hosts and limits of the RPC server.
+    pub http_options: HttpOptions,
+    /// Port of the WebSocket RPC server on `http_host`, off without one.
+    pub ws_port: Option<u16>,
+    /// Unix socket serving every RPC method, admin ones included, off
+    /// without one.
+    pub ipc_path: Option<PathBuf>,
+    /// Permission bits of the IPC socket, which are all that keeps others
+    /// out of it.
+    pub ipc_mode: u16,
     pub p2p: P2PConfig,
 }
 
@@ -27,6 +41,11 @@ impl NodeConfig {
         NodeConfig {
             http_host,
             http_port,
+            rpc_auth: AuthConfig::default(),
+            http_options: HttpOptions::default(),
+            ws_port: None,
+            ipc_path: None,
+            ipc_mode: 0o600,
             p2p: p2p_config,
         }
     }
@@ -43,23 +62,39 @@ pub struct Node {
     config: NodeConfig,
     server: P2PServer,
     http: HttpServer,
+    ws: Option<WsServer>,
+    ipc: Option<IpcServer>,
     state: NodeState,
 }
 
 impl Node {
     pub fn new(config: NodeConfig) -> NodeResult<Self> {
+        let metrics = Arc::new(Metrics::new());
+        let auth = Arc::new(Authenticator::load(&config.rpc_auth)?);
         let http = HttpServer::new(
             config.http_host.clone(),
             config.http_port,
+            metrics.clone(),
+            auth.clone(),
         );
+        let ws = config
+            .ws_port
+            .map(|port| WsServer::new(config.http_host.clone(), port, metrics.clone(), auth));
+        let ipc = config
+            .ipc_path
+            .clone()
+            .map(|path| IpcServer::new(path, config.ipc_mode, metrics.clone()));
         Ok(Node {
             http,
-            server: P2PServer::new(config.p2p.clone())?,
+            ws,
+            ipc,
+            server: P2PServer::new(config.p2p.clone(), metrics)?,
             config,
             state: NodeState::Init,
         })
     }
 
+    #[instrument(name = "node_start", skip(self))]
     pub async fn start(&mut self) -> NodeResult<()> {
         match self.state {
             NodeState::Running => Err(Box::new(NodeError::NodeRunning)),
@@ -72,19 +107,26 @@ impl Node {
         }
     }
 
+    #[instrument(name = "node_stop", skip(self), fields(state = ?self.state))]
     pub async fn stop(&mut self) {
         match self.state {
             NodeState::Init => {}
             NodeState::Running => {
                 self.stop_rpc().await;
+                self.server.stop().await;
             }
             NodeState::Closed => {}
         }
         self.state = NodeState::Closed;
     }
 
+    /// Resolves when the p2p side stops by itself, e.g. at the end of stdin.
+    pub async fn closed(&mut self) {
+        self.server.closed().await;
+    }
+
     async fn open_end_points(&mut self) -> NodeResult<()> {
-        println!("p2p starting");
+        info!("p2p starting");
         self.server.start().await?;
         self.start_rpc().await?;
         Ok(())
@@ -94,11 +136,26 @@ impl Node {
         self.http
             .set_listen_addr(self.config.http_host.clone(), self.config.http_port)
             .await?;
-        self.http.enable().await?;
+        self.http.set_options(self.config.http_options.clone());
+        let p2p = self.server.client().ok_or(NodeError::NodeStopped)?;
+        if let Some(ws) = &mut self.ws {
+            ws.set_options(self.config.http_options.clone());
+            ws.enable(p2p.clone()).await?;
+        }
+        if let Some(ipc) = &mut self.ipc {
+            ipc.enable(p2p.clone()).await?;
+        }
+        self.http.enable(p2p).await?;
         Ok(())
     }
 
     async fn stop_rpc(&mut self) {
         self.http.stop().await;
+        if let Some(ws) = &mut self.ws {
+            ws.stop().await;
+        }
+        if let Some(ipc) = &mut self.ipc {
+            ipc.stop().await;
+        }
     }
 }
@@ -1,24 +1,102 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use super::error::NodeResult;
+use super::{
+    auth::{Access, Authenticator, Authorize, Namespace, FORBIDDEN},
+    error::NodeResult,
+};
+use crate::{
+    blocks::{Cid, MAX_BLOCK_SIZE},
+    metrics::Metrics,
+    p2p::{FileTarget, P2PClient},
+};
+use data_encoding::BASE64;
 use jsonrpc_http_server::ServerBuilder;
 use jsonrpc_http_server::{
-    jsonrpc_core::{IoHandler, Params, Value},
-    Server,
+    hyper::{self, body::HttpBody, Body, Method, StatusCode},
+    jsonrpc_core::{
+        futures::{future::Either, Future, FutureExt},
+        middleware::{NoopCallFuture, NoopFuture},
+        Call, Error, MetaIoHandler, Metadata, Middleware, Output, Params, Value,
+    },
+    AccessControlAllowOrigin, DomainsValidation, Host, RequestMiddlewareAction, Server,
 };
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+/// Largest JSON-RPC request body accepted by default.
+const DEFAULT_MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+/// Who may talk to the HTTP server from where, and how much of it there is.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// Origins browsers may make calls from, e.g. `https://dash.example.com`,
+    /// `*` for any. Without any no CORS headers are sent.
+    pub cors_origins: Vec<String>,
+    /// `Host` headers accepted besides the listen address itself, e.g.
+    /// `node.example.com:8585` or `*.example.com:*`, `*` for any.
+    pub allowed_hosts: Vec<String>,
+    /// Largest JSON-RPC request body accepted, in bytes.
+    pub max_body_size: usize,
+    pub threads: NonZeroUsize,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            cors_origins: Vec::new(),
+            allowed_hosts: Vec::new(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            threads: NonZeroUsize::new(1).expect("1 is not zero"),
+        }
+    }
+}
+
+impl HttpOptions {
+    fn cors(&self) -> DomainsValidation<AccessControlAllowOrigin> {
+        if self.cors_origins.is_empty() {
+            DomainsValidation::Disabled
+        } else {
+            DomainsValidation::AllowOnly(self.cors_origins.iter().map(Into::into).collect())
+        }
+    }
+
+    /// The listen address is always allowed, so DNS rebinding cannot reach
+    /// the node through a browser unless more hosts are let in.
+    fn allowed_hosts(&self) -> DomainsValidation<Host> {
+        if self.allowed_hosts.iter().any(|host| host == "*") {
+            DomainsValidation::Disabled
+        } else {
+            DomainsValidation::AllowOnly(self.allowed_hosts.iter().map(Into::into).collect())
+        }
+    }
+}
 
 pub struct HttpServer {
     internal_server: Option<Server>,
     host: String,
     port: u16,
+    options: HttpOptions,
+    metrics: Arc<Metrics>,
+    auth: Arc<Authenticator>,
 }
 
 impl HttpServer {
-    pub fn new(host: String, port: u16) -> Self {
+    pub fn new(host: String, port: u16, metrics: Arc<Metrics>, auth: Arc<Authenticator>) -> Self {
         HttpServer {
             internal_server: None,
             host,
             port,
+            options: HttpOptions::default(),
+            metrics,
+            auth,
         }
     }
 
@@ -28,17 +106,45 @@ impl HttpServer {
         Ok(())
     }
 
-    pub async fn enable(&mut self) -> NodeResult<()> {
-        let socker_addr: SocketAddr =
-            String::from(format!("{}:{}", self.host, self.port)).parse()?;
-        let mut io = IoHandler::default();
-        io.add_method("hello", |_params: Params| async {
-            Ok(Value::String("hello".to_owned()))
-        });
-        let server = ServerBuilder::new(io)
-            .threads(1)
-            .start_http(&socker_addr)
-            .unwrap();
+    pub fn set_options(&mut self, options: HttpOptions) {
+        self.options = options;
+    }
+
+    #[instrument(name = "http_enable", skip(self, p2p), fields(host = %self.host, port = self.port))]
+    pub async fn enable(&mut self, p2p: P2PClient) -> NodeResult<()> {
+        let socker_addr: SocketAddr = format!("{}:{}", self.host, self.port).parse()?;
+        if !self.auth.enabled() && !socker_addr.ip().is_loopback() {
+            warn!(address = %socker_addr, "rpc server reachable from other hosts without auth");
+        }
+        let mut methods = MetaIoHandler::default();
+        add_methods(&mut methods, &p2p);
+        let rpc_metrics = RpcMetrics::new(self.metrics.clone(), &methods);
+        let mut io = MetaIoHandler::with_middleware((rpc_metrics, Authorize));
+        io.extend_with(methods);
+        let metrics = self.metrics.clone();
+        let auth = self.auth.clone();
+        let rest_auth = self.auth.clone();
+        let access = move |request: &hyper::Request<Body>| auth.access(request.headers());
+        let server = ServerBuilder::with_meta_extractor(io, access)
+            .threads(self.options.threads.get())
+            .cors(self.options.cors())
+            .allowed_hosts(self.options.allowed_hosts())
+            .max_request_body_size(self.options.max_body_size)
+            .request_middleware(move |request: hyper::Request<Body>| {
+                let path = request.uri().path();
+                let access = rest_auth.access(request.headers());
+                if path == "/metrics" {
+                    denied_response(&access, Namespace::Debug)
+                        .unwrap_or_else(|| metrics_response(&metrics))
+                } else if path == "/blocks" || path.starts_with("/blocks/") {
+                    denied_response(&access, Namespace::P2p)
+                        .unwrap_or_else(|| blocks_response(request, p2p.clone()))
+                } else {
+                    request.into()
+                }
+            })
+            .start_http(&socker_addr)?;
+        info!(address = %socker_addr, "rpc server listening");
         self.internal_server = Some(server);
         Ok(())
     }
@@ -47,7 +153,437 @@ impl HttpServer {
         self.host = "".to_string();
         self.port = 0;
         let internal_server = self.internal_server.take();
-        tokio::task::spawn_blocking(|| drop(internal_server));
-        println!("Successfully shutdown!");
+        if let Err(err) = tokio::task::spawn_blocking(|| drop(internal_server)).await {
+            warn!(%err, "rpc server did not close cleanly");
+        }
+        info!("rpc server shut down");
+    }
+}
+
+/// Registers the methods every transport serves.
+pub fn add_methods<M: Metadata, S: Middleware<M>>(io: &mut MetaIoHandler<M, S>, p2p: &P2PClient) {
+    io.add_method("hello", |_params: Params| async {
+        Ok(Value::String("hello".to_owned()))
+    });
+    let client = p2p.clone();
+    io.add_method("node_info", move |_params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let info = p2p.node_info().await.map_err(internal_error)?;
+            serde_json::to_value(info).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("p2p_sendFile", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: SendFileParams = params.parse()?;
+            let target = params.target()?;
+            let transfers = p2p
+                .send_file(target, params.path)
+                .await
+                .map_err(internal_error)?;
+            serde_json::to_value(transfers).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("p2p_transfers", move |_params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let transfers = p2p.transfers().await.map_err(internal_error)?;
+            serde_json::to_value(transfers).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("p2p_messageAcks", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: MessageParams = match params {
+                Params::None => MessageParams::default(),
+                params => params.parse()?,
+            };
+            let acks = p2p
+                .message_acks(params.message_id)
+                .await
+                .map_err(internal_error)?;
+            serde_json::to_value(acks).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("p2p_markRead", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: MessageParams = params.parse()?;
+            let message_id = params
+                .message_id
+                .ok_or_else(|| Error::invalid_params("expected messageId"))?;
+            p2p.mark_read(message_id).await.map_err(internal_error)?;
+            Ok(Value::Bool(true))
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("state_get", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: StateParams = match params {
+                Params::None => StateParams::default(),
+                params => params.parse()?,
+            };
+            p2p.state_get(params.key).await.map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("state_set", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: StateParams = params.parse()?;
+            let key = params
+                .key
+                .ok_or_else(|| Error::invalid_params("expected key"))?;
+            let entry = p2p
+                .state_set(key, params.value)
+                .await
+                .map_err(internal_error)?;
+            serde_json::to_value(entry).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("state_watch", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: WatchParams = match params {
+                Params::None => WatchParams::default(),
+                params => params.parse()?,
+            };
+            let timeout = params
+                .timeout
+                .unwrap_or(WATCH_TIMEOUT)
+                .min(MAX_WATCH_TIMEOUT);
+            let changes = p2p
+                .state_watch(params.since, params.prefix, Duration::from_secs(timeout))
+                .await
+                .map_err(internal_error)?;
+            serde_json::to_value(changes).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("dht_put", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: DhtParams = params.parse()?;
+            let value = serde_json::to_vec(&params.value).map_err(internal_error)?;
+            let outcome = p2p
+                .dht_put(params.key, value)
+                .await
+                .map_err(internal_error)?;
+            serde_json::to_value(outcome).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("dht_get", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: DhtParams = params.parse()?;
+            let value = p2p.dht_get(params.key).await.map_err(internal_error)?;
+            // Values put by other tools need not be JSON.
+            Ok(value
+                .map(|value| {
+                    serde_json::from_slice(&value).unwrap_or_else(|_| {
+                        Value::String(String::from_utf8_lossy(&value).into_owned())
+                    })
+                })
+                .unwrap_or_default())
+        }
+    });
+    let client = p2p.clone();
+    // Removes our copy only, replicas on other peers expire with their TTL.
+    io.add_method("dht_remove", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: DhtParams = params.parse()?;
+            let outcome = p2p.dht_remove(params.key).await.map_err(internal_error)?;
+            serde_json::to_value(outcome).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("blocks_put", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: BlockParams = params.parse()?;
+            let data = params
+                .data
+                .ok_or_else(|| Error::invalid_params("expected data"))?;
+            let data = BASE64
+                .decode(data.as_bytes())
+                .map_err(|err| Error::invalid_params(err.to_string()))?;
+            let size = data.len();
+            let cid = p2p.blocks_put(data).await.map_err(internal_error)?;
+            serde_json::to_value(BlockInfo::new(&cid, size)).map_err(internal_error)
+        }
+    });
+    let client = p2p.clone();
+    io.add_method("blocks_get", move |params: Params| {
+        let mut p2p = client.clone();
+        async move {
+            let params: BlockParams = params.parse()?;
+            let cid: Cid = params
+                .cid
+                .ok_or_else(|| Error::invalid_params("expected cid"))?
+                .parse()
+                .map_err(Error::invalid_params)?;
+            let data = p2p.blocks_get(cid.clone()).await.map_err(internal_error)?;
+            let info = BlockInfo {
+                data: Some(BASE64.encode(&data)),
+                ..BlockInfo::new(&cid, data.len())
+            };
+            serde_json::to_value(info).map_err(internal_error)
+        }
+    });
+}
+
+/// `{"peer": "<peer id>", "path": "<file>"}` or `{"topic": "<topic>", "path": "<file>"}`,
+/// the path inside the outbox directory.
+#[derive(Deserialize)]
+struct SendFileParams {
+    peer: Option<String>,
+    topic: Option<String>,
+    path: PathBuf,
+}
+
+impl SendFileParams {
+    fn target(&self) -> Result<FileTarget, Error> {
+        match (&self.peer, &self.topic) {
+            (Some(peer), None) => peer
+                .parse::<PeerId>()
+                .map(FileTarget::Peer)
+                .map_err(|err| Error::invalid_params(err.to_string())),
+            (None, Some(topic)) => Ok(FileTarget::Topic(topic.clone())),
+            _ => Err(Error::invalid_params("expected either peer or topic")),
+        }
     }
 }
+
+/// `{"messageId": "<id>"}`, the id the publisher logged the message with.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageParams {
+    message_id: Option<String>,
+}
+
+/// `{"key": "<key>", "value": <any JSON>}`, `state_get` without a key
+/// returns the whole map.
+#[derive(Default, Deserialize)]
+struct StateParams {
+    key: Option<String>,
+    #[serde(default)]
+    value: Value,
+}
+
+/// `{"key": "<key>", "value": <any JSON>}`, only `dht_put` takes a value.
+#[derive(Deserialize)]
+struct DhtParams {
+    key: String,
+    #[serde(default)]
+    value: Value,
+}
+
+/// `{"data": "<base64>"}` for `blocks_put`, `{"cid": "<cid>"}` for
+/// `blocks_get`.
+#[derive(Deserialize)]
+struct BlockParams {
+    cid: Option<String>,
+    data: Option<String>,
+}
+
+/// What `blocks_put` and `POST /blocks` answer with, `blocks_get` adds the
+/// block in base64.
+#[derive(Serialize)]
+struct BlockInfo {
+    cid: String,
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+}
+
+impl BlockInfo {
+    fn new(cid: &Cid, size: usize) -> Self {
+        BlockInfo {
+            cid: cid.to_string(),
+            size,
+            data: None,
+        }
+    }
+}
+
+/// Seconds `state_watch` waits for a change by default, and at most.
+const WATCH_TIMEOUT: u64 = 30;
+const MAX_WATCH_TIMEOUT: u64 = 300;
+
+/// `{"prefix": "<key prefix>", "since": <version>, "timeout": <seconds>}`,
+/// all optional. Pass the returned version as `since` to watch on.
+#[derive(Default, Deserialize)]
+struct WatchParams {
+    #[serde(default)]
+    prefix: String,
+    since: Option<u64>,
+    timeout: Option<u64>,
+}
+
+/// The REST endpoints check the same credentials as JSON-RPC calls, and
+/// answer with 401 or 403 when they are not enough.
+fn denied_response(access: &Access, namespace: Namespace) -> Option<RequestMiddlewareAction> {
+    let err = access.allows(namespace).err()?;
+    let response = if err.code.code() == FORBIDDEN {
+        text_response(StatusCode::FORBIDDEN, err.message)
+    } else {
+        let mut response = text_response(StatusCode::UNAUTHORIZED, err.message);
+        response.headers_mut().insert(
+            hyper::header::WWW_AUTHENTICATE,
+            hyper::header::HeaderValue::from_static("Bearer"),
+        );
+        response
+    };
+    Some(response.into())
+}
+
+fn metrics_response(metrics: &Metrics) -> RequestMiddlewareAction {
+    hyper::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+        .body(Body::from(metrics.encode()))
+        .unwrap()
+        .into()
+}
+
+/// REST access to blocks: `POST /blocks` with the raw block as body
+/// answers with its CID, `GET /blocks/<cid>` with the raw block.
+fn blocks_response(request: hyper::Request<Body>, p2p: P2PClient) -> RequestMiddlewareAction {
+    RequestMiddlewareAction::Respond {
+        should_validate_hosts: true,
+        response: Box::pin(async move { Ok(serve_blocks(request, p2p).await) }),
+    }
+}
+
+async fn serve_blocks(request: hyper::Request<Body>, mut p2p: P2PClient) -> hyper::Response<Body> {
+    let path = request.uri().path().to_owned();
+    match (request.method().clone(), path.strip_prefix("/blocks/")) {
+        (Method::POST, None) => {
+            let data = match read_block(request.into_body()).await {
+                Ok(data) => data,
+                Err(response) => return response,
+            };
+            let size = data.len();
+            match p2p.blocks_put(data).await {
+                Ok(cid) => {
+                    let info = serde_json::to_vec(&BlockInfo::new(&cid, size))
+                        .expect("block info serializes to JSON");
+                    hyper::Response::builder()
+                        .status(StatusCode::CREATED)
+                        .header(hyper::header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(info))
+                        .unwrap()
+                }
+                Err(err) => text_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+            }
+        }
+        (Method::GET, Some(cid)) => match cid.parse::<Cid>() {
+            Ok(cid) => match p2p.blocks_get(cid).await {
+                Ok(data) => hyper::Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+                    .body(Body::from(data))
+                    .unwrap(),
+                Err(err) => text_response(StatusCode::NOT_FOUND, err),
+            },
+            Err(err) => text_response(StatusCode::BAD_REQUEST, err),
+        },
+        _ => text_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "use POST /blocks or GET /blocks/<cid>",
+        ),
+    }
+}
+
+/// Reads a block from a request body, without buffering more than a block
+/// may hold.
+async fn read_block(mut body: Body) -> Result<Vec<u8>, hyper::Response<Body>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| text_response(StatusCode::BAD_REQUEST, err))?;
+        if data.len() + chunk.len() > MAX_BLOCK_SIZE {
+            let reason = format!("blocks hold at most {} bytes", MAX_BLOCK_SIZE);
+            return Err(text_response(StatusCode::PAYLOAD_TOO_LARGE, reason));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+fn text_response<E: std::fmt::Display>(status: StatusCode, message: E) -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+/// Counts and times every JSON-RPC call by method name and runs it inside
+/// an `rpc` span.
+pub struct RpcMetrics {
+    metrics: Arc<Metrics>,
+    /// Names calls are labelled with, any other is `unknown` so callers
+    /// cannot grow the label sets.
+    methods: Arc<HashSet<String>>,
+}
+
+impl RpcMetrics {
+    pub fn new<M: Metadata, S: Middleware<M>>(
+        metrics: Arc<Metrics>,
+        methods: &MetaIoHandler<M, S>,
+    ) -> Self {
+        let methods = methods.iter().map(|(name, _)| name.clone()).collect();
+        RpcMetrics {
+            metrics,
+            methods: Arc::new(methods),
+        }
+    }
+
+    fn label(&self, method: &str) -> String {
+        if self.methods.contains(method) {
+            method.to_owned()
+        } else {
+            "unknown".to_owned()
+        }
+    }
+}
+
+impl<M: Metadata> Middleware<M> for RpcMetrics {
+    type Future = NoopFuture;
+    type CallFuture = NoopCallFuture;
+
+    fn on_call<F, X>(&self, call: Call, meta: M, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, M) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let method = match &call {
+            Call::MethodCall(call) => self.label(&call.method),
+            Call::Notification(notification) => self.label(&notification.method),
+            Call::Invalid { .. } => "invalid".to_owned(),
+        };
+        let metrics = self.metrics.clone();
+        metrics.rpc_requests.with_label_values(&[&method]).inc();
+        let start = Instant::now();
+        let span = info_span!("rpc", method = %method);
+        Either::Left(Box::pin(next(call, meta).instrument(span).map(move |output| {
+            metrics
+                .rpc_latency
+                .with_label_values(&[&method])
+                .observe(start.elapsed().as_secs_f64());
+            output
+        })))
+    }
+}
+
+pub(super) fn internal_error<E: std::fmt::Display>(err: E) -> Error {
+    let mut error = Error::internal_error();
+    error.message = err.to_string();
+    error
+}
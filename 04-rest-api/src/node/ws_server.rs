@@ -0,0 +1,389 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{
+    auth::{Access, Authenticator, Authorize},
+    error::NodeResult,
+    rpc_server::{add_methods, HttpOptions, RpcMetrics},
+};
+use crate::{events::NodeEvent, metrics::Metrics, p2p::P2PClient};
+use jsonrpc_pubsub::{PubSubHandler, PubSubMetadata, Session, Sink, Subscriber, SubscriptionId};
+use jsonrpc_ws_server::{
+    jsonrpc_core::{futures::future, Error, MetaIoHandler, Metadata, Params, Value},
+    DomainsValidation, Host, Origin, RequestContext, Server, ServerBuilder,
+};
+use serde::Deserialize;
+use tokio::{
+    runtime::Handle,
+    sync::broadcast::{error::RecvError, Receiver},
+    task::JoinHandle,
+};
+use tracing::{info, instrument, warn};
+
+/// What a WebSocket connection may call, and the session its
+/// subscriptions are delivered on.
+#[derive(Clone, Default)]
+pub struct WsMeta {
+    access: Access,
+    /// Numbers connections, subscriptions belong to the one that made them.
+    connection: u64,
+    session: Option<Arc<Session>>,
+}
+
+impl Metadata for WsMeta {}
+
+impl PubSubMetadata for WsMeta {
+    fn session(&self) -> Option<Arc<Session>> {
+        self.session.clone()
+    }
+}
+
+impl AsRef<Access> for WsMeta {
+    fn as_ref(&self) -> &Access {
+        &self.access
+    }
+}
+
+/// Serves the HTTP methods over WebSocket, along with `p2p_subscribe` and
+/// `p2p_unsubscribe` for pushing node events to the caller.
+pub struct WsServer {
+    internal_server: Option<Server>,
+    host: String,
+    port: u16,
+    options: HttpOptions,
+    metrics: Arc<Metrics>,
+    auth: Arc<Authenticator>,
+}
+
+impl WsServer {
+    pub fn new(host: String, port: u16, metrics: Arc<Metrics>, auth: Arc<Authenticator>) -> Self {
+        WsServer {
+            internal_server: None,
+            host,
+            port,
+            options: HttpOptions::default(),
+            metrics,
+            auth,
+        }
+    }
+
+    /// Origins, hosts and the largest message are limited the same way as
+    /// on the HTTP server.
+    pub fn set_options(&mut self, options: HttpOptions) {
+        self.options = options;
+    }
+
+    #[instrument(name = "ws_enable", skip(self, p2p), fields(host = %self.host, port = self.port))]
+    pub async fn enable(&mut self, p2p: P2PClient) -> NodeResult<()> {
+        let socket_addr: SocketAddr = format!("{}:{}", self.host, self.port).parse()?;
+        if !self.auth.enabled() && !socket_addr.ip().is_loopback() {
+            warn!(address = %socket_addr, "ws server reachable from other hosts without auth");
+        }
+        let mut methods = PubSubHandler::new(MetaIoHandler::default());
+        add_methods(&mut methods, &p2p);
+        add_subscriptions(&mut methods, p2p);
+        let methods: MetaIoHandler<WsMeta> = methods.into();
+        let rpc_metrics = RpcMetrics::new(self.metrics.clone(), &methods);
+        let mut io = MetaIoHandler::with_middleware((rpc_metrics, Authorize));
+        io.extend_with(methods);
+        let auth = self.auth.clone();
+        let next_connection = AtomicU64::new(1);
+        let meta = move |context: &RequestContext| WsMeta {
+            access: auth.protocol_access(&context.protocols),
+            connection: next_connection.fetch_add(1, Ordering::Relaxed),
+            session: Some(Arc::new(Session::new(context.sender()))),
+        };
+        let server = ServerBuilder::with_meta_extractor(io, meta)
+            .allowed_origins(self.origins())
+            .allowed_hosts(self.allowed_hosts())
+            .max_payload(self.options.max_body_size)
+            .start(&socket_addr)?;
+        info!(address = %socket_addr, "ws server listening");
+        self.internal_server = Some(server);
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) {
+        if let Some(server) = self.internal_server.take() {
+            if let Err(err) = tokio::task::spawn_blocking(|| server.close()).await {
+                warn!(%err, "ws server did not close cleanly");
+            }
+            info!("ws server shut down");
+        }
+    }
+
+    fn origins(&self) -> DomainsValidation<Origin> {
+        let origins = &self.options.cors_origins;
+        if origins.iter().any(|origin| origin == "*") {
+            DomainsValidation::Disabled
+        } else {
+            DomainsValidation::AllowOnly(origins.iter().map(Into::into).collect())
+        }
+    }
+
+    fn allowed_hosts(&self) -> DomainsValidation<Host> {
+        let hosts = &self.options.allowed_hosts;
+        if hosts.iter().any(|host| host == "*") {
+            DomainsValidation::Disabled
+        } else {
+            DomainsValidation::AllowOnly(hosts.iter().map(Into::into).collect())
+        }
+    }
+}
+
+/// The events a subscription asks for, the first of `p2p_subscribe`'s
+/// params: `["messages", {"topic": ...}]`, `["peers"]` or
+/// `["state", {"prefix": ...}]`.
+#[derive(Debug)]
+enum Topic {
+    /// Messages on one floodsub topic, or on all of them.
+    Messages(Option<String>),
+    Peers,
+    /// Changes to shared state keys starting with the prefix.
+    State(String),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Filter {
+    topic: Option<String>,
+    prefix: Option<String>,
+}
+
+impl Topic {
+    fn parse(params: Params) -> Result<Self, Error> {
+        let mut params: Vec<Value> = params.parse()?;
+        let filter: Filter = match params.len() {
+            1 => Filter::default(),
+            2 => serde_json::from_value(params.pop().unwrap_or_default())
+                .map_err(|err| Error::invalid_params(err.to_string()))?,
+            _ => return Err(Error::invalid_params("expected [kind] or [kind, filter]")),
+        };
+        match params[0].as_str() {
+            Some("messages") => Ok(Topic::Messages(filter.topic)),
+            Some("peers") => Ok(Topic::Peers),
+            Some("state") => Ok(Topic::State(filter.prefix.unwrap_or_default())),
+            _ => Err(Error::invalid_params(
+                "unknown subscription, expected messages, peers or state",
+            )),
+        }
+    }
+
+    fn matches(&self, event: &NodeEvent) -> bool {
+        match (self, event) {
+            (Topic::Messages(topic), NodeEvent::Message(message)) => {
+                topic.as_ref().is_none_or(|topic| *topic == message.topic)
+            }
+            (Topic::Peers, NodeEvent::PeerConnected { .. })
+            | (Topic::Peers, NodeEvent::PeerDisconnected { .. }) => true,
+            (Topic::State(prefix), NodeEvent::StateChanged(change)) => {
+                change.key.starts_with(prefix.as_str())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Tasks forwarding events to subscribers, by connection.
+type Forwarders = Arc<Mutex<HashMap<u64, HashMap<SubscriptionId, JoinHandle<()>>>>>;
+
+fn add_subscriptions(io: &mut PubSubHandler<WsMeta>, p2p: P2PClient) {
+    // Subscriptions are set up on the WebSocket server's threads, the
+    // tasks forwarding events are spawned onto the node's runtime.
+    let runtime = Handle::current();
+    let next_id = AtomicU64::new(1);
+    let forwarders: Forwarders = Default::default();
+    let unsubscribe_forwarders = forwarders.clone();
+    let subscribe = move |params: Params, meta: WsMeta, subscriber: Subscriber| {
+        let topic = match Topic::parse(params) {
+            Ok(topic) => topic,
+            Err(err) => {
+                let _ = subscriber.reject(err);
+                return;
+            }
+        };
+        let id = SubscriptionId::Number(next_id.fetch_add(1, Ordering::Relaxed));
+        let Ok(sink) = subscriber.assign_id(id.clone()) else {
+            return;
+        };
+        let receiver = p2p.subscribe();
+        let forwarder = runtime.spawn(forward(receiver, topic, id.clone(), sink));
+        let mut all = forwarders.lock().unwrap();
+        if !all.contains_key(&meta.connection) {
+            // Stop forwarding once the connection is gone.
+            if let Some(session) = &meta.session {
+                let forwarders = forwarders.clone();
+                let connection = meta.connection;
+                session.on_drop(move || {
+                    let removed = forwarders.lock().unwrap().remove(&connection);
+                    for forwarder in removed.into_iter().flat_map(HashMap::into_values) {
+                        forwarder.abort();
+                    }
+                });
+            }
+        }
+        all.entry(meta.connection)
+            .or_default()
+            .insert(id, forwarder);
+    };
+    // Without metadata the session is being dropped, which its `on_drop`
+    // takes care of. Otherwise only the caller's own subscriptions can go.
+    let unsubscribe = move |id: SubscriptionId, meta: Option<WsMeta>| {
+        let removed = meta.and_then(|meta| {
+            let mut all = unsubscribe_forwarders.lock().unwrap();
+            all.get_mut(&meta.connection)?.remove(&id)
+        });
+        future::ready(match removed {
+            Some(forwarder) => {
+                forwarder.abort();
+                Ok(Value::Bool(true))
+            }
+            None => Err(Error::invalid_params("no such subscription")),
+        })
+    };
+    io.add_subscription(
+        "p2p_subscription",
+        ("p2p_subscribe", subscribe),
+        ("p2p_unsubscribe", unsubscribe),
+    );
+}
+
+/// Pushes the node events a subscription asked for until it is dropped or
+/// the connection goes away.
+async fn forward(mut receiver: Receiver<NodeEvent>, topic: Topic, id: SubscriptionId, sink: Sink) {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(subscription = ?id, missed, "subscriber too slow, events dropped");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !topic.matches(&event) {
+            continue;
+        }
+        let result = match serde_json::to_value(&event) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!(%err, "cannot encode event");
+                continue;
+            }
+        };
+        let mut notification = serde_json::Map::new();
+        notification.insert("subscription".to_owned(), id.clone().into());
+        notification.insert("result".to_owned(), result);
+        if sink.notify(Params::Map(notification)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::{self, ReceivedMessage},
+        state::Change,
+    };
+    use futures::channel::mpsc;
+    use serde_json::json;
+
+    fn parse(params: Value) -> Result<Topic, Error> {
+        Topic::parse(serde_json::from_value(params).unwrap())
+    }
+
+    fn message(topic: &str) -> NodeEvent {
+        NodeEvent::Message(ReceivedMessage {
+            topic: topic.to_owned(),
+            message_id: "id".to_owned(),
+            peer: "peer".to_owned(),
+            payload: "hi".to_owned(),
+        })
+    }
+
+    fn change(key: &str) -> NodeEvent {
+        NodeEvent::StateChanged(Change {
+            key: key.to_owned(),
+            value: Value::Null,
+            peer: "peer".to_owned(),
+            counter: 1,
+            version: 1,
+        })
+    }
+
+    #[test]
+    fn params_are_parsed() {
+        assert!(matches!(
+            parse(json!(["messages"])),
+            Ok(Topic::Messages(None))
+        ));
+        assert!(matches!(
+            parse(json!(["messages", {"topic": "chat"}])),
+            Ok(Topic::Messages(Some(topic))) if topic == "chat"
+        ));
+        assert!(matches!(parse(json!(["peers"])), Ok(Topic::Peers)));
+        assert!(matches!(
+            parse(json!(["state", {"prefix": "app/"}])),
+            Ok(Topic::State(prefix)) if prefix == "app/"
+        ));
+        assert!(parse(json!(["blocks"])).is_err());
+        assert!(parse(json!(["messages", {"room": "chat"}])).is_err());
+        assert!(parse(json!(["messages", {}, {}])).is_err());
+    }
+
+    #[test]
+    fn events_are_matched() {
+        let chat = Topic::Messages(Some("chat".to_owned()));
+        assert!(chat.matches(&message("chat")));
+        assert!(!chat.matches(&message("other")));
+        assert!(Topic::Messages(None).matches(&message("other")));
+        assert!(!chat.matches(&change("chat")));
+
+        let peers = Topic::Peers;
+        assert!(peers.matches(&NodeEvent::PeerDisconnected {
+            peer: "peer".to_owned()
+        }));
+        assert!(!peers.matches(&message("chat")));
+
+        let state = Topic::State("app/".to_owned());
+        assert!(state.matches(&change("app/key")));
+        assert!(!state.matches(&change("other/key")));
+        assert!(Topic::State(String::new()).matches(&change("other/key")));
+    }
+
+    fn meta(connection: u64) -> WsMeta {
+        let (sender, _) = mpsc::unbounded();
+        WsMeta {
+            access: Access::all(),
+            connection,
+            session: Some(Arc::new(Session::new(sender))),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_unsubscribe() {
+        let (sender, _commands) = mpsc::channel(0);
+        let mut io = PubSubHandler::new(MetaIoHandler::default());
+        add_subscriptions(&mut io, P2PClient::new(sender, events::events()));
+        let (owner, other) = (meta(1), meta(2));
+
+        let subscribe = r#"{"jsonrpc":"2.0","id":1,"method":"p2p_subscribe","params":["peers"]}"#;
+        let subscribed = io.handle_request(subscribe, owner.clone()).await.unwrap();
+        assert!(subscribed.contains(r#""result":1"#), "{}", subscribed);
+
+        let unsubscribe = r#"{"jsonrpc":"2.0","id":2,"method":"p2p_unsubscribe","params":[1]}"#;
+        let refused = io.handle_request(unsubscribe, other.clone()).await.unwrap();
+        assert!(refused.contains("error"), "{}", refused);
+        let done = io.handle_request(unsubscribe, owner.clone()).await.unwrap();
+        assert!(done.contains(r#""result":true"#), "{}", done);
+        let again = io.handle_request(unsubscribe, owner).await.unwrap();
+        assert!(again.contains("error"), "{}", again);
+    }
+}
@@ -0,0 +1,346 @@
+use super::error::{P2PError, P2PResult};
+use crate::{
+    ack::MessageAcks,
+    blocks::Cid,
+    dht::{PutOutcome, RemoveOutcome},
+    events::{Events, NodeEvent},
+    state::{Changes, Entry},
+    transfer::TransferInfo,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    SinkExt,
+};
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+use serde_json::Value;
+use std::{path::PathBuf, time::Duration};
+use tokio::sync::broadcast;
+
+/// Requests handed from the rest of the node to the p2p `EventLoop`.
+pub enum P2PCommand {
+    NodeInfo {
+        sender: oneshot::Sender<NodeInfo>,
+    },
+    SendFile {
+        target: FileTarget,
+        path: PathBuf,
+        sender: oneshot::Sender<Result<Vec<TransferInfo>, String>>,
+    },
+    Transfers {
+        sender: oneshot::Sender<Vec<TransferInfo>>,
+    },
+    /// Acks for one published message, or for all of them.
+    MessageAcks {
+        message_id: Option<String>,
+        sender: oneshot::Sender<Vec<MessageAcks>>,
+    },
+    /// Sends a read ack for a received message.
+    MarkRead {
+        message_id: String,
+        sender: oneshot::Sender<Result<(), String>>,
+    },
+    /// A key of the shared state, or the whole map. `Null` when unset.
+    StateGet {
+        key: Option<String>,
+        sender: oneshot::Sender<Value>,
+    },
+    StateSet {
+        key: String,
+        value: Value,
+        sender: oneshot::Sender<Entry>,
+    },
+    /// Answered with the first changes under `prefix` after version
+    /// `since`, by default the current one, or with none after `timeout`.
+    StateWatch {
+        since: Option<u64>,
+        prefix: String,
+        timeout: Duration,
+        sender: oneshot::Sender<Changes>,
+    },
+    /// Stores a record locally and on the peers closest to its key.
+    DhtPut {
+        key: String,
+        value: Vec<u8>,
+        sender: oneshot::Sender<Result<PutOutcome, String>>,
+    },
+    DhtGet {
+        key: String,
+        sender: oneshot::Sender<Option<Vec<u8>>>,
+    },
+    /// Drops the local copy of a record and stops republishing it, copies
+    /// on other peers live until the record TTL runs out.
+    DhtRemove {
+        key: String,
+        sender: oneshot::Sender<RemoveOutcome>,
+    },
+    /// Stores a block and announces us as its provider.
+    BlocksPut {
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<Cid, String>>,
+    },
+    /// A block from the local store, or fetched from one of its providers.
+    BlocksGet {
+        cid: Cid,
+        sender: oneshot::Sender<Result<Vec<u8>, String>>,
+    },
+    /// Dials a peer, answered once the dial has started.
+    AddPeer {
+        addr: Multiaddr,
+        sender: oneshot::Sender<Result<(), String>>,
+    },
+    /// Closes every connection to a peer, answered with whether there were
+    /// any.
+    RemovePeer {
+        peer: PeerId,
+        sender: oneshot::Sender<bool>,
+    },
+    Peers {
+        sender: oneshot::Sender<Vec<PeerInfo>>,
+    },
+    /// Starts listening on another address, answered with it.
+    ListenOn {
+        host: String,
+        port: u16,
+        sender: oneshot::Sender<Result<String, String>>,
+    },
+    /// Stops the listener started for an address, answered with whether
+    /// there was one.
+    RemoveListener {
+        addr: Multiaddr,
+        sender: oneshot::Sender<bool>,
+    },
+    /// Disconnects from every peer and ends the event loop.
+    Shutdown {
+        sender: oneshot::Sender<()>,
+    },
+}
+
+/// Who a file is offered to.
+#[derive(Debug, Clone)]
+pub enum FileTarget {
+    Peer(PeerId),
+    /// Every peer subscribed to the floodsub topic.
+    Topic(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfo {
+    pub peer_id: String,
+    pub listen_addrs: Vec<String>,
+    pub external_addrs: Vec<String>,
+    /// AutoNAT verdict: "public", "private" or "unknown".
+    pub reachability: String,
+    pub connected_peers: usize,
+    /// Protocols other peers can open substreams for.
+    pub protocols: Vec<String>,
+    pub uptime_secs: u64,
+}
+
+impl NodeInfo {
+    pub fn new(peer_id: &PeerId) -> Self {
+        NodeInfo {
+            peer_id: peer_id.to_base58(),
+            listen_addrs: Vec::new(),
+            external_addrs: Vec::new(),
+            reachability: "unknown".into(),
+            connected_peers: 0,
+            protocols: Vec::new(),
+            uptime_secs: 0,
+        }
+    }
+
+    pub fn add_listen_addr(&mut self, addr: &Multiaddr) {
+        self.listen_addrs.push(addr.to_string());
+    }
+
+    pub fn add_external_addr(&mut self, addr: &Multiaddr) {
+        self.external_addrs.push(addr.to_string());
+    }
+}
+
+/// A connected peer, with the address of the first connection to it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub address: String,
+}
+
+/// Cheap, cloneable handle used to drive a running `P2PServer`.
+#[derive(Clone)]
+pub struct P2PClient {
+    sender: mpsc::Sender<P2PCommand>,
+    events: Events,
+}
+
+impl P2PClient {
+    pub fn new(sender: mpsc::Sender<P2PCommand>, events: Events) -> Self {
+        P2PClient { sender, events }
+    }
+
+    /// Every event from now on: messages, peers coming and going and state
+    /// changes.
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn node_info(&mut self) -> P2PResult<NodeInfo> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::NodeInfo { sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    /// Offers a file, returns the transfers started for it.
+    pub async fn send_file(
+        &mut self,
+        target: FileTarget,
+        path: PathBuf,
+    ) -> P2PResult<Vec<TransferInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::SendFile {
+            target,
+            path,
+            sender,
+        })
+        .await?;
+        let transfers = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(transfers.map_err(P2PError::Transfer)?)
+    }
+
+    pub async fn transfers(&mut self) -> P2PResult<Vec<TransferInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::Transfers { sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn message_acks(
+        &mut self,
+        message_id: Option<String>,
+    ) -> P2PResult<Vec<MessageAcks>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::MessageAcks { message_id, sender })
+            .await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn mark_read(&mut self, message_id: String) -> P2PResult<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::MarkRead { message_id, sender })
+            .await?;
+        let read = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(read.map_err(P2PError::Ack)?)
+    }
+
+    pub async fn state_get(&mut self, key: Option<String>) -> P2PResult<Value> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::StateGet { key, sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn state_set(&mut self, key: String, value: Value) -> P2PResult<Entry> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::StateSet { key, value, sender })
+            .await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn state_watch(
+        &mut self,
+        since: Option<u64>,
+        prefix: String,
+        timeout: Duration,
+    ) -> P2PResult<Changes> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::StateWatch {
+            since,
+            prefix,
+            timeout,
+            sender,
+        })
+        .await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn dht_put(&mut self, key: String, value: Vec<u8>) -> P2PResult<PutOutcome> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::DhtPut { key, value, sender }).await?;
+        let outcome = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(outcome.map_err(P2PError::Dht)?)
+    }
+
+    pub async fn dht_get(&mut self, key: String) -> P2PResult<Option<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::DhtGet { key, sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn dht_remove(&mut self, key: String) -> P2PResult<RemoveOutcome> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::DhtRemove { key, sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn blocks_put(&mut self, data: Vec<u8>) -> P2PResult<Cid> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::BlocksPut { data, sender }).await?;
+        let cid = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(cid.map_err(P2PError::Blocks)?)
+    }
+
+    pub async fn blocks_get(&mut self, cid: Cid) -> P2PResult<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::BlocksGet { cid, sender }).await?;
+        let data = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(data.map_err(P2PError::Blocks)?)
+    }
+
+    pub async fn add_peer(&mut self, addr: Multiaddr) -> P2PResult<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::AddPeer { addr, sender }).await?;
+        let dialed = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(dialed.map_err(P2PError::Dial)?)
+    }
+
+    pub async fn remove_peer(&mut self, peer: PeerId) -> P2PResult<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::RemovePeer { peer, sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn peers(&mut self) -> P2PResult<Vec<PeerInfo>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::Peers { sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn listen_on(&mut self, host: String, port: u16) -> P2PResult<String> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::ListenOn { host, port, sender })
+            .await?;
+        let addr = receiver.await.map_err(|_| P2PError::ServerStopped)?;
+        Ok(addr.map_err(P2PError::Listen)?)
+    }
+
+    pub async fn remove_listener(&mut self, addr: Multiaddr) -> P2PResult<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::RemoveListener { addr, sender })
+            .await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    pub async fn shutdown(&mut self) -> P2PResult<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(P2PCommand::Shutdown { sender }).await?;
+        Ok(receiver.await.map_err(|_| P2PError::ServerStopped)?)
+    }
+
+    async fn send(&mut self, command: P2PCommand) -> P2PResult<()> {
+        self.sender
+            .send(command)
+            .await
+            .map_err(|_| P2PError::ServerStopped)?;
+        Ok(())
+    }
+}
@@ -4,6 +4,13 @@ pub type P2PResult<T> = Result<T, Box<dyn Error>>;
 #[derive(Debug)]
 pub enum P2PError {
     ServerRunning,
+    ServerStopped,
+    Transfer(String),
+    Ack(String),
+    Dht(String),
+    Blocks(String),
+    Dial(String),
+    Listen(String),
 }
 impl Error for P2PError {}
 
@@ -11,6 +18,13 @@ impl std::fmt::Display for P2PError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::ServerRunning => write!(f, "server already running"),
+            Self::ServerStopped => write!(f, "server stopped"),
+            Self::Transfer(reason) => write!(f, "file transfer failed: {}", reason),
+            Self::Ack(reason) => write!(f, "ack failed: {}", reason),
+            Self::Dht(reason) => write!(f, "dht operation failed: {}", reason),
+            Self::Blocks(reason) => write!(f, "block exchange failed: {}", reason),
+            Self::Dial(reason) => write!(f, "dial failed: {}", reason),
+            Self::Listen(reason) => write!(f, "listen failed: {}", reason),
         }
     }
 }
@@ -1,20 +1,80 @@
+mod client;
 mod error;
-use crate::behaviour::P2PBehaviour;
+use crate::{
+    ack::{self, Acks},
+    behaviour::P2PBehaviour,
+    blocks::{self, BlockFetches, BlockStore},
+    causal::CausalOrder,
+    dht::{DhtConfig, DhtQueries, RemoveOutcome, SledStore},
+    events::{self, Events, NodeEvent},
+    metrics::Metrics,
+    rendezvous::Rendezvous,
+    state::{self, Changes, SharedState},
+    transfer::{self, Direction, ReceiveConfig, TransferInfo, Transfers},
+};
 use async_std::sync::Mutex;
+pub use client::*;
 use error::*;
-use futures::{channel::mpsc, StreamExt};
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
 use libp2p::{
-    core::{connection::ListenerId, muxing::StreamMuxerBox, transport::Boxed, upgrade},
-    floodsub::{self, Floodsub, Topic},
+    core::{
+        connection::ListenerId,
+        muxing::StreamMuxerBox,
+        transport::{Boxed, OrTransport},
+        upgrade::{self, ProtocolName},
+        UpgradeInfo,
+    },
+    autonat,
+    bandwidth::{BandwidthLogging, BandwidthSinks},
+    floodsub::{self, Floodsub, FloodsubConfig, Topic},
+    identify::{Identify, IdentifyConfig},
     identity,
+    kad::{
+        record::{store::RecordStore, Key, Record},
+        Kademlia, Quorum,
+    },
     mdns::Mdns,
-    mplex, noise,
-    swarm::{SwarmBuilder, SwarmEvent},
+    mplex,
+    multiaddr::Protocol,
+    noise, ping,
+    relay::v2::{
+        client::{transport::ClientTransport, Client},
+        relay::Relay,
+    },
+    rendezvous,
+    swarm::{AddressScore, IntoProtocolsHandler, NetworkBehaviour, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
     Multiaddr, PeerId, Swarm, Transport,
 };
-use std::{collections::HashMap, error::Error};
-use tokio::io::{self, AsyncBufReadExt};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncBufReadExt},
+    time,
+};
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
+
+/// Default for how long received messages wait for causally earlier ones.
+pub const DEFAULT_MAX_ORDER_DELAY: Duration = Duration::from_secs(1);
+
+/// How often messages held for causal order are checked for expiry.
+const RELEASE_HELD_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often shared state is compared with a random neighbour.
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often rendezvous points are redialed, registrations renewed and
+/// peers discovered.
+const RENDEZVOUS_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct P2PConfigBuilder {
     host: String,
@@ -22,6 +82,19 @@ pub struct P2PConfigBuilder {
     private_key: identity::Keypair,
     peer_id: PeerId,
     peers: Vec<Multiaddr>,
+    relay_server: bool,
+    relays: Vec<Multiaddr>,
+    external_addrs: Vec<Multiaddr>,
+    transfers: ReceiveConfig,
+    outbox: PathBuf,
+    headless: bool,
+    acks: bool,
+    max_order_delay: Duration,
+    dht: DhtConfig,
+    blocks_dir: PathBuf,
+    rendezvous_server: bool,
+    rendezvous_points: Vec<Multiaddr>,
+    rendezvous_namespaces: Vec<String>,
 }
 impl Default for P2PConfigBuilder {
     fn default() -> Self {
@@ -32,32 +105,81 @@ impl Default for P2PConfigBuilder {
             private_key: key,
             peer_id,
             peers: Vec::new(),
+            relay_server: false,
+            relays: Vec::new(),
+            external_addrs: Vec::new(),
+            transfers: ReceiveConfig::default(),
+            outbox: "outbox".into(),
+            headless: false,
+            acks: false,
+            max_order_delay: DEFAULT_MAX_ORDER_DELAY,
+            dht: DhtConfig::default(),
+            blocks_dir: "blocks".into(),
+            rendezvous_server: false,
+            rendezvous_points: Vec::new(),
+            rendezvous_namespaces: Vec::new(),
         }
     }
 }
 
 impl P2PConfigBuilder {
-    pub fn set_host(mut self, host: String) -> Self {
-        self.host = host;
-        self
-    }
     pub fn set_port(mut self, port: u16) -> Self {
         self.port = port;
         self
     }
-    pub fn set_private_key(mut self, private_key: identity::Keypair) -> Self {
-        self.private_key = private_key;
+    pub fn set_relay_server(mut self, relay_server: bool) -> Self {
+        self.relay_server = relay_server;
         self
     }
-    pub fn set_peer_id(mut self, peer_id: PeerId) -> Self {
-        self.peer_id = peer_id;
+    pub fn set_relays(mut self, relays: Vec<Multiaddr>) -> Self {
+        self.relays = relays;
         self
     }
-    pub fn set_peers(mut self, peers: Vec<Multiaddr>) -> Self {
-        self.peers = peers;
+    pub fn set_external_addrs(mut self, external_addrs: Vec<Multiaddr>) -> Self {
+        self.external_addrs = external_addrs;
         self
     }
-    pub fn build(mut self) -> P2PConfig {
+    pub fn set_transfers(mut self, transfers: ReceiveConfig) -> Self {
+        self.transfers = transfers;
+        self
+    }
+    pub fn set_outbox(mut self, outbox: PathBuf) -> Self {
+        self.outbox = outbox;
+        self
+    }
+    pub fn set_headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+    pub fn set_acks(mut self, acks: bool) -> Self {
+        self.acks = acks;
+        self
+    }
+    pub fn set_max_order_delay(mut self, max_order_delay: Duration) -> Self {
+        self.max_order_delay = max_order_delay;
+        self
+    }
+    pub fn set_dht(mut self, dht: DhtConfig) -> Self {
+        self.dht = dht;
+        self
+    }
+    pub fn set_blocks_dir(mut self, blocks_dir: PathBuf) -> Self {
+        self.blocks_dir = blocks_dir;
+        self
+    }
+    pub fn set_rendezvous_server(mut self, rendezvous_server: bool) -> Self {
+        self.rendezvous_server = rendezvous_server;
+        self
+    }
+    pub fn set_rendezvous_points(mut self, rendezvous_points: Vec<Multiaddr>) -> Self {
+        self.rendezvous_points = rendezvous_points;
+        self
+    }
+    pub fn set_rendezvous_namespaces(mut self, rendezvous_namespaces: Vec<String>) -> Self {
+        self.rendezvous_namespaces = rendezvous_namespaces;
+        self
+    }
+    pub fn build(self) -> P2PConfig {
         P2PConfig::from_builder(self)
     }
 }
@@ -69,6 +191,36 @@ pub struct P2PConfig {
     private_key: identity::Keypair,
     peer_id: PeerId,
     peers: Vec<Multiaddr>,
+    /// Act as a circuit relay v2 server for other peers.
+    relay_server: bool,
+    /// Relays to reserve a slot on and listen through via `/p2p-circuit`.
+    relays: Vec<Multiaddr>,
+    /// Addresses other peers reach us at, advertised as they are besides
+    /// the one AutoNAT confirms.
+    external_addrs: Vec<Multiaddr>,
+    /// Whose files are accepted and where they are saved.
+    transfers: ReceiveConfig,
+    /// The only directory files are sent from.
+    outbox: PathBuf,
+    /// Do not read messages to publish from stdin.
+    headless: bool,
+    /// Send delivered and read acks for received messages to their
+    /// publishers.
+    acks: bool,
+    /// How long a received message may wait for causally earlier ones
+    /// before it is handled anyway.
+    max_order_delay: Duration,
+    dht: DhtConfig,
+    /// Where content-addressed blocks are stored.
+    blocks_dir: PathBuf,
+    /// Act as a rendezvous point other peers register with.
+    rendezvous_server: bool,
+    /// Rendezvous points to register with and discover peers at, each
+    /// ending in `/p2p/<peer id>`.
+    rendezvous_points: Vec<Multiaddr>,
+    /// Namespaces to register under and discover peers in, by default the
+    /// communication topic.
+    rendezvous_namespaces: Vec<String>,
 }
 
 impl Default for P2PConfig {
@@ -80,6 +232,19 @@ impl Default for P2PConfig {
             private_key: key,
             peer_id,
             peers: Vec::new(),
+            relay_server: false,
+            relays: Vec::new(),
+            external_addrs: Vec::new(),
+            transfers: ReceiveConfig::default(),
+            outbox: "outbox".into(),
+            headless: false,
+            acks: false,
+            max_order_delay: DEFAULT_MAX_ORDER_DELAY,
+            dht: DhtConfig::default(),
+            blocks_dir: "blocks".into(),
+            rendezvous_server: false,
+            rendezvous_points: Vec::new(),
+            rendezvous_namespaces: Vec::new(),
         }
     }
 }
@@ -92,6 +257,19 @@ impl P2PConfig {
             private_key,
             peer_id,
             peers,
+            relay_server,
+            relays,
+            external_addrs,
+            transfers,
+            outbox,
+            headless,
+            acks,
+            max_order_delay,
+            dht,
+            blocks_dir,
+            rendezvous_server,
+            rendezvous_points,
+            rendezvous_namespaces,
         } = builder;
         Self {
             host,
@@ -99,6 +277,19 @@ impl P2PConfig {
             private_key,
             peer_id,
             peers,
+            relay_server,
+            relays,
+            external_addrs,
+            transfers,
+            outbox,
+            headless,
+            acks,
+            max_order_delay,
+            dht,
+            blocks_dir,
+            rendezvous_server,
+            rendezvous_points,
+            rendezvous_namespaces,
         }
     }
     pub fn add_peer(&mut self, peer: Multiaddr) {
@@ -107,48 +298,478 @@ impl P2PConfig {
 }
 
 type ClientMessage = String;
-struct PeerMessage;
+
+struct Watcher {
+    since: u64,
+    prefix: String,
+    /// When to answer with no changes.
+    deadline: Instant,
+    sender: oneshot::Sender<Changes>,
+}
 
 struct EventLoop {
     swarm: Swarm<P2PBehaviour>,
     message_receiver: mpsc::Receiver<ClientMessage>,
+    command_receiver: mpsc::Receiver<P2PCommand>,
     topics: HashMap<String, Topic>,
+    /// Addresses configured as external, kept whatever AutoNAT says.
+    external_addrs: Vec<Multiaddr>,
+    /// The only directory files are sent from.
+    outbox: PathBuf,
+    /// Public address last confirmed by AutoNAT and advertised as external.
+    confirmed_addr: Option<Multiaddr>,
+    /// Connected peers, with the address of the first connection to each.
+    connected: HashMap<PeerId, Multiaddr>,
+    /// Listeners by the address they were started for, so they can be
+    /// removed by it.
+    listeners: HashMap<Multiaddr, ListenerId>,
+    protocols: Vec<String>,
+    started: Instant,
+    /// `state_watch` calls waiting for a change.
+    watchers: Vec<Watcher>,
+    /// State version subscribers have seen the changes up to.
+    published_version: u64,
+    /// Dropped with the event loop, which is how `P2PServer::closed` learns
+    /// that it ended.
+    _closed: oneshot::Sender<()>,
 }
 impl EventLoop {
     pub async fn run(mut self) {
+        let mut release_held = time::interval(RELEASE_HELD_INTERVAL);
+        let mut anti_entropy = time::interval(ANTI_ENTROPY_INTERVAL);
+        // Points were dialed at start, the first round waits for that.
+        let mut rendezvous = time::interval_at(
+            time::Instant::now() + RENDEZVOUS_INTERVAL,
+            RENDEZVOUS_INTERVAL,
+        );
         loop {
             tokio::select! {
-                    event = self.swarm.select_next_some() => match event{
-                        SwarmEvent::NewListenAddr{address,..} =>println!("Listening on: {:?}", address),
-                        _ => {}
-                    },
-            message = self.message_receiver.next() => {
-                match message{
-                            Some(c) => self.handle_incoming_message(c).await,
-                            None=>  return,
+                event = self.swarm.select_next_some() => {
+                    self.handle_event(event).await;
+                    self.sync_reachability();
+                    self.notify_watchers();
+                },
+                message = self.message_receiver.next() => {
+                    match message{
+                        Some(c) => self.handle_incoming_message(c).await,
+                        None=>  return,
+                    }
+                },
+                _ = release_held.tick() => {
+                    self.swarm.behaviour_mut().release_held();
+                    self.notify_watchers();
+                },
+                _ = anti_entropy.tick() => {
+                    self.swarm.behaviour_mut().sync_state();
+                },
+                _ = rendezvous.tick() => {
+                    self.refresh_rendezvous();
+                },
+                command = self.command_receiver.next() => {
+                    match command{
+                        Some(P2PCommand::Shutdown { sender }) => {
+                            self.shutdown();
+                            let _ = sender.send(());
+                            return;
                         }
+                        Some(c) => {
+                            self.handle_command(c).await;
+                            self.notify_watchers();
+                        }
+                        None=>  return,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_event<E: std::fmt::Debug>(&mut self, event: SwarmEvent<(), E>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!(%address, "listening");
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                info!(peer_id = %peer_id, address = %endpoint.get_remote_address(), "connection established");
+                self.connected
+                    .entry(peer_id)
+                    .or_insert_with(|| endpoint.get_remote_address().clone());
+                self.record_connection_event("established");
+                // Peers reached by dialing (directly or through a relay) are not
+                // discovered by mdns, so add them to the floodsub view here.
+                self.swarm
+                    .behaviour_mut()
+                    .floodsub
+                    .add_node_to_partial_view(peer_id);
+                self.swarm.behaviour_mut().resume_transfers(peer_id);
+                if num_established.get() == 1 {
+                    self.swarm.behaviour_mut().rendezvous_connected(peer_id);
+                    let connected = NodeEvent::PeerConnected {
+                        peer: peer_id.to_base58(),
+                        address: endpoint.get_remote_address().to_string(),
+                    };
+                    let _ = self.swarm.behaviour().events.send(connected);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                cause,
+                num_established,
+                ..
+            } => {
+                info!(peer_id = %peer_id, ?cause, "connection closed");
+                if num_established == 0 {
+                    self.connected.remove(&peer_id);
+                    let disconnected = NodeEvent::PeerDisconnected {
+                        peer: peer_id.to_base58(),
+                    };
+                    let _ = self.swarm.behaviour().events.send(disconnected);
+                }
+                self.record_connection_event("closed");
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                addresses,
+                reason,
+            } => {
+                info!(?addresses, ?reason, "listener closed");
+                self.listeners.retain(|_, id| *id != listener_id);
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => {
+                warn!(address = %send_back_addr, %error, "incoming connection failed");
+                self.record_connection_event("incoming_error");
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                warn!(?peer_id, %error, "outgoing connection failed");
+                self.record_connection_event("outgoing_error");
+            }
+            event => debug!(?event, "swarm event"),
+        }
+    }
+
+    /// Unsubscribes from every topic so peers stop routing to us, then
+    /// closes all connections.
+    #[instrument(skip(self), fields(peers = self.connected.len()))]
+    fn shutdown(&mut self) {
+        let behaviour = self.swarm.behaviour_mut();
+        for topic in self.topics.values() {
+            behaviour.floodsub.unsubscribe(topic.clone());
+        }
+        for (peer, _) in self.connected.drain() {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        info!("p2p stopped");
+    }
+
+    /// Answers the `state_watch` calls that have changes to see or waited
+    /// long enough, and drops those whose caller gave up.
+    fn notify_watchers(&mut self) {
+        self.publish_state_changes();
+        let state = &self.swarm.behaviour().state;
+        let now = Instant::now();
+        for mut watcher in mem::take(&mut self.watchers) {
+            if watcher.sender.is_canceled() {
+                continue;
+            }
+            let changes = if watcher.since < state.version() {
+                state.changes(watcher.since, &watcher.prefix)
+            } else {
+                Changes::none(state.version())
+            };
+            if !changes.changes.is_empty() || watcher.deadline <= now {
+                let _ = watcher.sender.send(changes);
+            } else {
+                // Changes elsewhere in the map do not wake this watcher.
+                watcher.since = changes.version;
+                self.watchers.push(watcher);
+            }
+        }
+    }
+
+    /// Hands state changes since the last call to subscribers, whether
+    /// written here or merged from other nodes.
+    fn publish_state_changes(&mut self) {
+        let behaviour = self.swarm.behaviour();
+        let version = behaviour.state.version();
+        if version == self.published_version {
+            return;
+        }
+        if behaviour.events.receiver_count() > 0 {
+            for change in behaviour.state.changes(self.published_version, "").changes {
+                let _ = behaviour.events.send(NodeEvent::StateChanged(change));
+            }
+        }
+        self.published_version = version;
+    }
+
+    /// Redials rendezvous points we lost, the others get their
+    /// registrations renewed and are asked for new peers.
+    fn refresh_rendezvous(&mut self) {
+        for (point, addr) in self.swarm.behaviour().rendezvous.points() {
+            if self.connected.contains_key(&point) {
+                self.swarm.behaviour_mut().refresh_rendezvous(point);
+            } else if let Err(err) = self.swarm.dial(addr) {
+                warn!(peer_id = %point, %err, "cannot dial rendezvous point");
+            }
+        }
+    }
+
+    fn record_connection_event(&self, event: &str) {
+        let metrics = &self.swarm.behaviour().metrics;
+        metrics
+            .connection_events
+            .with_label_values(&[event])
+            .inc();
+        metrics
+            .connected_peers
+            .set(self.swarm.network_info().num_peers() as i64);
+    }
+
+    /// Keeps the swarm's external addresses, which identify advertises to
+    /// other peers, in line with what AutoNAT has confirmed.
+    fn sync_reachability(&mut self) {
+        let public = self.swarm.behaviour().autonat.public_address().cloned();
+        if public == self.confirmed_addr {
+            return;
+        }
+        if let Some(old) = self.confirmed_addr.take() {
+            if !self.external_addrs.contains(&old) {
+                self.swarm.remove_external_address(&old);
+            }
+        }
+        if let Some(addr) = &public {
+            self.swarm
+                .add_external_address(addr.clone(), AddressScore::Infinite);
+        }
+        self.confirmed_addr = public;
+    }
+
+    async fn handle_command(&mut self, command: P2PCommand) {
+        match command {
+            P2PCommand::NodeInfo { sender } => {
+                let _ = sender.send(self.node_info());
+            }
+            P2PCommand::SendFile {
+                target,
+                path,
+                sender,
+            } => {
+                let _ = sender.send(self.send_file(target, &path));
+            }
+            P2PCommand::Transfers { sender } => {
+                let _ = sender.send(self.swarm.behaviour().transfers.list());
+            }
+            P2PCommand::MessageAcks { message_id, sender } => {
+                let acks = self.swarm.behaviour().acks.list(message_id.as_deref());
+                let _ = sender.send(acks);
+            }
+            P2PCommand::MarkRead { message_id, sender } => {
+                let _ = sender.send(self.swarm.behaviour_mut().mark_read(&message_id));
+            }
+            P2PCommand::StateGet { key, sender } => {
+                let state = &self.swarm.behaviour().state;
+                let value = match key {
+                    Some(key) => state.get(&key).map(|entry| entry.value.clone()),
+                    None => serde_json::to_value(state.values()).ok(),
+                };
+                let _ = sender.send(value.unwrap_or_default());
+            }
+            P2PCommand::StateSet { key, value, sender } => {
+                let _ = sender.send(self.swarm.behaviour_mut().set_state(key, value));
+            }
+            P2PCommand::DhtPut { key, value, sender } => {
+                let behaviour = self.swarm.behaviour_mut();
+                let record = Record::new(Key::from(key.into_bytes()), value);
+                match behaviour.kademlia.put_record(record, Quorum::One) {
+                    Ok(id) => behaviour.dht_queries.put(id, sender),
+                    Err(err) => {
+                        let _ = sender.send(Err(format!("{:?}", err)));
+                    }
                 }
             }
+            P2PCommand::DhtGet { key, sender } => {
+                let behaviour = self.swarm.behaviour_mut();
+                let id = behaviour
+                    .kademlia
+                    .get_record(&Key::from(key.into_bytes()), Quorum::One);
+                behaviour.dht_queries.get(id, sender);
+            }
+            P2PCommand::DhtRemove { key, sender } => {
+                let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+                let record_key = Key::from(key.clone().into_bytes());
+                let removed = kademlia.store_mut().get(&record_key).is_some();
+                kademlia.remove_record(&record_key);
+                let _ = sender.send(RemoveOutcome {
+                    key,
+                    removed,
+                    local_only: true,
+                });
+            }
+            P2PCommand::BlocksPut { data, sender } => {
+                let _ = sender.send(self.swarm.behaviour_mut().put_block(&data));
+            }
+            P2PCommand::BlocksGet { cid, sender } => {
+                self.swarm.behaviour_mut().get_block(cid, sender);
+            }
+            P2PCommand::StateWatch {
+                since,
+                prefix,
+                timeout,
+                sender,
+            } => {
+                let since = since.unwrap_or_else(|| self.swarm.behaviour().state.version());
+                self.watchers.push(Watcher {
+                    since,
+                    prefix,
+                    deadline: Instant::now() + timeout,
+                    sender,
+                });
+            }
+            P2PCommand::AddPeer { addr, sender } => {
+                let dialed = self.dial(addr).await.map_err(|err| err.to_string());
+                let _ = sender.send(dialed);
+            }
+            P2PCommand::RemovePeer { peer, sender } => {
+                let _ = sender.send(self.swarm.disconnect_peer_id(peer).is_ok());
+            }
+            P2PCommand::Peers { sender } => {
+                let peers = self
+                    .connected
+                    .iter()
+                    .map(|(peer, addr)| PeerInfo {
+                        peer_id: peer.to_base58(),
+                        address: addr.to_string(),
+                    })
+                    .collect();
+                let _ = sender.send(peers);
+            }
+            P2PCommand::ListenOn { host, port, sender } => {
+                let listening = match self.start_listen(host, port).await {
+                    Ok(id) => Ok(self.listen_addr(id)),
+                    Err(err) => Err(err.to_string()),
+                };
+                let _ = sender.send(listening);
+            }
+            P2PCommand::RemoveListener { addr, sender } => {
+                let removed = match self.listeners.remove(&addr) {
+                    Some(id) => self.swarm.remove_listener(id),
+                    None => false,
+                };
+                let _ = sender.send(removed);
+            }
+            // Handled in `run`, which has to stop afterwards.
+            P2PCommand::Shutdown { sender } => {
+                let _ = sender.send(());
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    fn send_file(&mut self, target: FileTarget, path: &Path) -> Result<Vec<TransferInfo>, String> {
+        let path = &outbox_file(&self.outbox, path)?;
+        let peers = match target {
+            FileTarget::Peer(peer) => vec![peer],
+            FileTarget::Topic(topic) => {
+                // Accept the short names in `topics` as well as raw topic ids.
+                let topic = match self.topics.get(&topic) {
+                    Some(topic) => topic.id().to_owned(),
+                    None => topic,
+                };
+                let peers = self.swarm.behaviour().topic_peers(&topic);
+                if peers.is_empty() {
+                    return Err(format!("no peers subscribed to {}", topic));
+                }
+                peers
+            }
+        };
+        let behaviour = self.swarm.behaviour_mut();
+        let mut id = String::new();
+        for peer in &peers {
+            let offer = behaviour
+                .send_file(*peer, path)
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            info!(peer_id = %peer, transfer_id = %offer.id, "file offered");
+            id = offer.id;
         }
+        let peers: Vec<String> = peers.iter().map(PeerId::to_base58).collect();
+        Ok(behaviour
+            .transfers
+            .list()
+            .into_iter()
+            .filter(|t| t.id == id && t.direction == Direction::Sending && peers.contains(&t.peer))
+            .collect())
     }
-    //async fn handle_event(&mut self, event: SwarmEvent<>) {
-    //    todo!()
-    //}
+
+    fn node_info(&self) -> NodeInfo {
+        let mut info = NodeInfo::new(self.swarm.local_peer_id());
+        for addr in self.swarm.listeners() {
+            info.add_listen_addr(addr);
+        }
+        for record in self.swarm.external_addresses() {
+            info.add_external_addr(&record.addr);
+        }
+        info.reachability = match self.swarm.behaviour().autonat.nat_status() {
+            autonat::NatStatus::Public(_) => "public",
+            autonat::NatStatus::Private => "private",
+            autonat::NatStatus::Unknown => "unknown",
+        }
+        .into();
+        info.connected_peers = self.swarm.network_info().num_peers();
+        info.protocols = self.protocols.clone();
+        info.uptime_secs = self.started.elapsed().as_secs();
+        info
+    }
+
+    /// The address a listener was started for.
+    fn listen_addr(&self, id: ListenerId) -> String {
+        self.listeners
+            .iter()
+            .find(|(_, listener)| **listener == id)
+            .map(|(addr, _)| addr.to_string())
+            .unwrap_or_default()
+    }
+
+    #[instrument(skip(self, message), fields(bytes = message.len()))]
     async fn handle_incoming_message(&mut self, message: ClientMessage) {
-        self.swarm.behaviour_mut().floodsub.publish(
-            self.topics.get("Communication").unwrap().clone(),
-            message.as_bytes(),
-        )
+        let topic = self.topics.get("Communication").unwrap().clone();
+        info!(topic = topic.id(), "publishing message");
+        self.swarm
+            .behaviour()
+            .metrics
+            .messages_published
+            .with_label_values(&[topic.id()])
+            .inc();
+        let behaviour = self.swarm.behaviour_mut();
+        let data = behaviour.causal.stamp(topic.id(), message);
+        behaviour.floodsub.publish(topic, data)
     }
 
     pub async fn dial(&mut self, addr: Multiaddr) -> P2PResult<()> {
-        Ok(self.swarm.dial_addr(addr)?)
+        Ok(self.swarm.dial(addr)?)
     }
 
     pub async fn start_listen(&mut self, host: String, port: u16) -> P2PResult<ListenerId> {
         let listen_addr: Multiaddr = format!("/ip4/{}/tcp/{}", host, port).parse()?;
-        println!("trying to listen on: {}", listen_addr);
-        Ok(self.swarm.listen_on(listen_addr)?)
+        info!(%listen_addr, "trying to listen");
+        let id = self.swarm.listen_on(listen_addr.clone())?;
+        self.listeners.insert(listen_addr, id);
+        Ok(id)
+    }
+
+    pub async fn listen_via_relay(&mut self, relay: Multiaddr) -> P2PResult<ListenerId> {
+        let listen_addr = relay.with(Protocol::P2pCircuit);
+        info!(%listen_addr, "trying to listen via relay");
+        let id = self.swarm.listen_on(listen_addr.clone())?;
+        self.listeners.insert(listen_addr, id);
+        Ok(id)
     }
 }
 
@@ -158,19 +779,49 @@ pub struct P2PServer {
     private_key: identity::Keypair,
     peer_id: PeerId,
     peers: Vec<Multiaddr>,
+    relay_server: bool,
+    relays: Vec<Multiaddr>,
+    external_addrs: Vec<Multiaddr>,
+    transfers: ReceiveConfig,
+    outbox: PathBuf,
+    headless: bool,
+    acks: bool,
+    max_order_delay: Duration,
+    dht: DhtConfig,
+    blocks_dir: PathBuf,
+    rendezvous_server: bool,
+    rendezvous_points: Vec<Multiaddr>,
+    rendezvous_namespaces: Vec<String>,
     runnig: bool,
     lock: Mutex<()>,
     message_sender: Option<mpsc::Sender<ClientMessage>>,
+    command_sender: Option<mpsc::Sender<P2PCommand>>,
+    closed: Option<oneshot::Receiver<()>>,
+    metrics: Arc<Metrics>,
+    events: Events,
 }
 
 impl P2PServer {
-    pub fn new(config: P2PConfig) -> P2PResult<Self> {
+    pub fn new(config: P2PConfig, metrics: Arc<Metrics>) -> P2PResult<Self> {
         let P2PConfig {
             host,
             port,
             private_key,
             peer_id,
             peers,
+            relay_server,
+            relays,
+            external_addrs,
+            transfers,
+            outbox,
+            headless,
+            acks,
+            max_order_delay,
+            dht,
+            blocks_dir,
+            rendezvous_server,
+            rendezvous_points,
+            rendezvous_namespaces,
         } = config;
 
         Ok(Self {
@@ -179,76 +830,236 @@ impl P2PServer {
             private_key,
             peers,
             peer_id,
+            relay_server,
+            relays,
+            external_addrs,
+            transfers,
+            outbox,
+            headless,
+            acks,
+            max_order_delay,
+            dht,
+            blocks_dir,
+            rendezvous_server,
+            rendezvous_points,
+            rendezvous_namespaces,
             runnig: false,
             lock: Mutex::new(()),
             message_sender: None,
+            command_sender: None,
+            closed: None,
+            metrics,
+            events: events::events(),
         })
     }
+
+    /// Returns a handle to the running server, or `None` before `start`.
+    pub fn client(&self) -> Option<P2PClient> {
+        let events = self.events.clone();
+        self.command_sender
+            .clone()
+            .map(|sender| P2PClient::new(sender, events))
+    }
+
+    /// Resolves once the event loop has ended, right away if it never ran.
+    pub async fn closed(&mut self) {
+        if let Some(closed) = self.closed.as_mut() {
+            let _ = closed.await;
+        }
+    }
+
+    /// Disconnects from all peers and ends the event loop.
+    pub async fn stop(&mut self) {
+        if let Some(mut client) = self.client() {
+            // Fails only if the event loop is gone already.
+            let _ = client.shutdown().await;
+        }
+        self.closed().await;
+    }
+    #[instrument(name = "p2p_start", skip(self), fields(peer_id = %self.peer_id))]
     pub async fn start(&mut self) -> P2PResult<()> {
         self.lock.lock().await;
         if self.runnig {
-            eprintln!("Server already running");
+            warn!("server already running");
             Err(Box::new(P2PError::ServerRunning))
         } else {
             self.runnig = true;
             let (message_sender, message_receiver) = mpsc::channel(0);
             self.message_sender = Some(message_sender);
+            let (command_sender, command_receiver) = mpsc::channel(0);
+            self.command_sender = Some(command_sender);
             let mdns = futures::executor::block_on(generate_mdns())?;
             let topic = generate_floodsub_topic("P2PNodeCommunicationTopic");
-            //let transport = config_transport(&self.private_key);
-            let transport = libp2p::development_transport(self.private_key.clone()).await?;
-            let behaviour = futures::executor::block_on(floodsub_behaviour(
-                self.peer_id.clone(),
+            let (relay_transport, relay_client) =
+                Client::new_transport_and_behaviour(self.peer_id);
+            let (transport, bandwidth) = config_transport(&self.private_key, relay_transport);
+            self.metrics.set_bandwidth(bandwidth);
+            let store = SledStore::open(self.peer_id, &self.dht.store_path)?;
+            let kademlia = Kademlia::with_config(self.peer_id, store, self.dht.kademlia_config());
+            let blocks = BlockStore::open(&self.blocks_dir)?;
+            let namespaces = if self.rendezvous_namespaces.is_empty() {
+                vec![topic.id().to_owned()]
+            } else {
+                self.rendezvous_namespaces.clone()
+            };
+            let rendezvous = Rendezvous::new(&self.rendezvous_points, &namespaces)?;
+            let options = BehaviourOptions {
+                topic: topic.clone(),
+                relay_client,
+                relay_server: self.relay_server,
+                rendezvous_server: self.rendezvous_server,
+                rendezvous,
+                kademlia,
+                transfers: self.transfers.clone(),
+                acks: self.acks,
+                max_order_delay: self.max_order_delay,
+                blocks,
+                events: self.events.clone(),
+                metrics: self.metrics.clone(),
+            };
+            let mut behaviour = futures::executor::block_on(floodsub_behaviour(
+                &self.private_key,
                 mdns,
-                topic.clone(),
+                options,
             ));
+            let protocols = supported_protocols(&mut behaviour);
             let swarm = futures::executor::block_on(swarm_config(
                 transport,
                 behaviour,
-                self.peer_id.clone(),
+                self.peer_id,
             ));
-            let topics = HashMap::from([("Communication".to_string(), topic)]);
+            let topics = HashMap::from([
+                ("Communication".to_string(), topic),
+                ("State".to_string(), state::state_topic()),
+            ]);
+            let (closed_sender, closed) = oneshot::channel();
+            self.closed = Some(closed);
             let mut event_loop = EventLoop {
                 swarm,
                 topics,
                 message_receiver,
+                command_receiver,
+                external_addrs: self.external_addrs.clone(),
+                outbox: self.outbox.clone(),
+                confirmed_addr: None,
+                connected: HashMap::new(),
+                listeners: HashMap::new(),
+                protocols,
+                started: Instant::now(),
+                watchers: Vec::new(),
+                published_version: 0,
+                _closed: closed_sender,
             };
+            // Relay reservations and rendezvous registrations hand out our
+            // external addresses, only these and what AutoNAT confirms are.
+            for addr in &self.external_addrs {
+                event_loop
+                    .swarm
+                    .add_external_address(addr.clone(), AddressScore::Infinite);
+            }
+            let rendezvous_client = event_loop.swarm.behaviour().rendezvous.is_client();
+            if rendezvous_client && self.external_addrs.is_empty() {
+                info!("no external address, rendezvous registration waits for AutoNAT");
+            }
             event_loop
                 .start_listen(self.host.clone(), self.port)
                 .await?;
-            for p in &self.peers {
+            for r in &self.relays {
+                event_loop.listen_via_relay(r.clone()).await?;
+            }
+            for p in self.peers.iter().chain(&self.rendezvous_points) {
                 event_loop.dial(p.clone()).await?;
             }
-            tokio::spawn(event_loop.run());
-            let mut message_sender = self.message_sender.as_ref().unwrap().clone();
-            tokio::spawn(async move {
-                let mut stdin = io::BufReader::new(io::stdin()).lines();
-                loop {
-                    tokio::select! {
-                    line = stdin.next_line() => {
-                    let line = format!(r#"{}"#,line.unwrap().unwrap());
-                    match message_sender.try_send(line.clone()){
-                        Ok(_) => {println!("Sent data: {}", line)},
-                        Err(_) => {
-                            println!("Retrying to send data");
-                            message_sender.try_send(line);}
-                    }
-                    },
-                    }
-                }
-            });
+            tokio::spawn(
+                event_loop
+                    .run()
+                    .instrument(info_span!("event_loop", peer_id = %self.peer_id)),
+            );
+            if !self.headless {
+                let message_sender = self.message_sender.as_ref().unwrap().clone();
+                let client = self.client().unwrap();
+                tokio::spawn(publish_stdin(message_sender, client));
+            }
             Ok(())
         }
     }
 }
 
+/// Resolves `path`, relative to the outbox or absolute, to a file inside
+/// the outbox. Links are followed first, so none lead out of it.
+fn outbox_file(outbox: &Path, path: &Path) -> Result<PathBuf, String> {
+    let outbox = outbox
+        .canonicalize()
+        .map_err(|err| format!("outbox {}: {}", outbox.display(), err))?;
+    let file = outbox
+        .join(path)
+        .canonicalize()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if file.starts_with(&outbox) {
+        Ok(file)
+    } else {
+        Err(format!(
+            "{}: not in the outbox {}",
+            path.display(),
+            outbox.display()
+        ))
+    }
+}
+
+/// Publishes every line typed on stdin. At the end of stdin the server is
+/// stopped, as there is nobody left to talk to it.
+async fn publish_stdin(mut message_sender: mpsc::Sender<ClientMessage>, mut client: P2PClient) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    loop {
+        let line = match stdin.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                info!("stdin closed, stopping");
+                break;
+            }
+            Err(err) => {
+                warn!(%err, "cannot read stdin, stopping");
+                break;
+            }
+        };
+        match message_sender.try_send(line.clone()) {
+            Ok(_) => {
+                debug!(%line, "sent data")
+            }
+            Err(_) => {
+                debug!("retrying to send data");
+                let _ = message_sender.try_send(line);
+            }
+        }
+    }
+    let _ = client.shutdown().await;
+}
+
 pub fn generate_identity() -> (identity::Keypair, PeerId) {
     let local_key: identity::Keypair = identity::Keypair::generate_ed25519();
     let local_peer_id: PeerId = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
+    info!(peer_id = %local_peer_id, "generated identity");
     (local_key, local_peer_id)
 }
 
+pub fn config_transport(
+    local_key: &identity::Keypair,
+    relay_transport: ClientTransport,
+) -> (Boxed<(PeerId, StreamMuxerBox)>, Arc<BandwidthSinks>) {
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(local_key)
+        .expect("Signing libp2p-noise static DH keypair failed.");
+    let transport = TokioTcpConfig::new().nodelay(true);
+    let (transport, bandwidth) =
+        BandwidthLogging::new(OrTransport::new(relay_transport, transport));
+    let transport = transport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(mplex::MplexConfig::new())
+        .boxed();
+    (transport, bandwidth)
+}
+
 pub async fn generate_mdns() -> Result<Mdns, Box<dyn Error>> {
     Ok(Mdns::new(Default::default()).await?)
 }
@@ -257,17 +1068,100 @@ pub fn generate_floodsub_topic(name: &str) -> floodsub::Topic {
     floodsub::Topic::new(name)
 }
 
+/// Everything the behaviour is built from besides our key and mDNS.
+pub struct BehaviourOptions {
+    /// The communication topic, subscribed to right away.
+    pub topic: floodsub::Topic,
+    pub relay_client: Client,
+    /// Act as a circuit relay v2 server for other peers.
+    pub relay_server: bool,
+    /// Act as a rendezvous point other peers register with.
+    pub rendezvous_server: bool,
+    pub rendezvous: Rendezvous,
+    pub kademlia: Kademlia<SledStore>,
+    pub transfers: ReceiveConfig,
+    /// Send acks for received messages to their publishers.
+    pub acks: bool,
+    pub max_order_delay: Duration,
+    pub blocks: BlockStore,
+    pub events: Events,
+    pub metrics: Arc<Metrics>,
+}
+
 pub async fn floodsub_behaviour(
-    local_peer_id: PeerId,
+    local_key: &identity::Keypair,
     mdns: Mdns,
-    topic: floodsub::Topic,
+    options: BehaviourOptions,
 ) -> P2PBehaviour {
+    let BehaviourOptions {
+        topic,
+        relay_client,
+        relay_server,
+        rendezvous_server,
+        rendezvous,
+        kademlia,
+        transfers,
+        acks,
+        max_order_delay,
+        blocks,
+        events,
+        metrics,
+    } = options;
+    let local_peer_id = PeerId::from(local_key.public());
+    let relay = relay_server.then(|| Relay::new(local_peer_id, Default::default()));
+    let rendezvous_server =
+        rendezvous_server.then(|| rendezvous::server::Behaviour::new(Default::default()));
     let mut behaviour = P2PBehaviour {
-        floodsub: Floodsub::new(local_peer_id.clone()),
+        floodsub: Floodsub::from_config(FloodsubConfig {
+            // Lets us log the id floodsub assigns to our own messages.
+            subscribe_local_messages: true,
+            ..FloodsubConfig::new(local_peer_id)
+        }),
         mdns,
+        relay_client,
+        relay: relay.into(),
+        rendezvous_client: rendezvous::client::Behaviour::new(local_key.clone()),
+        rendezvous_server: rendezvous_server.into(),
+        identify: Identify::new(IdentifyConfig::new(
+            "/p2p-node/0.1.0".into(),
+            local_key.public(),
+        )),
+        autonat: autonat::Behaviour::new(local_peer_id, Default::default()),
+        ping: ping::Behaviour::new(ping::Config::new()),
+        kademlia,
+        file_transfer: transfer::file_transfer(b"/p2p-node/file/1.0.0"),
+        ack_exchange: ack::ack_exchange(),
+        state_sync: state::state_sync(),
+        block_exchange: blocks::block_exchange(),
+        transfers: Transfers::new(transfers),
+        acks: Acks::new(local_key.clone(), acks),
+        causal: CausalOrder::new(&local_peer_id, max_order_delay),
+        state: SharedState::new(&local_peer_id),
+        dht_queries: DhtQueries::default(),
+        rendezvous,
+        blocks,
+        block_fetches: BlockFetches::default(),
+        subscribers: HashMap::new(),
+        subscribed: HashSet::new(),
+        events,
+        metrics,
+        local_peer_id,
     };
-    behaviour.floodsub.subscribe(topic.clone());
+    behaviour.subscribe(topic);
+    behaviour.subscribe(state::state_topic());
+    behaviour.provide_blocks();
+    behaviour
+}
+
+/// The protocols the swarm will accept substreams for, worked out the same
+/// way it does.
+fn supported_protocols(behaviour: &mut P2PBehaviour) -> Vec<String> {
     behaviour
+        .new_handler()
+        .inbound_protocol()
+        .protocol_info()
+        .map(|info| String::from_utf8_lossy(info.protocol_name()).into_owned())
+        .collect()
 }
 
 pub async fn swarm_config(
@@ -0,0 +1,165 @@
+use libp2p::{
+    multiaddr::Protocol,
+    rendezvous::{Cookie, Namespace, Ttl},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Registrations are renewed once less than this is left of them.
+const RENEW_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+/// Longest registration the rendezvous spec allows, a point answering with
+/// more is not believed.
+const MAX_TTL: Ttl = 72 * 60 * 60;
+
+/// The rendezvous points a node registers with and the namespaces it
+/// registers under, which are also the ones it discovers peers in.
+pub struct Rendezvous {
+    /// Rendezvous points by peer id, with the address to dial them at.
+    points: HashMap<PeerId, Multiaddr>,
+    namespaces: Vec<Namespace>,
+    /// When each registration, by point and namespace, runs out.
+    registrations: HashMap<(PeerId, Namespace), Instant>,
+    /// Cookies of the last discovery at each point, so the next one only
+    /// returns what changed since.
+    cookies: HashMap<(PeerId, Option<Namespace>), Cookie>,
+}
+
+impl Rendezvous {
+    /// Every point address has to end in `/p2p/<peer id>`, registering is
+    /// done with a known peer.
+    pub fn new(points: &[Multiaddr], namespaces: &[String]) -> Result<Self, String> {
+        let points = points
+            .iter()
+            .map(|addr| match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+                    .map(|peer| (peer, addr.clone()))
+                    .map_err(|_| format!("{}: invalid peer id", addr)),
+                _ => Err(format!("{}: rendezvous point without /p2p/<peer id>", addr)),
+            })
+            .collect::<Result<_, _>>()?;
+        let namespaces = namespaces
+            .iter()
+            .map(|ns| Namespace::new(ns.clone()).map_err(|_| format!("{}: namespace too long", ns)))
+            .collect::<Result<_, _>>()?;
+        Ok(Rendezvous {
+            points,
+            namespaces,
+            registrations: HashMap::new(),
+            cookies: HashMap::new(),
+        })
+    }
+
+    /// Whether the node registers with any rendezvous point.
+    pub fn is_client(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    pub fn is_point(&self, peer: &PeerId) -> bool {
+        self.points.contains_key(peer)
+    }
+
+    pub fn points(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.points
+            .iter()
+            .map(|(peer, addr)| (*peer, addr.clone()))
+            .collect()
+    }
+
+    pub fn namespaces(&self) -> &[Namespace] {
+        &self.namespaces
+    }
+
+    /// Forgets the registrations at a point we just (re)connected to, it
+    /// may have restarted since.
+    pub fn connected(&mut self, point: &PeerId) {
+        self.registrations.retain(|(peer, _), _| peer != point);
+    }
+
+    /// Namespaces not registered at `point`, or about to run out there.
+    pub fn due(&self, point: &PeerId) -> Vec<Namespace> {
+        let renew_at = Instant::now() + RENEW_MARGIN;
+        self.namespaces
+            .iter()
+            .filter(|ns| {
+                self.registrations
+                    .get(&(*point, (*ns).clone()))
+                    .is_none_or(|until| *until <= renew_at)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Records a registration the point confirmed for `ttl` seconds.
+    pub fn registered(&mut self, point: PeerId, namespace: Namespace, ttl: Ttl) {
+        let until = Instant::now() + Duration::from_secs(ttl.min(MAX_TTL));
+        self.registrations.insert((point, namespace), until);
+    }
+
+    pub fn cookie(&self, point: PeerId, namespace: &Namespace) -> Option<Cookie> {
+        self.cookies.get(&(point, Some(namespace.clone()))).cloned()
+    }
+
+    pub fn discovered(&mut self, point: PeerId, cookie: Cookie) {
+        self.cookies
+            .insert((point, cookie.namespace().cloned()), cookie);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendezvous() -> (Rendezvous, PeerId) {
+        let point = PeerId::random();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/8500/p2p/{}", point)
+            .parse()
+            .unwrap();
+        let namespaces = ["chat".to_owned(), "files".to_owned()];
+        (Rendezvous::new(&[addr], &namespaces).unwrap(), point)
+    }
+
+    fn names(namespaces: Vec<Namespace>) -> Vec<String> {
+        namespaces.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn points_need_a_peer_id() {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/8500".parse().unwrap();
+        assert!(Rendezvous::new(&[addr], &[]).is_err());
+    }
+
+    #[test]
+    fn unregistered_and_expiring_namespaces_are_due() {
+        let (mut rendezvous, point) = rendezvous();
+        assert_eq!(names(rendezvous.due(&point)), ["chat", "files"]);
+        rendezvous.registered(point, Namespace::from_static("chat"), 2 * 60 * 60);
+        assert_eq!(names(rendezvous.due(&point)), ["files"]);
+        // Less than the renew margin left.
+        rendezvous.registered(point, Namespace::from_static("chat"), 60);
+        assert_eq!(names(rendezvous.due(&point)), ["chat", "files"]);
+    }
+
+    #[test]
+    fn huge_ttl_is_clamped() {
+        let (mut rendezvous, point) = rendezvous();
+        rendezvous.registered(point, Namespace::from_static("chat"), Ttl::MAX);
+        let until = rendezvous.registrations[&(point, Namespace::from_static("chat"))];
+        assert!(until <= Instant::now() + Duration::from_secs(MAX_TTL));
+        assert_eq!(names(rendezvous.due(&point)), ["files"]);
+    }
+
+    #[test]
+    fn reconnecting_forgets_registrations() {
+        let (mut rendezvous, point) = rendezvous();
+        let other = PeerId::random();
+        rendezvous.registered(point, Namespace::from_static("chat"), 2 * 60 * 60);
+        rendezvous.registered(other, Namespace::from_static("chat"), 2 * 60 * 60);
+        rendezvous.connected(&point);
+        assert_eq!(names(rendezvous.due(&point)), ["chat", "files"]);
+        assert_eq!(names(rendezvous.due(&other)), ["files"]);
+    }
+}
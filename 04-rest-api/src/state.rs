@@ -0,0 +1,343 @@
+use crate::codec::{read_json, write_json};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::ProtocolName,
+    floodsub::Topic,
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, io, iter};
+
+/// Every node sharing state subscribes to this topic, deltas are
+/// published on it.
+pub fn state_topic() -> Topic {
+    Topic::new("p2p-node-state")
+}
+
+/// Lamport counter of a write and the peer that made it. Ties on the
+/// counter go to the larger peer id, so every node picks the same winner.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Stamp {
+    pub counter: u64,
+    pub peer: String,
+}
+
+/// The value of one key, as last written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub value: Value,
+    pub stamp: Stamp,
+}
+
+/// Published on the state topic after every local write.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Delta {
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// Anti-entropy between two neighbours: the asking side sends what it has,
+/// the other side answers with what it is missing and asks for what it is
+/// missing itself, which is then pushed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateRequest {
+    Sync { digest: BTreeMap<String, Stamp> },
+    Push { entries: BTreeMap<String, Entry> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateResponse {
+    Sync {
+        entries: BTreeMap<String, Entry>,
+        wanted: Vec<String>,
+    },
+    Merged,
+}
+
+/// One row of `state_watch`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    pub key: String,
+    pub value: Value,
+    pub peer: String,
+    pub counter: u64,
+    /// Local version the change was applied at.
+    pub version: u64,
+}
+
+/// Answer to `state_watch`: the changes after the version asked for, and
+/// the version to ask for next time.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Changes {
+    pub version: u64,
+    pub changes: Vec<Change>,
+}
+
+impl Changes {
+    pub fn none(version: u64) -> Self {
+        Changes {
+            version,
+            changes: Vec::new(),
+        }
+    }
+}
+
+/// A map replicated between nodes, where the last write to a key wins.
+/// Merging is commutative and idempotent, so deltas can arrive in any
+/// order and any number of times.
+pub struct SharedState {
+    local: String,
+    /// Entries with the local version they were applied at.
+    entries: BTreeMap<String, (Entry, u64)>,
+    /// Highest Lamport counter seen.
+    counter: u64,
+    /// Bumped for every applied change, only meaningful on this node.
+    version: u64,
+}
+
+impl SharedState {
+    pub fn new(local_peer_id: &PeerId) -> Self {
+        SharedState {
+            local: local_peer_id.to_base58(),
+            entries: BTreeMap::new(),
+            counter: 0,
+            version: 0,
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.get(key).map(|(entry, _)| entry)
+    }
+
+    /// Every key with its value.
+    pub fn values(&self) -> BTreeMap<String, Value> {
+        self.entries
+            .iter()
+            .map(|(key, (entry, _))| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    /// Writes `value` locally and returns the delta to publish.
+    pub fn set(&mut self, key: String, value: Value) -> Delta {
+        self.counter += 1;
+        let entry = Entry {
+            value,
+            stamp: Stamp {
+                counter: self.counter,
+                peer: self.local.clone(),
+            },
+        };
+        self.version += 1;
+        self.entries
+            .insert(key.clone(), (entry.clone(), self.version));
+        Delta {
+            entries: BTreeMap::from([(key, entry)]),
+        }
+    }
+
+    /// Applies whatever in `entries` is newer than what we have, returns
+    /// how many were.
+    pub fn merge(&mut self, entries: BTreeMap<String, Entry>) -> usize {
+        let mut applied = 0;
+        for (key, entry) in entries {
+            self.counter = self.counter.max(entry.stamp.counter);
+            let newer = self.get(&key).is_none_or(|known| entry.stamp > known.stamp);
+            if newer {
+                self.version += 1;
+                self.entries.insert(key, (entry, self.version));
+                applied += 1;
+            }
+        }
+        applied
+    }
+
+    pub fn digest(&self) -> BTreeMap<String, Stamp> {
+        self.entries
+            .iter()
+            .map(|(key, (entry, _))| (key.clone(), entry.stamp.clone()))
+            .collect()
+    }
+
+    /// Compares a neighbour's digest with ours: the entries it lacks or has
+    /// older, and the keys where it is ahead of us.
+    pub fn diff(&self, digest: &BTreeMap<String, Stamp>) -> (BTreeMap<String, Entry>, Vec<String>) {
+        let mut missing = BTreeMap::new();
+        for (key, (entry, _)) in &self.entries {
+            if digest.get(key).is_none_or(|stamp| entry.stamp > *stamp) {
+                missing.insert(key.clone(), entry.clone());
+            }
+        }
+        let mut wanted = Vec::new();
+        for (key, stamp) in digest {
+            if self.get(key).is_none_or(|entry| *stamp > entry.stamp) {
+                wanted.push(key.clone());
+            }
+        }
+        (missing, wanted)
+    }
+
+    /// The entries for `keys` that we have.
+    pub fn pick(&self, keys: &[String]) -> BTreeMap<String, Entry> {
+        keys.iter()
+            .filter_map(|key| Some((key.clone(), self.get(key)?.clone())))
+            .collect()
+    }
+
+    /// Changes to keys under `prefix` applied after version `since`.
+    pub fn changes(&self, since: u64, prefix: &str) -> Changes {
+        let changes = self
+            .entries
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, (_, version))| *version > since)
+            .map(|(key, (entry, version))| Change {
+                key: key.clone(),
+                value: entry.value.clone(),
+                peer: entry.stamp.peer.clone(),
+                counter: entry.stamp.counter,
+                version: *version,
+            })
+            .collect();
+        Changes {
+            version: self.version,
+            changes,
+        }
+    }
+}
+
+/// Request-response behaviour for anti-entropy rounds.
+pub type StateSync = RequestResponse<StateCodec>;
+
+pub fn state_sync() -> StateSync {
+    RequestResponse::new(
+        StateCodec,
+        iter::once((StateProtocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct StateProtocol;
+
+impl ProtocolName for StateProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-node/state/1.0.0"
+    }
+}
+
+#[derive(Clone)]
+pub struct StateCodec;
+
+#[async_trait]
+impl RequestResponseCodec for StateCodec {
+    type Protocol = StateProtocol;
+    type Request = StateRequest;
+    type Response = StateResponse;
+
+    async fn read_request<T>(&mut self, _: &StateProtocol, io: &mut T) -> io::Result<StateRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StateProtocol, io: &mut T) -> io::Result<StateResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StateProtocol,
+        io: &mut T,
+        request: StateRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StateProtocol,
+        io: &mut T,
+        response: StateResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(value: Value, counter: u64, peer: &str) -> BTreeMap<String, Entry> {
+        let stamp = Stamp {
+            counter,
+            peer: peer.to_owned(),
+        };
+        BTreeMap::from([("key".to_owned(), Entry { value, stamp })])
+    }
+
+    #[test]
+    fn merge_order_does_not_matter() {
+        let deltas = [
+            entry(json!("a"), 1, "peer-a"),
+            entry(json!("b"), 2, "peer-a"),
+            entry(json!("c"), 2, "peer-b"),
+            entry(json!("d"), 1, "peer-c"),
+        ];
+        let mut forward = SharedState::new(&PeerId::random());
+        let mut backward = SharedState::new(&PeerId::random());
+        for delta in &deltas {
+            forward.merge(delta.clone());
+        }
+        for delta in deltas.iter().rev() {
+            backward.merge(delta.clone());
+        }
+        assert_eq!(forward.values(), backward.values());
+        assert_eq!(forward.digest(), backward.digest());
+        // The counter tie goes to the larger peer id.
+        assert_eq!(forward.values()["key"], json!("c"));
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        let mut state = SharedState::new(&PeerId::random());
+        assert_eq!(state.merge(entry(json!(1), 3, "peer-a")), 1);
+        assert_eq!(state.merge(entry(json!(1), 3, "peer-a")), 0);
+        assert_eq!(state.version(), 1);
+    }
+
+    #[test]
+    fn local_write_wins_over_merged_history() {
+        let mut state = SharedState::new(&PeerId::random());
+        state.merge(entry(json!("remote"), 7, "peer-a"));
+        let delta = state.set("key".into(), json!("local"));
+        assert_eq!(delta.entries["key"].stamp.counter, 8);
+
+        let mut other = SharedState::new(&PeerId::random());
+        other.merge(delta.entries);
+        other.merge(entry(json!("remote"), 7, "peer-a"));
+        assert_eq!(other.values()["key"], json!("local"));
+    }
+}
@@ -0,0 +1,251 @@
+use serde_json::{json, Map, Value};
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{
+    fmt as tracing_fmt,
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    /// Readable lines on stdout.
+    Human,
+    /// One JSON object per line on stdout, including the enclosing spans.
+    Json,
+    /// Human output plus every finished span written to a file as OTLP/JSON.
+    OtlpFile,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "otlp-file" => Ok(Self::OtlpFile),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Human => write!(f, "human"),
+            Self::Json => write!(f, "json"),
+            Self::OtlpFile => write!(f, "otlp-file"),
+        }
+    }
+}
+
+/// Installs the global subscriber. Filtering follows `RUST_LOG` and
+/// defaults to `info`; `log` records from libp2p are forwarded as well.
+pub fn init(format: LogFormat, otlp_file: &Path) -> Result<(), Box<dyn Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Human => registry.with(tracing_fmt::layer()).try_init()?,
+        LogFormat::Json => registry
+            .with(
+                tracing_fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .try_init()?,
+        LogFormat::OtlpFile => registry
+            .with(tracing_fmt::layer())
+            .with(OtlpFileLayer::create(otlp_file)?)
+            .try_init()?,
+    }
+    Ok(())
+}
+
+/// Writes each closed span as an OTLP/JSON `ExportTraceServiceRequest`,
+/// one per line, so the file can be replayed into any OTLP collector.
+struct OtlpFileLayer {
+    file: Mutex<LineWriter<File>>,
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: u128,
+    attributes: Map<String, Value>,
+    events: Vec<Value>,
+}
+
+impl OtlpFileLayer {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(OtlpFileLayer {
+            file: Mutex::new(LineWriter::new(File::create(path)?)),
+        })
+    }
+}
+
+impl<S> Layer<S> for OtlpFileLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+        let mut data = SpanData {
+            trace_id: parent.map_or_else(rand::random, |(trace_id, _)| trace_id),
+            span_id: rand::random(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            start: now(),
+            attributes: Map::new(),
+            events: Vec::new(),
+        };
+        attrs.record(&mut JsonVisitor(&mut data.attributes));
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut JsonVisitor(&mut data.attributes));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let span = match ctx.event_span(event) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let name = match fields.remove("message") {
+            Some(Value::String(message)) => message,
+            _ => event.metadata().name().to_owned(),
+        };
+        fields.insert("level".into(), event.metadata().level().to_string().into());
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            data.events.push(json!({
+                "timeUnixNano": now().to_string(),
+                "name": name,
+                "attributes": otlp_attributes(fields),
+            }));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let data = match ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<SpanData>())
+        {
+            Some(data) => data,
+            None => return,
+        };
+        let mut span = json!({
+            "traceId": hex(&data.trace_id),
+            "spanId": hex(&data.span_id),
+            "name": data.name,
+            "kind": 1,
+            "startTimeUnixNano": data.start.to_string(),
+            "endTimeUnixNano": now().to_string(),
+            "attributes": otlp_attributes(data.attributes),
+            "events": data.events,
+        });
+        if let Some(parent) = data.parent_span_id {
+            span["parentSpanId"] = hex(&parent).into();
+        }
+        let service = Map::from_iter([(
+            "service.name".to_owned(),
+            Value::from(env!("CARGO_PKG_NAME")),
+        )]);
+        let request = json!({
+            "resourceSpans": [{
+                "resource": { "attributes": otlp_attributes(service) },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME") },
+                    "spans": [span],
+                }],
+            }],
+        });
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(file, "{}", request);
+    }
+}
+
+/// Collects span and event fields as JSON values.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
+
+fn otlp_attributes(fields: Map<String, Value>) -> Vec<Value> {
+    fields
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Bool(b) => json!({ "boolValue": b }),
+                Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+                Value::Number(n) => json!({ "intValue": n.to_string() }),
+                Value::String(s) => json!({ "stringValue": s }),
+                other => json!({ "stringValue": other.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
@@ -0,0 +1,36 @@
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+
+/// Largest request or response we are willing to read.
+const MAX_SIZE: usize = 1024 * 1024;
+
+/// Reads one length prefixed JSON message, as used by our request-response
+/// codecs.
+pub async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    read_json_max(io, MAX_SIZE).await
+}
+
+/// Like `read_json`, for protocols whose messages may be larger.
+pub async fn read_json_max<T, M>(io: &mut T, max_size: usize) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let data = read_length_prefixed(io, max_size).await?;
+    serde_json::from_slice(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub async fn write_json<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let data = serde_json::to_vec(message)?;
+    write_length_prefixed(io, data).await
+}
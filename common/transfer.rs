@@ -0,0 +1,811 @@
+use crate::codec::{read_json, write_json};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade::ProtocolName,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    iter,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Files travel in chunks of this size, each fetched on its own substream.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Largest file accepted unless configured otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// How many numbered names are tried when a received file's name is taken.
+const MAX_RENAMES: u32 = 1000;
+
+/// Which offers are accepted and where received files go.
+#[derive(Debug, Clone)]
+pub struct ReceiveConfig {
+    pub download_dir: PathBuf,
+    /// Senders whose offers are accepted, nobody's when empty.
+    pub accept_from: Vec<AcceptFrom>,
+    /// Largest file accepted, in bytes.
+    pub max_size: u64,
+}
+
+impl Default for ReceiveConfig {
+    fn default() -> Self {
+        ReceiveConfig {
+            download_dir: "downloads".into(),
+            accept_from: Vec::new(),
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+/// A peer files are accepted from, `*` on the command line for anyone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcceptFrom {
+    Anyone,
+    Peer(PeerId),
+}
+
+impl FromStr for AcceptFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "*" => Ok(AcceptFrom::Anyone),
+            peer => peer
+                .parse()
+                .map(AcceptFrom::Peer)
+                .map_err(|_| format!("{}: expected a peer id or *", peer)),
+        }
+    }
+}
+
+/// What a sender announces before any data moves. The id is the SHA-256 of
+/// the whole file, so offering the same file again resumes the transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 of every chunk, in order.
+    pub chunks: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileRequest {
+    Offer(Offer),
+    /// The receiver pulls chunks one by one.
+    Chunk {
+        id: String,
+        index: u64,
+    },
+}
+
+impl FileRequest {
+    pub fn transfer_id(&self) -> &str {
+        match self {
+            FileRequest::Offer(offer) => &offer.id,
+            FileRequest::Chunk { id, .. } => id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileResponse {
+    Accepted,
+    Chunk { data: Vec<u8> },
+    Refused { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Active,
+    /// The peer went away, the transfer picks up when it is back.
+    Stalled,
+    Done,
+    Failed(String),
+}
+
+/// A row of `/transfers` in the chat and of `p2p_transfers` over RPC.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferInfo {
+    pub id: String,
+    pub name: String,
+    pub peer: String,
+    pub direction: Direction,
+    pub size: u64,
+    pub bytes: u64,
+    pub status: Status,
+}
+
+impl fmt::Display for TransferInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Sending => "->",
+            Direction::Receiving => "<-",
+        };
+        write!(
+            f,
+            "{} {} {} {}/{} bytes {:?}",
+            self.name, arrow, self.peer, self.bytes, self.size, self.status
+        )
+    }
+}
+
+struct Outgoing {
+    path: PathBuf,
+    offer: Offer,
+    /// Bytes served to each peer we offered the file to.
+    peers: HashMap<PeerId, (u64, Status)>,
+}
+
+struct Incoming {
+    peer: PeerId,
+    offer: Offer,
+    /// First chunk we do not have yet.
+    next: u64,
+    status: Status,
+}
+
+/// Sent and received files. Nothing here touches the network, callers send
+/// the returned requests and feed responses back in.
+pub struct Transfers {
+    config: ReceiveConfig,
+    outgoing: HashMap<String, Outgoing>,
+    incoming: HashMap<String, Incoming>,
+    /// Which transfer an outbound request belongs to.
+    requests: HashMap<RequestId, String>,
+}
+
+impl Transfers {
+    pub fn new(config: ReceiveConfig) -> Self {
+        Transfers {
+            config,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Hashes `path` and remembers it so `peer` can fetch it.
+    pub fn offer(&mut self, peer: PeerId, path: &Path) -> io::Result<Offer> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?
+            .to_owned();
+        let mut file = File::open(path)?;
+        let mut whole = Sha256::new();
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        let mut buffer = vec![0; CHUNK_SIZE as usize];
+        loop {
+            let read = read_chunk(&mut file, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            whole.update(&buffer[..read]);
+            chunks.push(hash(&buffer[..read]));
+            size += read as u64;
+        }
+        let offer = Offer {
+            id: format!("{:x}", whole.finalize()),
+            name,
+            size,
+            chunks,
+        };
+        let outgoing = self
+            .outgoing
+            .entry(offer.id.clone())
+            .or_insert_with(|| Outgoing {
+                path: path.to_owned(),
+                offer: offer.clone(),
+                peers: HashMap::new(),
+            });
+        outgoing.peers.insert(peer, (0, Status::Active));
+        Ok(offer)
+    }
+
+    /// Remembers which transfer an outbound request was about, so its
+    /// response or failure can be matched up.
+    pub fn track(&mut self, request_id: RequestId, id: String) {
+        self.requests.insert(request_id, id);
+    }
+
+    /// A peer offers us a file. Accepts it into the download directory if
+    /// the sender may send files and the file is not too large, and returns
+    /// the first chunk to fetch, skipping whatever an earlier attempt left
+    /// in the partial file. When nothing is left to fetch the file is
+    /// finished right away and the outcome returned instead.
+    pub fn receive_offer(
+        &mut self,
+        peer: PeerId,
+        offer: Offer,
+    ) -> (FileResponse, Result<FileRequest, Option<String>>) {
+        if !is_plain_name(&offer.name)
+            || !is_hash(&offer.id)
+            || offer.chunks.len() as u64 != chunk_count(offer.size)
+        {
+            let reason = "malformed offer".to_owned();
+            return (FileResponse::Refused { reason }, Err(None));
+        }
+        if !self.accepts_from(&peer) {
+            let reason = "not accepting files from you".to_owned();
+            return (FileResponse::Refused { reason }, Err(None));
+        }
+        if offer.size > self.config.max_size {
+            let reason = format!("larger than {} bytes", self.config.max_size);
+            return (FileResponse::Refused { reason }, Err(None));
+        }
+        if let Err(err) = fs::create_dir_all(&self.config.download_dir) {
+            let reason = err.to_string();
+            return (FileResponse::Refused { reason }, Err(None));
+        }
+        let next = match self.incoming.get(&offer.id) {
+            Some(incoming) if incoming.status == Status::Done => {
+                let reason = "already received".to_owned();
+                return (FileResponse::Refused { reason }, Err(None));
+            }
+            Some(incoming) => incoming.next,
+            None => self.valid_prefix(&offer),
+        };
+        let id = offer.id.clone();
+        self.incoming.insert(
+            id.clone(),
+            Incoming {
+                peer,
+                offer,
+                next,
+                status: Status::Active,
+            },
+        );
+        match self.next_request(&id) {
+            Some(request) => (FileResponse::Accepted, Ok(request)),
+            None => (FileResponse::Accepted, Err(self.finish(&id))),
+        }
+    }
+
+    /// Reads chunk `index` of a file we offered to `peer`.
+    pub fn serve_chunk(&mut self, peer: PeerId, id: &str, index: u64) -> FileResponse {
+        let outgoing = match self.outgoing.get_mut(id) {
+            Some(outgoing) if outgoing.peers.contains_key(&peer) => outgoing,
+            _ => {
+                let reason = "no such offer".to_owned();
+                return FileResponse::Refused { reason };
+            }
+        };
+        let mut data = vec![0; CHUNK_SIZE as usize];
+        let read = File::open(&outgoing.path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+            read_chunk(&mut file, &mut data)
+        });
+        match read {
+            Ok(read) if outgoing.offer.chunks.get(index as usize) == Some(&hash(&data[..read])) => {
+                data.truncate(read);
+                let sent = (index * CHUNK_SIZE + read as u64).min(outgoing.offer.size);
+                let status = if sent == outgoing.offer.size {
+                    Status::Done
+                } else {
+                    Status::Active
+                };
+                outgoing.peers.insert(peer, (sent, status));
+                FileResponse::Chunk { data }
+            }
+            Ok(_) => FileResponse::Refused {
+                reason: "file changed since it was offered".to_owned(),
+            },
+            Err(err) => FileResponse::Refused {
+                reason: err.to_string(),
+            },
+        }
+    }
+
+    /// Handles the answer to one of our requests. Returns the next chunk to
+    /// fetch, if any, and a line worth showing the user.
+    pub fn receive_response(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        response: FileResponse,
+    ) -> (Option<FileRequest>, Option<String>) {
+        let id = match self.requests.remove(&request_id) {
+            Some(id) => id,
+            None => return (None, None),
+        };
+        match response {
+            FileResponse::Accepted => {
+                let name = self.outgoing.get(&id).map(|o| o.offer.name.clone());
+                let line = name.map(|name| format!("{} accepted {}", peer, name));
+                (None, line)
+            }
+            FileResponse::Refused { reason } => {
+                let name = match self.incoming.get_mut(&id) {
+                    Some(incoming) => {
+                        incoming.status = Status::Failed(reason.clone());
+                        incoming.offer.name.clone()
+                    }
+                    None => match self.outgoing.get_mut(&id) {
+                        Some(outgoing) => {
+                            outgoing
+                                .peers
+                                .insert(peer, (0, Status::Failed(reason.clone())));
+                            outgoing.offer.name.clone()
+                        }
+                        None => return (None, None),
+                    },
+                };
+                (None, Some(format!("{} refused {}: {}", peer, name, reason)))
+            }
+            FileResponse::Chunk { data } => match self.write_chunk(&id, &data) {
+                Ok(true) => (self.next_request(&id), None),
+                Ok(false) => (None, self.finish(&id)),
+                Err(reason) => {
+                    if let Some(incoming) = self.incoming.get_mut(&id) {
+                        incoming.status = Status::Failed(reason.clone());
+                    }
+                    (None, Some(reason))
+                }
+            },
+        }
+    }
+
+    /// An outbound request got no answer, the transfer waits for the peer.
+    pub fn request_failed(&mut self, request_id: RequestId) -> Option<String> {
+        let id = self.requests.remove(&request_id)?;
+        let incoming = self.incoming.get_mut(&id)?;
+        incoming.status = Status::Stalled;
+        Some(format!(
+            "{} stalled at {} of {} bytes, it resumes when {} is back",
+            incoming.offer.name,
+            (incoming.next * CHUNK_SIZE).min(incoming.offer.size),
+            incoming.offer.size,
+            incoming.peer
+        ))
+    }
+
+    /// Requests to restart the stalled downloads from `peer`.
+    pub fn resume(&mut self, peer: PeerId) -> Vec<FileRequest> {
+        let ids: Vec<_> = self
+            .incoming
+            .iter()
+            .filter(|(_, incoming)| incoming.peer == peer && incoming.status == Status::Stalled)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut requests = Vec::new();
+        for id in ids {
+            if let Some(incoming) = self.incoming.get_mut(&id) {
+                incoming.status = Status::Active;
+            }
+            requests.extend(self.next_request(&id));
+        }
+        requests
+    }
+
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let mut list: Vec<_> = self
+            .incoming
+            .iter()
+            .map(|(id, incoming)| TransferInfo {
+                id: id.clone(),
+                name: incoming.offer.name.clone(),
+                peer: incoming.peer.to_base58(),
+                direction: Direction::Receiving,
+                size: incoming.offer.size,
+                bytes: (incoming.next * CHUNK_SIZE).min(incoming.offer.size),
+                status: incoming.status.clone(),
+            })
+            .collect();
+        for (id, outgoing) in &self.outgoing {
+            for (peer, (bytes, status)) in &outgoing.peers {
+                list.push(TransferInfo {
+                    id: id.clone(),
+                    name: outgoing.offer.name.clone(),
+                    peer: peer.to_base58(),
+                    direction: Direction::Sending,
+                    size: outgoing.offer.size,
+                    bytes: *bytes,
+                    status: status.clone(),
+                });
+            }
+        }
+        list.sort_by(|a, b| a.name.cmp(&b.name).then(a.peer.cmp(&b.peer)));
+        list
+    }
+
+    fn next_request(&self, id: &str) -> Option<FileRequest> {
+        let incoming = self.incoming.get(id)?;
+        if incoming.next < incoming.offer.chunks.len() as u64 {
+            Some(FileRequest::Chunk {
+                id: id.to_owned(),
+                index: incoming.next,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Checks and appends the next chunk, returns whether more are due.
+    fn write_chunk(&mut self, id: &str, data: &[u8]) -> Result<bool, String> {
+        let path = self.part_path(id).ok_or("unknown transfer")?;
+        let incoming = self.incoming.get_mut(id).ok_or("unknown transfer")?;
+        let index = incoming.next;
+        if incoming.offer.chunks.get(index as usize) != Some(&hash(data)) {
+            return Err(format!(
+                "chunk {} of {} is corrupt",
+                index, incoming.offer.name
+            ));
+        }
+        let write = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+                file.write_all(data)
+            });
+        write.map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+        incoming.next += 1;
+        Ok(incoming.next < incoming.offer.chunks.len() as u64)
+    }
+
+    fn accepts_from(&self, peer: &PeerId) -> bool {
+        self.config
+            .accept_from
+            .iter()
+            .any(|from| *from == AcceptFrom::Anyone || *from == AcceptFrom::Peer(*peer))
+    }
+
+    /// Verifies the whole file and moves it out of its partial name, to a
+    /// numbered name if the offered one is taken.
+    fn finish(&mut self, id: &str) -> Option<String> {
+        let part = self.part_path(id)?;
+        let download_dir = self.config.download_dir.clone();
+        let incoming = self.incoming.get_mut(id)?;
+        if incoming.status == Status::Done {
+            return None;
+        }
+        let verified = if incoming.offer.size == 0 {
+            File::create(&part).map(|_| true)
+        } else {
+            file_hash(&part).map(|hash| hash == incoming.offer.id)
+        };
+        let result = match verified {
+            Ok(true) => save(&part, &download_dir, &incoming.offer.name),
+            Ok(false) => {
+                // Start over rather than keep bytes that do not add up.
+                let _ = fs::remove_file(&part);
+                incoming.next = 0;
+                Err("whole-file hash mismatch".to_owned())
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        Some(match result {
+            Ok(target) => {
+                incoming.status = Status::Done;
+                format!(
+                    "saved {} ({} bytes, sha256 ok)",
+                    target.display(),
+                    incoming.offer.size
+                )
+            }
+            Err(reason) => {
+                incoming.status = Status::Failed(reason.clone());
+                format!("{} failed: {}", incoming.offer.name, reason)
+            }
+        })
+    }
+
+    /// Number of leading chunks an earlier attempt already got right.
+    fn valid_prefix(&self, offer: &Offer) -> u64 {
+        let path = self.config.download_dir.join(format!("{}.part", offer.id));
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return 0,
+        };
+        let mut buffer = vec![0; CHUNK_SIZE as usize];
+        let mut valid = 0;
+        for expected in &offer.chunks {
+            match read_chunk(&mut file, &mut buffer) {
+                Ok(read) if read > 0 && hash(&buffer[..read]) == *expected => valid += 1,
+                _ => break,
+            }
+        }
+        valid
+    }
+
+    /// Named after the file's hash rather than its name, so it cannot
+    /// clash with a file already in the download directory.
+    fn part_path(&self, id: &str) -> Option<PathBuf> {
+        self.incoming.get(id)?;
+        Some(self.config.download_dir.join(format!("{}.part", id)))
+    }
+}
+
+/// Moves `part` to `name` in `dir`, or to `name (1)`, `name (2)` and so on
+/// if that is taken. Linking fails rather than replace an existing file,
+/// unlike renaming.
+fn save(part: &Path, dir: &Path, name: &str) -> Result<PathBuf, String> {
+    for n in 0..MAX_RENAMES {
+        let target = dir.join(numbered(name, n));
+        match fs::hard_link(part, &target) {
+            Ok(()) => {
+                let _ = fs::remove_file(part);
+                return Ok(target);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Err(format!("{} and its numbered names are taken", name))
+}
+
+/// `name` for 0, otherwise `stem (n).ext`.
+fn numbered(name: &str, n: u32) -> String {
+    if n == 0 {
+        return name.to_owned();
+    }
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{} ({}).{}", stem, n, ext),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE)
+}
+
+/// Offered names end up in the download directory, they must not point
+/// anywhere else.
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+/// Offer ids name partial files, so they have to be what `offer` makes.
+fn is_hash(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Fills `buffer` as far as the file allows.
+fn read_chunk(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn file_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Request-response behaviour moving offers and chunks, every request on
+/// its own substream.
+pub type FileTransfer = RequestResponse<FileCodec>;
+
+/// Each application speaks the protocol under its own name, e.g.
+/// `/p2p-node/file/1.0.0`.
+pub fn file_transfer(protocol: &'static [u8]) -> FileTransfer {
+    RequestResponse::new(
+        FileCodec,
+        iter::once((FileProtocol(protocol), ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct FileProtocol(&'static [u8]);
+
+impl ProtocolName for FileProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        self.0
+    }
+}
+
+#[derive(Clone)]
+pub struct FileCodec;
+
+#[async_trait]
+impl RequestResponseCodec for FileCodec {
+    type Protocol = FileProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(&mut self, _: &FileProtocol, io: &mut T) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &FileProtocol, io: &mut T) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileProtocol,
+        io: &mut T,
+        request: FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileProtocol,
+        io: &mut T,
+        response: FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("transfer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path, accept_from: Vec<AcceptFrom>) -> ReceiveConfig {
+        ReceiveConfig {
+            download_dir: dir.join("downloads"),
+            accept_from,
+            max_size: 1024,
+        }
+    }
+
+    /// Offers `data` as `notes.txt` to `receiver`.
+    fn offer(dir: &Path, data: &[u8], receiver: PeerId) -> (Transfers, Offer) {
+        let path = dir.join("notes.txt");
+        fs::write(&path, data).unwrap();
+        let mut sender = Transfers::new(ReceiveConfig::default());
+        let offer = sender.offer(receiver, &path).unwrap();
+        (sender, offer)
+    }
+
+    fn refusal(response: FileResponse) -> String {
+        match response {
+            FileResponse::Refused { reason } => reason,
+            response => panic!("expected a refusal, got {:?}", response),
+        }
+    }
+
+    /// Takes an offer through to the saved file, returns the outcome line.
+    fn receive(
+        from: PeerId,
+        sender: &mut Transfers,
+        to: PeerId,
+        receiver: &mut Transfers,
+        offer: Offer,
+    ) -> String {
+        let (response, mut next) = receiver.receive_offer(from, offer);
+        assert!(matches!(response, FileResponse::Accepted));
+        loop {
+            let (id, index) = match next {
+                Ok(FileRequest::Chunk { id, index }) => (id, index),
+                Ok(request) => panic!("unexpected {:?}", request),
+                Err(line) => return line.unwrap(),
+            };
+            let data = match sender.serve_chunk(to, &id, index) {
+                FileResponse::Chunk { data } => data,
+                response => panic!("unexpected {:?}", response),
+            };
+            next = match receiver.write_chunk(&id, &data) {
+                Ok(true) => Ok(receiver.next_request(&id).unwrap()),
+                Ok(false) => Err(receiver.finish(&id)),
+                Err(reason) => panic!("{}", reason),
+            };
+        }
+    }
+
+    #[test]
+    fn refuses_senders_not_accepted() {
+        let dir = temp_dir("refuse-sender");
+        let (from, to) = (PeerId::random(), PeerId::random());
+        let (_, offer) = offer(&dir, b"hello", to);
+        let mut nobody = Transfers::new(config(&dir, Vec::new()));
+        let (response, _) = nobody.receive_offer(from, offer.clone());
+        assert_eq!(refusal(response), "not accepting files from you");
+        let other = AcceptFrom::Peer(PeerId::random());
+        let mut others = Transfers::new(config(&dir, vec![other]));
+        let (response, _) = others.receive_offer(from, offer.clone());
+        assert_eq!(refusal(response), "not accepting files from you");
+        let mut sender = Transfers::new(config(&dir, vec![AcceptFrom::Peer(from)]));
+        let (response, _) = sender.receive_offer(from, offer);
+        assert!(matches!(response, FileResponse::Accepted));
+    }
+
+    #[test]
+    fn refuses_files_over_max_size() {
+        let dir = temp_dir("refuse-size");
+        let (from, to) = (PeerId::random(), PeerId::random());
+        let (_, offer) = offer(&dir, &[7; 1025], to);
+        let mut receiver = Transfers::new(config(&dir, vec![AcceptFrom::Anyone]));
+        let (response, _) = receiver.receive_offer(from, offer);
+        assert_eq!(refusal(response), "larger than 1024 bytes");
+        assert!(!dir.join("downloads").exists());
+    }
+
+    #[test]
+    fn keeps_existing_files() {
+        let dir = temp_dir("keep-existing");
+        let downloads = dir.join("downloads");
+        fs::create_dir_all(&downloads).unwrap();
+        fs::write(downloads.join("notes.txt"), "mine").unwrap();
+        let (from, to) = (PeerId::random(), PeerId::random());
+        let (mut sender, offer) = offer(&dir, b"theirs", to);
+        let mut receiver = Transfers::new(config(&dir, vec![AcceptFrom::Anyone]));
+        let line = receive(from, &mut sender, to, &mut receiver, offer);
+        assert!(line.contains("notes (1).txt"), "{}", line);
+        assert_eq!(fs::read(downloads.join("notes.txt")).unwrap(), b"mine");
+        assert_eq!(
+            fs::read(downloads.join("notes (1).txt")).unwrap(),
+            b"theirs"
+        );
+        let left: Vec<_> = fs::read_dir(&downloads).unwrap().collect();
+        assert_eq!(left.len(), 2, "the partial file is gone");
+    }
+
+    #[test]
+    fn numbers_names_before_the_extension() {
+        assert_eq!(numbered("notes.txt", 0), "notes.txt");
+        assert_eq!(numbered("notes.txt", 2), "notes (2).txt");
+        assert_eq!(numbered("archive.tar.gz", 1), "archive.tar (1).gz");
+        assert_eq!(numbered(".profile", 1), ".profile (1)");
+        assert_eq!(numbered("README", 1), "README (1)");
+    }
+
+    #[test]
+    fn parses_accept_from() {
+        assert_eq!("*".parse(), Ok(AcceptFrom::Anyone));
+        let peer = PeerId::random();
+        assert_eq!(peer.to_base58().parse(), Ok(AcceptFrom::Peer(peer)));
+        assert!("somebody".parse::<AcceptFrom>().is_err());
+    }
+}